@@ -0,0 +1,185 @@
+use std::{fmt::Write, fs, iter::Peekable, str::Chars};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use screwdriver::keyvalue::{Conditions, KeyValues, KeyValuesError, Location};
+
+/// Builds a VMF with the given number of 6 sided brushes, roughly 1.5 KB each
+fn generate_vmf(brushes: usize) -> String {
+    let mut vmf = String::from(
+        "versioninfo\n{\n\t\"editorversion\" \"400\"\n\t\"mapversion\" \"1\"\n}\nworld\n{\n\t\"id\" \"1\"\n\t\"classname\" \"worldspawn\"\n",
+    );
+    for brush in 0..brushes {
+        let x = (brush % 100) as f32 * 64.0;
+        let y = (brush / 100) as f32 * 64.0;
+        writeln!(vmf, "\tsolid\n\t{{\n\t\t\"id\" \"{}\"", brush * 7 + 2).unwrap();
+        for side in 0..6 {
+            write!(
+                vmf,
+                "\t\tside\n\t\t{{\n\t\t\t\"id\" \"{}\"\n\t\t\t\"plane\" \"({x} {y} 64) ({} {y} 64) ({} {} 64)\"\n\t\t\t\"material\" \"DEV/DEV_MEASUREGENERIC01B\"\n\t\t\t\"uaxis\" \"[1 0 0 0] 0.25\"\n\t\t\t\"vaxis\" \"[0 -1 0 0] 0.25\"\n\t\t\t\"rotation\" \"0\"\n\t\t\t\"lightmapscale\" \"16\"\n\t\t\t\"smoothing_groups\" \"0\"\n\t\t}}\n",
+                brush * 7 + 3 + side,
+                x + 64.0,
+                x + 64.0,
+                y - 64.0,
+            )
+            .unwrap();
+        }
+        vmf.push_str("\t\teditor\n\t\t{\n\t\t\t\"color\" \"0 180 233\"\n\t\t\t\"visgroupshown\" \"1\"\n\t\t\t\"visgroupautoshown\" \"1\"\n\t\t}\n\t}\n");
+    }
+    vmf.push_str("}\n");
+    vmf
+}
+
+fn parse(content: &str) -> KeyValues<'_> {
+    KeyValues::parse_internal(content, "bench.vmf", &Conditions::default(), &|x| {
+        Err(KeyValuesError::InvalidFile(x, Location::default()))
+    })
+    .unwrap()
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let inputs = [
+        (
+            "sample",
+            fs::read_to_string("tests/data/sample.vmf").expect("is part of the repository"),
+        ),
+        ("generated", generate_vmf(2000)),
+    ];
+    for (name, content) in &inputs {
+        let mut group = c.benchmark_group(format!("parse_{}", name));
+        group.throughput(Throughput::Bytes(content.len() as u64));
+        group.bench_function("previous", |b| {
+            b.iter(|| previous::parse(black_box(content)))
+        });
+        group.bench_function("borrowed", |b| b.iter(|| parse(black_box(content))));
+        group.bench_function("owned", |b| {
+            b.iter(|| parse(black_box(content)).into_owned())
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
+
+/// The parser KeyValues used before the byte tokenizer, to compare against
+/// Walks the file with Peekable<Chars> and copies every token into a String
+/// Conditionals and macros are left out, the benchmark files don't have any
+mod previous {
+    use super::*;
+
+    /// Only built to be dropped again, just like the real thing in the benchmark
+    #[allow(dead_code)]
+    pub enum Node {
+        Value(String),
+        List(Vec<(String, Node)>),
+    }
+
+    struct Reader<'a> {
+        chars: Peekable<Chars<'a>>,
+        line: usize,
+        column: usize,
+    }
+
+    impl Iterator for Reader<'_> {
+        type Item = char;
+
+        fn next(&mut self) -> Option<char> {
+            let c = self.chars.next()?;
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+            Some(c)
+        }
+    }
+
+    pub fn parse(content: &str) -> Node {
+        let mut content = Reader {
+            chars: content.chars().peekable(),
+            line: 1,
+            column: 1,
+        };
+        let mut current = vec![];
+        let mut stack = vec![];
+        loop {
+            skip_trivia(&mut content);
+            match content.chars.peek() {
+                None => break,
+                Some('}') => {
+                    let (name, parent) = stack.pop().unwrap();
+                    let part = Node::List(current);
+                    current = parent;
+                    current.push((name, part));
+                    content.next();
+                    continue;
+                }
+                _ => {}
+            }
+            let name = read_text(&mut content);
+            skip_trivia(&mut content);
+            if content.chars.peek() == Some(&'{') {
+                content.next();
+                stack.push((name, current));
+                current = vec![];
+            } else {
+                let value = read_text(&mut content);
+                current.push((name, Node::Value(value)));
+            }
+        }
+        Node::List(current)
+    }
+
+    fn skip_trivia(content: &mut Reader) {
+        loop {
+            while let Some(' ' | '\r' | '\n' | '\t') = content.chars.peek() {
+                content.next();
+            }
+            if content.chars.peek() != Some(&'/') {
+                return;
+            }
+            for c in content.by_ref() {
+                if c == '\n' {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn read_text(content: &mut Reader) -> String {
+        let mut text = String::new();
+        let quoted = match content.next().unwrap() {
+            '"' => true,
+            c => {
+                text.push(c);
+                false
+            }
+        };
+        loop {
+            match content.chars.peek() {
+                None => break,
+                Some('\\') => {
+                    content.next();
+                    match content.next().unwrap() {
+                        'n' => text.push('\n'),
+                        't' => text.push('\t'),
+                        c => text.push(c),
+                    }
+                }
+                Some('"') => {
+                    if quoted {
+                        content.next();
+                    }
+                    break;
+                }
+                Some(' ' | '\r' | '\n' | '\t' | '{' | '}') if !quoted => break,
+                Some(c) => {
+                    text.push(*c);
+                    content.next();
+                }
+            }
+        }
+        text
+    }
+}
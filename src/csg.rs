@@ -0,0 +1,444 @@
+use nalgebra_glm as glm;
+
+use crate::{
+    math::{Epsilons, Plane, PolyhedronError},
+    vmf::{Brush, Face},
+};
+
+/// How far a vertex may be in front of a side and still count as behind it when merging
+const MERGE_EPSILON: f64 = 0.01;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum CsgError {
+    #[error(transparent)]
+    Polyhedron(#[from] PolyhedronError),
+    #[error("the brushes together aren't convex")]
+    NotConvex,
+    #[error("the walls are too thick for the brush")]
+    TooThick,
+    #[error("a side with a displacement would change its shape")]
+    Displacement,
+}
+
+// Brushes and sides made by these functions have the id 0, they need new ids before saving.
+// The first piece of a brush keeps its id and the ids of its old sides.
+// Displacements only fit the side they were made on, so sides with one may not get cut or moved.
+
+/// Cuts the brush in two at the plane, gives the parts behind and in front of it
+/// The new sides get the material of the side facing most the same way as the plane
+pub fn clip(brush: &Brush, plane: &Plane) -> Result<(Option<Brush>, Option<Brush>), CsgError> {
+    let near = get_center(brush);
+    let material = &brush
+        .shape
+        .faces
+        .iter()
+        .max_by(|(a, _), (b, _)| {
+            let normal = plane.normal.cast();
+            glm::dot(&a.normal(), &normal).total_cmp(&glm::dot(&b.normal(), &normal))
+        })
+        .ok_or(PolyhedronError::Empty)?
+        .0
+        .material;
+    let behind = cut(brush, Face::new(0, plane, &near, material))?;
+    let mut in_front = cut(brush, Face::new(0, &plane.flipped(), &near, material))?;
+    if let (Some(_), Some(in_front)) = (&behind, &mut in_front) {
+        clear_ids(in_front);
+    }
+    Ok((behind, in_front))
+}
+
+/// Subtracts the cutter from the brush, leaving convex pieces around it
+/// The new sides get the material and texture alignment of the cutter
+/// A brush that doesn't touch the cutter comes back as it is, one inside it is gone
+pub fn carve(brush: &Brush, cutter: &Brush) -> Result<Vec<Brush>, CsgError> {
+    let near = get_center(brush);
+    let mut pieces: Vec<Brush> = vec![];
+    let mut rest = brush.clone();
+    for (cutter_face, _) in &cutter.shape.faces {
+        let Some(plane) = cutter_face.get_plane() else {
+            continue;
+        };
+        if let Some(mut piece) = cut(&rest, new_side(cutter_face, &plane.flipped(), &near))? {
+            if !pieces.is_empty() {
+                clear_ids(&mut piece);
+            }
+            pieces.push(piece);
+        }
+        match cut(&rest, new_side(cutter_face, &plane, &near))? {
+            Some(inside) => rest = inside,
+            None => return Ok(vec![brush.clone()]),
+        }
+    }
+    // What's left now is inside the cutter
+    Ok(pieces)
+}
+
+/// Turns the brush into walls around its inside, like Hammer's hollow tool
+/// Positive thickness puts the walls inside the brush, negative thickness outside of it
+pub fn hollow(brush: &Brush, thickness: f32) -> Result<Vec<Brush>, CsgError> {
+    if thickness > 0.0 {
+        let inside = offset(brush, -thickness as f64).map_err(|e| match e {
+            CsgError::Polyhedron(PolyhedronError::Empty) => CsgError::TooThick,
+            e => e,
+        })?;
+        carve(brush, &inside)
+    } else {
+        carve(&offset(brush, -thickness as f64)?, brush)
+    }
+}
+
+/// Combines brushes into one, they have to be convex together and can't overlap
+/// The result keeps the id of the first brush, sides on the same plane become one
+pub fn merge(brushes: &[&Brush]) -> Result<Brush, CsgError> {
+    let first = brushes.first().ok_or(PolyhedronError::Empty)?;
+    let vertices: Vec<glm::DVec3> = brushes
+        .iter()
+        .flat_map(|brush| &brush.shape.vertices)
+        .map(|x| x.cast())
+        .collect();
+    // Only sides with every vertex behind them are on the outside of the merged brush
+    let faces = brushes
+        .iter()
+        .flat_map(|brush| &brush.shape.faces)
+        .filter(|(face, _)| {
+            face.get_plane().is_some_and(|plane| {
+                vertices
+                    .iter()
+                    .all(|x| plane.signed_distance(x) <= MERGE_EPSILON)
+            })
+        })
+        .map(|(face, _)| face.clone())
+        .collect();
+    let merged = finish(first, Brush::from_faces(first.id, faces)?);
+    check_displacements(brushes, &merged)?;
+    // Anything concave has gaps that the merged brush fills in
+    let volume: f32 = brushes.iter().map(|brush| brush.shape.get_volume()).sum();
+    if (merged.shape.get_volume() - volume).abs() > volume * 1e-4 {
+        return Err(CsgError::NotConvex);
+    }
+    Ok(merged)
+}
+
+/// The part of the brush behind the new side, None if nothing is left
+fn cut(brush: &Brush, side: Face) -> Result<Option<Brush>, CsgError> {
+    let mut faces: Vec<Face> = brush.shape.faces.iter().map(|(x, _)| x.clone()).collect();
+    faces.push(side);
+    match Brush::from_faces(brush.id, faces) {
+        Ok(piece) => {
+            let piece = finish(brush, piece);
+            check_displacements(&[brush], &piece)?;
+            Ok(Some(piece))
+        }
+        Err(PolyhedronError::Empty) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The brush with every side moved out by distance, or in if it's negative
+fn offset(brush: &Brush, distance: f64) -> Result<Brush, CsgError> {
+    let near = get_center(brush);
+    let mut faces = vec![];
+    for (face, _) in &brush.shape.faces {
+        let mut face = face.clone();
+        if let Some(mut plane) = face.get_plane() {
+            plane.distance += distance;
+            face.set_plane(&plane, &near);
+        }
+        faces.push(face);
+    }
+    let offset = finish(brush, Brush::from_faces(brush.id, faces)?);
+    check_displacements(&[brush], &offset)?;
+    Ok(offset)
+}
+
+/// A side on the plane that looks like the template, without displacement
+fn new_side(template: &Face, plane: &Plane, near: &glm::DVec3) -> Face {
+    let mut side = template.clone();
+    side.id = 0;
+    side.dispinfo = None;
+    side.set_plane(plane, near);
+    side
+}
+
+/// Only keeps the sides that touch the new brush and copies over the editor info
+fn finish(original: &Brush, mut brush: Brush) -> Brush {
+    brush.faces = brush.shape.faces.iter().map(|(x, _)| x.clone()).collect();
+    brush.editor = original.editor.clone();
+    brush.extra = original.extra.clone();
+    brush
+}
+
+/// Errors if a side with a displacement doesn't have the same corners as in the brushes it came from
+fn check_displacements(originals: &[&Brush], brush: &Brush) -> Result<(), CsgError> {
+    let epsilons = Epsilons::default();
+    let get_corners = |brush: &Brush, indices: &[usize]| -> Vec<glm::Vec3> {
+        indices.iter().map(|&i| brush.shape.vertices[i]).collect()
+    };
+    for (face, indices) in &brush.shape.faces {
+        if face.dispinfo.is_none() {
+            continue;
+        }
+        let plane = face.get_plane();
+        let corners = get_corners(brush, indices);
+        let unchanged = originals.iter().any(|original| {
+            original.shape.faces.iter().any(|(x, old_indices)| {
+                let old_corners = get_corners(original, old_indices);
+                x.dispinfo.is_some()
+                    && x.get_plane()
+                        .zip(plane)
+                        .is_some_and(|(a, b)| a.is_coplanar(&b, &epsilons))
+                    && old_corners.len() == corners.len()
+                    && corners.iter().all(|a| {
+                        old_corners
+                            .iter()
+                            .any(|b| glm::distance(a, b) as f64 <= epsilons.vertex_merge)
+                    })
+            })
+        });
+        if !unchanged {
+            return Err(CsgError::Displacement);
+        }
+    }
+    Ok(())
+}
+
+fn clear_ids(brush: &mut Brush) {
+    brush.id = 0;
+    for face in brush
+        .faces
+        .iter_mut()
+        .chain(brush.shape.faces.iter_mut().map(|(x, _)| x))
+    {
+        face.id = 0;
+    }
+}
+
+fn get_center(brush: &Brush) -> glm::DVec3 {
+    brush.shape.get_centroid().unwrap_or_default().cast()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_box(id: i32, mins: glm::Vec3, maxs: glm::Vec3, material: &str) -> Brush {
+        Brush::new_box(id, id * 10, &mins, &maxs, material).unwrap()
+    }
+
+    /// Every side gets a material saying which way it faces, e.g. "+X"
+    fn label_sides(brush: &mut Brush) {
+        for face in brush
+            .faces
+            .iter_mut()
+            .chain(brush.shape.faces.iter_mut().map(|(x, _)| x))
+        {
+            let normal = face.normal();
+            let axis = normal.iamax();
+            let sign = if normal[axis] > 0.0 { '+' } else { '-' };
+            face.material = format!("{sign}{}", ['X', 'Y', 'Z'][axis]);
+        }
+    }
+
+    fn total_volume(brushes: &[Brush]) -> f32 {
+        brushes.iter().map(|x| x.shape.get_volume()).sum()
+    }
+
+    #[test]
+    fn test_clip() {
+        let mut brush = new_box(1, glm::vec3(0.0, 0.0, 0.0), glm::vec3(64.0, 64.0, 64.0), "");
+        label_sides(&mut brush);
+        let plane = Plane {
+            normal: glm::normalize(&glm::vec3(1.0, 0.0, 0.2)),
+            distance: 16.0,
+        };
+        let (behind, in_front) = clip(&brush, &plane).unwrap();
+        let (behind, in_front) = (behind.unwrap(), in_front.unwrap());
+        assert_eq!(behind.id, 1);
+        assert_eq!(in_front.id, 0);
+        assert!(in_front.faces.iter().all(|face| face.id == 0));
+        assert!(
+            (behind.shape.get_volume() + in_front.shape.get_volume() - 64.0_f32.powi(3)).abs()
+                < 1.0
+        );
+        for piece in [&behind, &in_front] {
+            assert_eq!(piece.faces.len(), 6);
+            assert!(piece
+                .faces
+                .iter()
+                .any(|face| face.id == 0 && face.material == "+X"));
+        }
+
+        // Planes missing the brush leave it on one side
+        let (behind, in_front) = clip(
+            &brush,
+            &Plane {
+                distance: 100.0,
+                ..plane
+            },
+        )
+        .unwrap();
+        assert_eq!(behind.unwrap().id, 1);
+        assert!(in_front.is_none());
+    }
+
+    #[test]
+    fn test_carve() {
+        let brush = new_box(
+            1,
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(128.0, 128.0, 128.0),
+            "WALL",
+        );
+        // A hole through the middle
+        let cutter = new_box(
+            2,
+            glm::vec3(32.0, 32.0, -16.0),
+            glm::vec3(96.0, 96.0, 144.0),
+            "HOLE",
+        );
+        let pieces = carve(&brush, &cutter).unwrap();
+        assert_eq!(pieces.len(), 4);
+        assert_eq!(pieces[0].id, 1);
+        assert!(pieces[1..].iter().all(|x| x.id == 0));
+        assert!(
+            (total_volume(&pieces) - (128.0 * 128.0 * 128.0 - 64.0 * 64.0 * 128.0)).abs() < 1.0
+        );
+        let center = glm::vec3(64.0, 64.0, 64.0);
+        for piece in &pieces {
+            assert!(!piece.shape.contains_point(&center, 0.0));
+            let new_sides: Vec<_> = piece
+                .faces
+                .iter()
+                .filter(|x| x.material == "HOLE")
+                .collect();
+            assert!(!new_sides.is_empty());
+            assert!(new_sides.iter().all(|x| x.id == 0));
+        }
+
+        let outside = new_box(
+            3,
+            glm::vec3(200.0, 0.0, 0.0),
+            glm::vec3(300.0, 64.0, 64.0),
+            "",
+        );
+        let pieces = carve(&brush, &outside).unwrap();
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(pieces[0].faces.len(), 6);
+        assert_eq!(pieces[0].faces[0].id, brush.faces[0].id);
+        let around = new_box(
+            4,
+            glm::vec3(-1.0, -1.0, -1.0),
+            glm::vec3(129.0, 129.0, 129.0),
+            "",
+        );
+        assert!(carve(&brush, &around).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_hollow() {
+        let mut brush = new_box(
+            1,
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(128.0, 128.0, 128.0),
+            "",
+        );
+        label_sides(&mut brush);
+        let walls = hollow(&brush, 16.0).unwrap();
+        assert_eq!(walls.len(), 6);
+        assert!((total_volume(&walls) - (128.0f32.powi(3) - 96.0f32.powi(3))).abs() < 1.0);
+        // The floor keeps the material of the floor on both sides
+        let floor = walls
+            .iter()
+            .find(|x| x.shape.contains_point(&glm::vec3(64.0, 64.0, 8.0), 0.0))
+            .unwrap();
+        assert_eq!(floor.faces.iter().filter(|x| x.material == "-Z").count(), 2);
+
+        let walls = hollow(&brush, -16.0).unwrap();
+        assert!((total_volume(&walls) - (160.0f32.powi(3) - 128.0f32.powi(3))).abs() < 1.0);
+        assert_eq!(hollow(&brush, 64.0).unwrap_err(), CsgError::TooThick);
+    }
+
+    #[test]
+    fn test_merge() {
+        let a = new_box(
+            1,
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(64.0, 64.0, 64.0),
+            "A",
+        );
+        let b = new_box(
+            2,
+            glm::vec3(64.0, 0.0, 0.0),
+            glm::vec3(96.0, 64.0, 64.0),
+            "B",
+        );
+        let merged = merge(&[&a, &b]).unwrap();
+        assert_eq!(merged.id, 1);
+        assert_eq!(merged.faces.len(), 6);
+        assert_eq!(merged.shape.get_volume(), 96.0 * 64.0 * 64.0);
+        assert_eq!(
+            merged.get_bounds(),
+            Some((glm::vec3(0.0, 0.0, 0.0), glm::vec3(96.0, 64.0, 64.0)))
+        );
+
+        // Together they make an L
+        let c = new_box(
+            3,
+            glm::vec3(64.0, 0.0, 0.0),
+            glm::vec3(96.0, 32.0, 64.0),
+            "C",
+        );
+        assert_eq!(merge(&[&a, &c]).unwrap_err(), CsgError::NotConvex);
+    }
+
+    #[test]
+    fn test_displacements() {
+        // A box from (-64 -64 0) to (64 64 64) with a displacement on top
+        let vmf = crate::vmf::VMF::parse(std::path::Path::new("tests/data/sample.vmf")).unwrap();
+        let brush = &vmf.world.brushes[0];
+        let has_displacement = |x: &Brush| x.faces.iter().any(|face| face.dispinfo.is_some());
+        assert!(has_displacement(brush));
+
+        // Cutting across the displacement would leave it too big for its side
+        let across = Plane {
+            normal: glm::vec3(1.0, 0.0, 0.0),
+            distance: 0.0,
+        };
+        assert_eq!(clip(brush, &across).unwrap_err(), CsgError::Displacement);
+        let corner = new_box(
+            2,
+            glm::vec3(32.0, 32.0, 32.0),
+            glm::vec3(96.0, 96.0, 96.0),
+            "",
+        );
+        assert_eq!(carve(brush, &corner).unwrap_err(), CsgError::Displacement);
+        assert_eq!(hollow(brush, 8.0).unwrap_err(), CsgError::Displacement);
+        let beside = new_box(
+            3,
+            glm::vec3(64.0, -64.0, 0.0),
+            glm::vec3(96.0, 64.0, 64.0),
+            "",
+        );
+        assert_eq!(
+            merge(&[brush, &beside]).unwrap_err(),
+            CsgError::Displacement
+        );
+
+        // The side with the displacement stays the same when cutting below it
+        let below = Plane {
+            normal: glm::vec3(0.0, 0.0, 1.0),
+            distance: 32.0,
+        };
+        let (behind, in_front) = clip(brush, &below).unwrap();
+        assert!(!has_displacement(&behind.unwrap()));
+        assert!(has_displacement(&in_front.unwrap()));
+        let away = new_box(
+            4,
+            glm::vec3(200.0, 0.0, 0.0),
+            glm::vec3(300.0, 64.0, 64.0),
+            "",
+        );
+        let pieces = carve(brush, &away).unwrap();
+        assert!(has_displacement(&pieces[0]));
+    }
+}
@@ -0,0 +1,819 @@
+use std::{
+    io,
+    mem::size_of,
+    path::{Path, PathBuf},
+};
+
+use glm::Mat4;
+use nalgebra_glm as glm;
+
+use crate::vmf::{Brush, Entity, Face, TextureLock, TransformError, VmfError, VMF};
+
+/// How much memory the undo history may use by default, in bytes
+pub const DEFAULT_MAX_MEMORY: usize = 64 * 1024 * 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum CommandError {
+    #[error("there is no entity with id {0}")]
+    MissingEntity(i32),
+    #[error("there is no solid with id {0}")]
+    MissingBrush(i32),
+    #[error("solid {brush} has no side with id {side}")]
+    MissingSide { brush: i32, side: i32 },
+    #[error(transparent)]
+    Transform(#[from] TransformError),
+}
+
+/// A single change to a VMF, applying it gives back the command that reverts it
+/// Solids and entities are found by their id, only the ones that aren't hidden can be changed
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// Adds a solid to the entity, at index or at the end
+    CreateBrush {
+        entity: i32,
+        index: Option<usize>,
+        brush: Brush,
+    },
+    DeleteBrush {
+        id: i32,
+    },
+    /// Swaps the solid with the same id for this one
+    ReplaceBrush {
+        brush: Brush,
+    },
+    TransformBrush {
+        id: i32,
+        matrix: Mat4,
+        lock: TextureLock,
+    },
+    SetMaterial {
+        brush: i32,
+        side: i32,
+        material: String,
+    },
+    /// Swaps the side with the same id for this one, e.g. after changing its texture axes
+    /// The shape doesn't get rebuilt, use ReplaceBrush if the plane changes
+    SetFace {
+        brush: i32,
+        face: Box<Face>,
+    },
+    /// Sets the keyvalue, None removes it
+    SetKeyValue {
+        entity: i32,
+        key: String,
+        value: Option<String>,
+    },
+    /// Sets all keyvalues of the entity at once
+    SetKeyValues {
+        entity: i32,
+        keyvalues: Vec<(String, String)>,
+    },
+    /// Adds an entity to the map, at index or at the end
+    CreateEntity {
+        index: Option<usize>,
+        entity: Entity,
+    },
+    /// Removes an entity, the world can't be removed
+    DeleteEntity {
+        id: i32,
+    },
+}
+
+impl Command {
+    /// Applies the command and returns the command that reverts it
+    /// Nothing changes when it fails
+    pub fn apply(&self, vmf: &mut VMF) -> Result<Command, CommandError> {
+        Ok(match self {
+            Command::CreateBrush {
+                entity,
+                index,
+                brush,
+            } => {
+                let brushes = &mut get_entity_mut(vmf, *entity)?.brushes;
+                let index = index.unwrap_or(brushes.len()).min(brushes.len());
+                brushes.insert(index, brush.clone());
+                Command::DeleteBrush { id: brush.id }
+            }
+            Command::DeleteBrush { id } => {
+                let (entity, index) = find_brush(vmf, *id)?;
+                let brush = get_entity_mut(vmf, entity)?.brushes.remove(index);
+                Command::CreateBrush {
+                    entity,
+                    index: Some(index),
+                    brush,
+                }
+            }
+            Command::ReplaceBrush { brush } => {
+                let old = std::mem::replace(get_brush_mut(vmf, brush.id)?, brush.clone());
+                Command::ReplaceBrush { brush: old }
+            }
+            Command::TransformBrush { id, matrix, lock } => {
+                let brush = get_brush_mut(vmf, *id)?;
+                let old = brush.clone();
+                brush.transform(matrix, *lock)?;
+                Command::ReplaceBrush { brush: old }
+            }
+            Command::SetMaterial {
+                brush,
+                side,
+                material,
+            } => {
+                let old = get_face_mut(vmf, *brush, *side)?.material.clone();
+                for face in get_faces_mut(get_brush_mut(vmf, *brush)?, *side) {
+                    face.material = material.clone();
+                }
+                Command::SetMaterial {
+                    brush: *brush,
+                    side: *side,
+                    material: old,
+                }
+            }
+            Command::SetFace { brush, face } => {
+                let old = get_face_mut(vmf, *brush, face.id)?.clone();
+                for x in get_faces_mut(get_brush_mut(vmf, *brush)?, face.id) {
+                    *x = Face::clone(face);
+                }
+                Command::SetFace {
+                    brush: *brush,
+                    face: Box::new(old),
+                }
+            }
+            Command::SetKeyValue { entity, key, value } => {
+                let keyvalues = &mut get_entity_mut(vmf, *entity)?.keyvalues;
+                let position = keyvalues.iter().position(|(x, _)| x == key);
+                let old_value = position.map(|i| keyvalues[i].1.clone());
+                match (position, value) {
+                    (Some(i), Some(value)) => keyvalues[i].1 = value.clone(),
+                    (None, Some(value)) => keyvalues.push((key.clone(), value.clone())),
+                    (Some(i), None) => {
+                        // Removing it from the middle moves the keys after it, so all of them get restored
+                        let old = keyvalues.clone();
+                        keyvalues.remove(i);
+                        return Ok(Command::SetKeyValues {
+                            entity: *entity,
+                            keyvalues: old,
+                        });
+                    }
+                    (None, None) => {}
+                }
+                Command::SetKeyValue {
+                    entity: *entity,
+                    key: key.clone(),
+                    value: old_value,
+                }
+            }
+            Command::SetKeyValues { entity, keyvalues } => {
+                let old = std::mem::replace(
+                    &mut get_entity_mut(vmf, *entity)?.keyvalues,
+                    keyvalues.clone(),
+                );
+                Command::SetKeyValues {
+                    entity: *entity,
+                    keyvalues: old,
+                }
+            }
+            Command::CreateEntity { index, entity } => {
+                let index = index.unwrap_or(vmf.entities.len()).min(vmf.entities.len());
+                vmf.entities.insert(index, entity.clone());
+                Command::DeleteEntity { id: entity.id }
+            }
+            Command::DeleteEntity { id } => {
+                let index = vmf
+                    .entities
+                    .iter()
+                    .position(|x| x.id == *id && !x.hidden)
+                    .ok_or(CommandError::MissingEntity(*id))?;
+                Command::CreateEntity {
+                    index: Some(index),
+                    entity: vmf.entities.remove(index),
+                }
+            }
+        })
+    }
+
+    /// What the undo and redo menu entries call it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::CreateBrush { .. } => "Create solid",
+            Command::DeleteBrush { .. } => "Delete solid",
+            Command::ReplaceBrush { .. } => "Change solid",
+            Command::TransformBrush { .. } => "Transform",
+            Command::SetMaterial { .. } => "Apply material",
+            Command::SetFace { .. } => "Change face",
+            Command::SetKeyValue { .. } | Command::SetKeyValues { .. } => "Change keyvalue",
+            Command::CreateEntity { .. } => "Create entity",
+            Command::DeleteEntity { .. } => "Delete entity",
+        }
+    }
+
+    /// Roughly how many bytes the command takes up
+    pub fn get_size(&self) -> usize {
+        size_of::<Command>()
+            + match self {
+                Command::CreateBrush { brush, .. } | Command::ReplaceBrush { brush } => {
+                    get_brush_size(brush)
+                }
+                Command::SetMaterial { material, .. } => material.len(),
+                Command::SetFace { face, .. } => get_face_size(face),
+                Command::SetKeyValue { key, value, .. } => {
+                    key.len() + value.as_ref().map_or(0, |x| x.len())
+                }
+                Command::SetKeyValues { keyvalues, .. } => get_keyvalues_size(keyvalues),
+                Command::CreateEntity { entity, .. } => {
+                    size_of::<Entity>()
+                        + get_keyvalues_size(&entity.keyvalues)
+                        + entity.brushes.iter().map(get_brush_size).sum::<usize>()
+                }
+                Command::DeleteBrush { .. }
+                | Command::TransformBrush { .. }
+                | Command::DeleteEntity { .. } => 0,
+            }
+    }
+}
+
+/// One entry of the undo history, the commands revert it when applied in order
+#[derive(Debug)]
+struct Step {
+    id: u64,
+    name: String,
+    commands: Vec<Command>,
+    size: usize,
+}
+
+impl Step {
+    fn new(id: u64, name: String, commands: Vec<Command>) -> Self {
+        let size = commands.iter().map(Command::get_size).sum();
+        Self {
+            id,
+            name,
+            commands,
+            size,
+        }
+    }
+}
+
+/// An open VMF, all changes go through commands so they can be undone
+/// Commands run between begin_group and end_group get undone together
+#[derive(Debug)]
+pub struct Document {
+    vmf: VMF,
+    path: Option<PathBuf>,
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+    /// The name and the reverting commands of the open group, in the order they were made
+    group: Option<(String, Vec<Command>)>,
+    group_depth: usize,
+    memory: usize,
+    max_memory: usize,
+    /// Every state the history can get back to has an id, this one is for an empty undo stack
+    base_id: u64,
+    next_id: u64,
+    saved_id: Option<u64>,
+}
+
+impl Document {
+    /// A document for a VMF that has no file yet, it starts out without changes
+    pub fn new(vmf: VMF) -> Self {
+        Self {
+            vmf,
+            path: None,
+            undo: vec![],
+            redo: vec![],
+            group: None,
+            group_depth: 0,
+            memory: 0,
+            max_memory: DEFAULT_MAX_MEMORY,
+            base_id: 0,
+            next_id: 1,
+            saved_id: Some(0),
+        }
+    }
+
+    pub fn open(path: &Path) -> Result<Self, VmfError> {
+        let mut document = Self::new(VMF::parse(path)?);
+        document.path = Some(path.to_path_buf());
+        Ok(document)
+    }
+
+    pub fn vmf(&self) -> &VMF {
+        &self.vmf
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Applies the command and puts it on the undo history, the redo history gets cleared
+    /// Nothing changes when the command fails
+    pub fn execute(&mut self, command: Command) -> Result<(), CommandError> {
+        let revert = command.apply(&mut self.vmf)?;
+        self.clear_redo();
+        match &mut self.group {
+            Some((_, commands)) => commands.push(revert),
+            None => self.push_undo(command.name().to_string(), vec![revert]),
+        }
+        Ok(())
+    }
+
+    /// Starts collecting commands into one undo step, groups can be nested
+    pub fn begin_group(&mut self, name: &str) {
+        if self.group_depth == 0 {
+            self.group = Some((name.to_string(), vec![]));
+        }
+        self.group_depth += 1;
+    }
+
+    /// Ends the group, once the outermost one ends its commands become one undo step
+    pub fn end_group(&mut self) {
+        self.group_depth = self.group_depth.saturating_sub(1);
+        if self.group_depth > 0 {
+            return;
+        }
+        if let Some((name, mut commands)) = self.group.take() {
+            if !commands.is_empty() {
+                commands.reverse();
+                self.push_undo(name, commands);
+            }
+        }
+    }
+
+    /// Reverts the last step, returns false if there is nothing to undo
+    /// An open group gets ended first
+    pub fn undo(&mut self) -> Result<bool, CommandError> {
+        self.end_all_groups();
+        let Some(step) = self.undo.pop() else {
+            return Ok(false);
+        };
+        match apply_all(&mut self.vmf, &step.commands) {
+            Ok(commands) => {
+                self.memory -= step.size;
+                let step = Step::new(step.id, step.name, commands);
+                self.memory += step.size;
+                self.redo.push(step);
+                Ok(true)
+            }
+            Err(err) => {
+                self.undo.push(step);
+                Err(err)
+            }
+        }
+    }
+
+    /// Applies the last undone step again, returns false if there is nothing to redo
+    pub fn redo(&mut self) -> Result<bool, CommandError> {
+        self.end_all_groups();
+        let Some(step) = self.redo.pop() else {
+            return Ok(false);
+        };
+        match apply_all(&mut self.vmf, &step.commands) {
+            Ok(commands) => {
+                self.memory -= step.size;
+                let step = Step::new(step.id, step.name, commands);
+                self.memory += step.size;
+                self.undo.push(step);
+                Ok(true)
+            }
+            Err(err) => {
+                self.redo.push(step);
+                Err(err)
+            }
+        }
+    }
+
+    /// The name of the step undo would revert, for the menu
+    pub fn undo_name(&self) -> Option<&str> {
+        self.undo.last().map(|x| x.name.as_str())
+    }
+
+    pub fn redo_name(&self) -> Option<&str> {
+        self.redo.last().map(|x| x.name.as_str())
+    }
+
+    pub fn undo_count(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn redo_count(&self) -> usize {
+        self.redo.len()
+    }
+
+    /// Roughly how many bytes the history uses
+    pub fn get_memory(&self) -> usize {
+        self.memory
+    }
+
+    /// Limits the memory of the history, the oldest steps get dropped when it's over
+    /// The last step always stays, even if it's bigger than that
+    pub fn set_max_memory(&mut self, bytes: usize) {
+        self.max_memory = bytes;
+        self.trim();
+    }
+
+    /// Whether there are changes that aren't saved
+    pub fn is_dirty(&self) -> bool {
+        self.saved_id != Some(self.current_id())
+    }
+
+    /// Marks the current state as the saved one, e.g. after writing it somewhere else
+    pub fn mark_saved(&mut self) {
+        self.saved_id = Some(self.current_id());
+    }
+
+    /// Writes the VMF to its file, errors if it doesn't have one yet
+    pub fn save(&mut self) -> Result<(), io::Error> {
+        let path = self.path.clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "the document has no file yet")
+        })?;
+        self.save_as(&path)
+    }
+
+    pub fn save_as(&mut self, path: &Path) -> Result<(), io::Error> {
+        self.vmf.write(path)?;
+        self.path = Some(path.to_path_buf());
+        self.mark_saved();
+        Ok(())
+    }
+
+    /// The file name for the window title, with a * when there are unsaved changes
+    pub fn get_title(&self) -> String {
+        let name = self
+            .path
+            .as_ref()
+            .and_then(|x| x.file_name())
+            .map_or(String::from("Untitled"), |x| {
+                x.to_string_lossy().to_string()
+            });
+        if self.is_dirty() {
+            format!("{name}*")
+        } else {
+            name
+        }
+    }
+
+    fn current_id(&self) -> u64 {
+        self.undo.last().map_or(self.base_id, |x| x.id)
+    }
+
+    fn push_undo(&mut self, name: String, commands: Vec<Command>) {
+        let step = Step::new(self.next_id, name, commands);
+        self.next_id += 1;
+        self.memory += step.size;
+        self.undo.push(step);
+        self.trim();
+    }
+
+    fn clear_redo(&mut self) {
+        for step in self.redo.drain(..) {
+            self.memory -= step.size;
+        }
+    }
+
+    /// Drops the oldest steps until the history fits, the state before them can't be reached anymore
+    /// After that the redo steps furthest away go
+    fn trim(&mut self) {
+        while self.memory > self.max_memory && self.undo.len() > 1 {
+            let step = self.undo.remove(0);
+            self.memory -= step.size;
+            self.base_id = step.id;
+        }
+        while self.memory > self.max_memory && !self.redo.is_empty() {
+            self.memory -= self.redo.remove(0).size;
+        }
+    }
+
+    fn end_all_groups(&mut self) {
+        while self.group_depth > 0 {
+            self.end_group();
+        }
+    }
+}
+
+/// Applies the commands in order and returns the commands that revert all of them
+/// If one fails the ones before it get reverted again
+fn apply_all(vmf: &mut VMF, commands: &[Command]) -> Result<Vec<Command>, CommandError> {
+    let mut reverts = vec![];
+    for command in commands {
+        match command.apply(vmf) {
+            Ok(revert) => reverts.push(revert),
+            Err(err) => {
+                for revert in reverts.iter().rev() {
+                    revert.apply(vmf)?;
+                }
+                return Err(err);
+            }
+        }
+    }
+    reverts.reverse();
+    Ok(reverts)
+}
+
+fn get_entity_mut(vmf: &mut VMF, id: i32) -> Result<&mut Entity, CommandError> {
+    if vmf.world.id == id {
+        return Ok(&mut vmf.world);
+    }
+    vmf.entities
+        .iter_mut()
+        .find(|x| x.id == id && !x.hidden)
+        .ok_or(CommandError::MissingEntity(id))
+}
+
+/// The id of the entity the solid belongs to and its index there
+fn find_brush(vmf: &VMF, id: i32) -> Result<(i32, usize), CommandError> {
+    std::iter::once(&vmf.world)
+        .chain(&vmf.entities)
+        .filter(|entity| !entity.hidden)
+        .find_map(|entity| {
+            let index = entity
+                .brushes
+                .iter()
+                .position(|x| x.id == id && !x.hidden)?;
+            Some((entity.id, index))
+        })
+        .ok_or(CommandError::MissingBrush(id))
+}
+
+fn get_brush_mut(vmf: &mut VMF, id: i32) -> Result<&mut Brush, CommandError> {
+    std::iter::once(&mut vmf.world)
+        .chain(&mut vmf.entities)
+        .filter(|x| !x.hidden)
+        .flat_map(|x| &mut x.brushes)
+        .find(|x| x.id == id && !x.hidden)
+        .ok_or(CommandError::MissingBrush(id))
+}
+
+fn get_face_mut(vmf: &mut VMF, brush: i32, side: i32) -> Result<&mut Face, CommandError> {
+    get_brush_mut(vmf, brush)?
+        .faces
+        .iter_mut()
+        .find(|x| x.id == side)
+        .ok_or(CommandError::MissingSide { brush, side })
+}
+
+/// The side and its copy in the shape
+fn get_faces_mut(brush: &mut Brush, side: i32) -> impl Iterator<Item = &mut Face> {
+    brush
+        .faces
+        .iter_mut()
+        .chain(brush.shape.faces.iter_mut().map(|(face, _)| face))
+        .filter(move |x| x.id == side)
+}
+
+fn get_brush_size(brush: &Brush) -> usize {
+    let faces: usize = brush
+        .faces
+        .iter()
+        .chain(brush.shape.faces.iter().map(|(face, _)| face))
+        .map(get_face_size)
+        .sum();
+    size_of::<Brush>() + faces + brush.shape.vertices.len() * size_of::<glm::Vec3>()
+}
+
+fn get_face_size(face: &Face) -> usize {
+    // Every vertex of a displacement has a normal, offset, offset normal, distance and alpha
+    let vertices = face
+        .dispinfo
+        .as_ref()
+        .map_or(0, |x| ((1 << x.power) + 1) * ((1 << x.power) + 1));
+    size_of::<Face>() + face.material.len() + vertices * (3 * size_of::<glm::Vec3>() + 8)
+}
+
+fn get_keyvalues_size(keyvalues: &[(String, String)]) -> usize {
+    keyvalues
+        .iter()
+        .map(|(key, value)| size_of::<(String, String)>() + key.len() + value.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ids::IdRegistry, primitives, test_utils::Rng};
+
+    const MATERIALS: [&str; 3] = [
+        "DEV/DEV_MEASUREGENERIC01B",
+        "TOOLS/TOOLSNODRAW",
+        "BRICK/BRICKWALL001A",
+    ];
+
+    fn get_sample() -> VMF {
+        VMF::parse(Path::new("tests/data/sample.vmf")).unwrap()
+    }
+
+    fn block(registry: &mut IdRegistry, rng: &mut Rng) -> Brush {
+        let mins = glm::vec3(
+            rng.range(-512.0, 512.0) as f32,
+            rng.range(-512.0, 512.0) as f32,
+            0.0,
+        );
+        let size = glm::vec3(
+            rng.range(8.0, 128.0) as f32,
+            rng.range(8.0, 128.0) as f32,
+            64.0,
+        );
+        let mut brush = primitives::block(&mins, &(mins + size), MATERIALS[0]).unwrap();
+        registry.assign_missing(&mut brush);
+        brush
+    }
+
+    /// A random command on the current state, sometimes one that fails
+    fn random_command(vmf: &VMF, registry: &mut IdRegistry, rng: &mut Rng) -> Command {
+        let entities: Vec<&Entity> = std::iter::once(&vmf.world)
+            .chain(&vmf.entities)
+            .filter(|x| !x.hidden)
+            .collect();
+        let brushes: Vec<&Brush> = entities
+            .iter()
+            .flat_map(|x| &x.brushes)
+            .filter(|x| !x.hidden)
+            .collect();
+        let entity = rng.pick(&entities).unwrap().id;
+        let Some(&brush) = rng.pick(&brushes) else {
+            return Command::CreateBrush {
+                entity: vmf.world.id,
+                index: None,
+                brush: block(registry, rng),
+            };
+        };
+        let face = rng.pick(&brush.faces).unwrap();
+        match rng.below(10) {
+            0 => Command::CreateBrush {
+                entity,
+                index: [None, Some(0), Some(rng.below(4))][rng.below(3)],
+                brush: block(registry, rng),
+            },
+            1 => Command::DeleteBrush { id: brush.id },
+            2 => Command::TransformBrush {
+                id: brush.id,
+                matrix: glm::translation(&glm::vec3(rng.range(-64.0, 64.0) as f32, 0.0, 16.0))
+                    * glm::rotation(rng.range(-3.0, 3.0) as f32, &glm::vec3(0.0, 0.0, 1.0)),
+                lock: *rng
+                    .pick(&[
+                        TextureLock::None,
+                        TextureLock::Lock,
+                        TextureLock::ScalingLock,
+                    ])
+                    .unwrap(),
+            },
+            3 => Command::SetMaterial {
+                brush: brush.id,
+                side: face.id,
+                material: rng.pick(&MATERIALS).unwrap().to_string(),
+            },
+            4 => {
+                let mut face = face.clone();
+                face.rotate(rng.range(-180.0, 180.0) as f32);
+                face.shift(rng.range(-32.0, 32.0) as f32, 0.0);
+                Command::SetFace {
+                    brush: brush.id,
+                    face: Box::new(face),
+                }
+            }
+            5 => {
+                let entity = rng.pick(&entities).unwrap();
+                let keys: Vec<&str> = entity.keyvalues.iter().map(|(x, _)| x.as_str()).collect();
+                Command::SetKeyValue {
+                    entity: entity.id,
+                    key: rng.pick(&keys).unwrap_or(&"targetname").to_string(),
+                    value: (rng.below(3) > 0).then(|| format!("value{}", rng.below(100))),
+                }
+            }
+            6 => {
+                let mut entity = Entity::clone(rng.pick(&entities).unwrap());
+                registry.renumber_entity(&mut entity, &mut Default::default());
+                Command::CreateEntity {
+                    index: [None, Some(0)][rng.below(2)],
+                    entity,
+                }
+            }
+            7 => Command::DeleteEntity { id: entity },
+            8 => Command::ReplaceBrush {
+                brush: Brush {
+                    id: brush.id,
+                    ..block(registry, rng)
+                },
+            },
+            // Doesn't exist
+            _ => Command::DeleteBrush { id: -5 },
+        }
+    }
+
+    #[test]
+    fn test_random_undo_redo() {
+        for seed in 1..6u64 {
+            let mut rng = Rng::new(seed);
+            let mut document = Document::new(get_sample());
+            let mut registry = document.vmf().get_id_registry();
+            let mut states = vec![document.vmf().get_string()];
+            for _ in 0..40 {
+                let count = 1 + rng.below(3) * rng.below(2);
+                let before = document.vmf().get_string();
+                document.begin_group("Random");
+                let mut changed = false;
+                for _ in 0..count {
+                    let command = random_command(document.vmf(), &mut registry, &mut rng);
+                    let state = document.vmf().get_string();
+                    match document.execute(command) {
+                        Ok(()) => changed = true,
+                        Err(_) => assert_eq!(document.vmf().get_string(), state),
+                    }
+                }
+                document.end_group();
+                if changed {
+                    states.push(document.vmf().get_string());
+                } else {
+                    assert_eq!(document.vmf().get_string(), before);
+                }
+                assert_eq!(document.undo_count(), states.len() - 1);
+            }
+
+            // Undo everything, redo everything and then jump around
+            let mut position = states.len() - 1;
+            for target in [0, states.len() - 1, rng.below(states.len()), 3, 1] {
+                let target = target.min(states.len() - 1);
+                while position != target {
+                    if position > target {
+                        assert!(document.undo().unwrap());
+                        position -= 1;
+                    } else {
+                        assert!(document.redo().unwrap());
+                        position += 1;
+                    }
+                    assert_eq!(document.vmf().get_string(), states[position]);
+                }
+                assert_eq!(document.is_dirty(), position != 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let mut rng = Rng::new(42);
+        let mut document = Document::new(get_sample());
+        let mut registry = document.vmf().get_id_registry();
+        let world = document.vmf().world.id;
+        let create = |registry: &mut IdRegistry, rng: &mut Rng| Command::CreateBrush {
+            entity: world,
+            index: None,
+            brush: block(registry, rng),
+        };
+        assert!(!document.is_dirty());
+        assert_eq!(document.get_title(), "Untitled");
+        assert!(!document.undo().unwrap());
+
+        document.execute(create(&mut registry, &mut rng)).unwrap();
+        assert!(document.is_dirty());
+        assert_eq!(document.get_title(), "Untitled*");
+        assert_eq!(document.undo_name(), Some("Create solid"));
+        document.undo().unwrap();
+        assert!(!document.is_dirty());
+        assert_eq!(document.redo_name(), Some("Create solid"));
+        document.redo().unwrap();
+        document.mark_saved();
+        document.undo().unwrap();
+        assert!(document.is_dirty());
+        // A new command drops the redo step, so the saved state can't come back
+        document.execute(create(&mut registry, &mut rng)).unwrap();
+        assert_eq!(document.redo_count(), 0);
+        document.undo().unwrap();
+        assert!(document.is_dirty());
+
+        // Nested groups become one step
+        document.begin_group("Outer");
+        document.execute(create(&mut registry, &mut rng)).unwrap();
+        document.begin_group("Inner");
+        document.execute(create(&mut registry, &mut rng)).unwrap();
+        document.end_group();
+        assert_eq!(document.undo_count(), 0);
+        document.end_group();
+        assert_eq!(document.undo_count(), 1);
+        assert_eq!(document.undo_name(), Some("Outer"));
+        let brushes = document.vmf().world.brushes.len();
+        document.undo().unwrap();
+        assert_eq!(document.vmf().world.brushes.len(), brushes - 2);
+
+        // Only the newest steps that fit stay, undoing a transform keeps a copy of the solid
+        let original = document.vmf().get_string();
+        let brush = document.vmf().world.brushes[0].clone();
+        let size = Command::ReplaceBrush {
+            brush: brush.clone(),
+        }
+        .get_size();
+        document.set_max_memory(size * 3);
+        for _ in 0..10 {
+            let command = Command::TransformBrush {
+                id: brush.id,
+                matrix: glm::translation(&glm::vec3(16.0, 0.0, 0.0)),
+                lock: TextureLock::Lock,
+            };
+            document.execute(command).unwrap();
+            assert!(document.get_memory() <= size * 3);
+        }
+        assert_eq!(document.undo_count(), 3);
+        while document.undo().unwrap() {}
+        assert_ne!(document.vmf().get_string(), original);
+        assert!(document.is_dirty());
+        document.set_max_memory(0);
+        assert_eq!(document.redo_count(), 0);
+        assert_eq!(document.get_memory(), 0);
+    }
+}
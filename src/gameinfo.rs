@@ -4,25 +4,55 @@ use std::{
     path::{self, Path, PathBuf},
 };
 
-use crate::{keyvalue::KeyValues, vpk::VPK};
+use serde::Deserialize;
+
+use crate::{
+    keyvalue::{de, Conditions, KeyValues},
+    vpk::VPK,
+};
+
+/// The parts of gameinfo.txt that are used, everything else is ignored
+#[derive(Deserialize)]
+struct GameinfoFile {
+    gameinfo: GameinfoBlock,
+}
+
+#[derive(Deserialize)]
+struct GameinfoBlock {
+    filesystem: FileSystem,
+}
+
+#[derive(Deserialize)]
+struct FileSystem {
+    /// The keys are the kinds of search path, so they have to stay pairs
+    searchpaths: KeyValues<'static>,
+}
 
 pub struct Gameinfo {
     search_paths: Vec<SearchPathProvider>,
+    conditions: Conditions,
 }
 
 impl Gameinfo {
     pub fn parse(file: &Path) -> Option<Self> {
-        let kv = KeyValues::parse(file).ok()?;
+        Self::parse_with_conditions(file, Conditions::default())
+    }
+
+    /// The conditions are used for the gameinfo and every KeyValues file loaded from the search path
+    pub fn parse_with_conditions(file: &Path, conditions: Conditions) -> Option<Self> {
+        let kv = KeyValues::parse_with_conditions(file, &conditions).ok()?;
+        let gameinfo: GameinfoFile = match de::from_keyvalues(&kv) {
+            Ok(gameinfo) => gameinfo,
+            Err(err) => {
+                eprintln!("Couldn't load {:?}: {}", file, err);
+                return None;
+            }
+        };
 
         let mut search_paths = vec![];
-        for (keys, path) in kv
-            .get("gameinfo")?
-            .get("filesystem")?
-            .get("searchpaths")?
-            .get_all_kv_pairs()
-        {
+        for (keys, path) in gameinfo.gameinfo.filesystem.searchpaths.get_all_kv_pairs() {
             // Just make this lowercase to be sure it doesn't break. Even the Valve Developer Community page on gameinfo uses upper- and lowercase
-            let keys: Vec<String> = keys.split('+').map(String::from).collect();
+            let keys: Vec<String> = keys.split('+').map(|x| x.to_lowercase()).collect();
             // TF2 (and maybe other games) uses download to store assets downloaded from community servers
             // I've decided to exclude these, so that mappers dont accidentially use them (although stock hammer doesn't exclude them (This might be the first intentional difference (Why do i write bracket sentences in other bracket sentences)))
             if (keys.contains(&String::from("game")) || keys.contains(&String::from("mod")))
@@ -61,7 +91,14 @@ impl Gameinfo {
                 }
             }
         }
-        Some(Self { search_paths })
+        Some(Self {
+            search_paths,
+            conditions,
+        })
+    }
+
+    pub fn conditions(&self) -> &Conditions {
+        &self.conditions
     }
 
     pub fn get_file(&self, path: &str, extension: &str) -> Option<Vec<u8>> {
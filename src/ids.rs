@@ -0,0 +1,386 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::vmf::{Brush, Entity, VMF};
+
+/// Entity keyvalues that list side ids separated by spaces, e.g. the sides an overlay is on
+const SIDE_LIST_KEYS: [&str; 2] = ["sides", "sidelist"];
+
+/// What an id belongs to, solids and entities share their ids like in Hammer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IdKind {
+    Entity,
+    Solid,
+    Side,
+}
+
+impl std::fmt::Display for IdKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            IdKind::Entity => "entity",
+            IdKind::Solid => "solid",
+            IdKind::Side => "side",
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum IdError {
+    #[error("{kind} id {id} is used more than once")]
+    Duplicate { kind: IdKind, id: i32 },
+    #[error("{kind} has no id")]
+    Missing { kind: IdKind },
+    #[error("entity {entity} refers to side {side}, which doesn't exist")]
+    UnknownSide { entity: i32, side: i32 },
+}
+
+/// Hands out ids that aren't used in the map yet
+/// Only knows the highest ids, objects added without it can still take ids it hands out
+#[derive(Debug, Clone)]
+pub struct IdRegistry {
+    next_id: i32,
+    next_side_id: i32,
+}
+
+/// Which ids got replaced by which, to fix references to them
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IdRemap {
+    /// Entities and solids
+    pub objects: HashMap<i32, i32>,
+    pub sides: HashMap<i32, i32>,
+}
+
+impl IdRegistry {
+    pub fn new(vmf: &VMF) -> Self {
+        let mut max_id = 0;
+        let mut max_side_id = 0;
+        for entity in get_entities(vmf) {
+            max_id = max_id.max(entity.id);
+            for brush in &entity.brushes {
+                max_id = max_id.max(brush.id);
+                for face in &brush.faces {
+                    max_side_id = max_side_id.max(face.id);
+                }
+            }
+        }
+        Self {
+            next_id: max_id + 1,
+            next_side_id: max_side_id + 1,
+        }
+    }
+
+    /// A new id for an entity or solid
+    pub fn next_id(&mut self) -> i32 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    pub fn next_side_id(&mut self) -> i32 {
+        self.next_side_id += 1;
+        self.next_side_id - 1
+    }
+
+    /// Gives the brush and its sides ids if they don't have one yet, e.g. ones made by csg or primitives
+    pub fn assign_missing(&mut self, brush: &mut Brush) {
+        if brush.id <= 0 {
+            brush.id = self.next_id();
+        }
+        for face in &mut brush.faces {
+            if face.id <= 0 {
+                face.id = self.next_side_id();
+            }
+        }
+        sync_shape_ids(brush);
+    }
+
+    /// Gives the brush and all its sides new ids, e.g. when pasting
+    pub fn renumber_brush(&mut self, brush: &mut Brush, remap: &mut IdRemap) {
+        let id = self.next_id();
+        remap.objects.insert(brush.id, id);
+        brush.id = id;
+        for face in &mut brush.faces {
+            let id = self.next_side_id();
+            remap.sides.insert(face.id, id);
+            face.id = id;
+        }
+        sync_shape_ids(brush);
+    }
+
+    /// Gives the entity, its brushes and their sides new ids
+    /// References to sides of other objects stay as they are, use IdRemap::update_references
+    /// once everything that gets pasted together is renumbered
+    pub fn renumber_entity(&mut self, entity: &mut Entity, remap: &mut IdRemap) {
+        let id = self.next_id();
+        remap.objects.insert(entity.id, id);
+        entity.id = id;
+        for brush in &mut entity.brushes {
+            self.renumber_brush(brush, remap);
+        }
+    }
+}
+
+impl IdRemap {
+    /// Points the side lists of the entity to the new side ids
+    /// Ids that weren't remapped or aren't numbers are kept
+    pub fn update_references(&self, entity: &mut Entity) {
+        for (key, value) in &mut entity.keyvalues {
+            if !SIDE_LIST_KEYS.iter().any(|x| key.eq_ignore_ascii_case(x)) {
+                continue;
+            }
+            *value = value
+                .split_whitespace()
+                .map(|side| match side.parse() {
+                    Ok(id) => self.sides.get(&id).unwrap_or(&id).to_string(),
+                    Err(_) => side.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+        }
+    }
+}
+
+impl VMF {
+    pub fn get_id_registry(&self) -> IdRegistry {
+        IdRegistry::new(self)
+    }
+
+    /// Finds ids used more than once, objects without ids and side lists naming sides that don't exist
+    pub fn validate_ids(&self) -> Vec<IdError> {
+        let mut errors = vec![];
+        let mut ids = HashSet::new();
+        let mut side_ids = HashSet::new();
+        let mut check = |kind, id, seen: &mut HashSet<i32>| {
+            if id <= 0 {
+                errors.push(IdError::Missing { kind });
+            } else if !seen.insert(id) {
+                errors.push(IdError::Duplicate { kind, id });
+            }
+        };
+        for entity in get_entities(self) {
+            check(IdKind::Entity, entity.id, &mut ids);
+            for brush in &entity.brushes {
+                check(IdKind::Solid, brush.id, &mut ids);
+                for face in &brush.faces {
+                    check(IdKind::Side, face.id, &mut side_ids);
+                }
+            }
+        }
+        for entity in get_entities(self) {
+            for side in get_side_references(entity) {
+                if !side_ids.contains(&side) {
+                    errors.push(IdError::UnknownSide {
+                        entity: entity.id,
+                        side,
+                    });
+                }
+            }
+        }
+        errors
+    }
+
+    /// Gives objects with a duplicate or missing id a new one
+    /// The first object with an id keeps it, so side lists keep pointing to the same sides
+    pub fn fix_ids(&mut self) {
+        let mut registry = self.get_id_registry();
+        let mut ids = HashSet::new();
+        let mut side_ids = HashSet::new();
+        for entity in get_entities_mut(self) {
+            fix_id(&mut entity.id, &mut ids, || registry.next_id());
+            for brush in &mut entity.brushes {
+                fix_id(&mut brush.id, &mut ids, || registry.next_id());
+                for face in &mut brush.faces {
+                    fix_id(&mut face.id, &mut side_ids, || registry.next_side_id());
+                }
+                sync_shape_ids(brush);
+            }
+        }
+    }
+
+    /// Changes ids everywhere in the map, including in side lists of entities
+    pub fn remap_ids(&mut self, remap: &IdRemap) {
+        for entity in get_entities_mut(self) {
+            if let Some(&id) = remap.objects.get(&entity.id) {
+                entity.id = id;
+            }
+            for brush in &mut entity.brushes {
+                if let Some(&id) = remap.objects.get(&brush.id) {
+                    brush.id = id;
+                }
+                for face in &mut brush.faces {
+                    if let Some(&id) = remap.sides.get(&face.id) {
+                        face.id = id;
+                    }
+                }
+                sync_shape_ids(brush);
+            }
+            remap.update_references(entity);
+        }
+    }
+}
+
+/// The world, then all entities including hidden ones
+fn get_entities(vmf: &VMF) -> impl Iterator<Item = &Entity> {
+    std::iter::once(&vmf.world).chain(&vmf.entities)
+}
+
+fn get_entities_mut(vmf: &mut VMF) -> impl Iterator<Item = &mut Entity> {
+    std::iter::once(&mut vmf.world).chain(&mut vmf.entities)
+}
+
+/// Replaces the id if it's missing or was seen before
+fn fix_id(id: &mut i32, seen: &mut HashSet<i32>, new_id: impl FnOnce() -> i32) {
+    if *id <= 0 || !seen.insert(*id) {
+        *id = new_id();
+        seen.insert(*id);
+    }
+}
+
+/// The shape has its own copies of the sides, they get the ids of the sides on the same plane
+fn sync_shape_ids(brush: &mut Brush) {
+    for (face, _) in &mut brush.shape.faces {
+        let plane = face.get_plane();
+        if let Some(side) = brush.faces.iter().find(|x| x.get_plane() == plane) {
+            face.id = side.id;
+        }
+    }
+}
+
+/// The side ids in the side lists of the entity
+fn get_side_references(entity: &Entity) -> impl Iterator<Item = i32> + '_ {
+    entity
+        .keyvalues
+        .iter()
+        .filter(|(key, _)| SIDE_LIST_KEYS.iter().any(|x| key.eq_ignore_ascii_case(x)))
+        .flat_map(|(_, value)| value.split_whitespace())
+        .filter_map(|side| side.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra_glm as glm;
+
+    use crate::primitives;
+
+    use super::*;
+
+    fn get_sample() -> VMF {
+        let (vmf, warnings) =
+            VMF::parse_lenient(std::path::Path::new("tests/data/sample.vmf")).unwrap();
+        assert!(warnings.is_empty());
+        vmf
+    }
+
+    fn overlay(id: i32, sides: &str) -> Entity {
+        let mut entity = get_sample().entities.remove(0);
+        entity.id = id;
+        entity.brushes.clear();
+        entity.keyvalues = vec![
+            (String::from("classname"), String::from("info_overlay")),
+            (String::from("sides"), String::from(sides)),
+        ];
+        entity
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut vmf = get_sample();
+        assert_eq!(vmf.validate_ids(), vec![]);
+        let mut registry = vmf.get_id_registry();
+        let first = registry.next_id();
+        assert_eq!(registry.next_id(), first + 1);
+
+        let mut brush = primitives::cylinder(
+            &glm::vec3(0.0, 0.0, 0.0),
+            &glm::vec3(64.0, 64.0, 64.0),
+            8,
+            "DEV/DEV_MEASUREGENERIC01B",
+        )
+        .unwrap();
+        registry.assign_missing(&mut brush);
+        assert_eq!(brush.id, first + 2);
+        assert!(brush.faces.iter().all(|x| x.id > 0));
+        assert!(brush.shape.faces.iter().all(|(x, _)| x.id > 0));
+        vmf.world.brushes.push(brush);
+        assert_eq!(vmf.validate_ids(), vec![]);
+    }
+
+    #[test]
+    fn test_paste() {
+        let mut vmf = get_sample();
+        let side = vmf.world.brushes[0].faces[0].id;
+        vmf.entities.push(overlay(500, &format!("{side} 9999")));
+        assert_eq!(
+            vmf.validate_ids(),
+            vec![IdError::UnknownSide {
+                entity: 500,
+                side: 9999
+            }]
+        );
+
+        // Pasting a brush and an overlay on it again
+        let mut brush = vmf.world.brushes[0].clone();
+        let mut entity = overlay(500, &side.to_string());
+        let mut copy = vmf.clone();
+        copy.world.brushes.push(brush.clone());
+        copy.entities.push(overlay(500, &side.to_string()));
+        assert_eq!(
+            copy.validate_ids()[..2],
+            [
+                IdError::Duplicate {
+                    kind: IdKind::Solid,
+                    id: brush.id
+                },
+                IdError::Duplicate {
+                    kind: IdKind::Side,
+                    id: side
+                }
+            ]
+        );
+
+        let mut registry = vmf.get_id_registry();
+        let mut remap = IdRemap::default();
+        registry.renumber_brush(&mut brush, &mut remap);
+        registry.renumber_entity(&mut entity, &mut remap);
+        remap.update_references(&mut entity);
+        let new_side = brush.faces[0].id;
+        assert_ne!(new_side, side);
+        assert_eq!(brush.shape.faces[0].0.id, new_side);
+        assert_eq!(entity.get("sides"), Some(new_side.to_string().as_str()));
+        vmf.world.brushes.push(brush);
+        vmf.entities.push(entity);
+        assert_eq!(vmf.validate_ids().len(), 1);
+    }
+
+    #[test]
+    fn test_fix_and_remap() {
+        let mut vmf = get_sample();
+        let side = vmf.world.brushes[0].faces[0].id;
+        let brush = vmf.world.brushes[0].clone();
+        vmf.world.brushes.push(brush);
+        let mut missing = vmf.world.brushes[0].clone();
+        missing.id = 0;
+        vmf.entities[0].brushes.push(missing);
+        vmf.entities.push(overlay(500, &side.to_string()));
+        assert!(vmf.validate_ids().contains(&IdError::Missing {
+            kind: IdKind::Solid
+        }));
+        vmf.fix_ids();
+        assert_eq!(vmf.validate_ids(), vec![]);
+        // The original keeps its ids, so the overlay still is on it
+        assert_eq!(vmf.world.brushes[0].faces[0].id, side);
+        for brush in &vmf.world.brushes {
+            for (face, (shape_face, _)) in brush.faces.iter().zip(&brush.shape.faces) {
+                assert_eq!(face.id, shape_face.id);
+            }
+        }
+
+        let mut remap = IdRemap::default();
+        remap.sides.insert(side, 12345);
+        remap.objects.insert(500, 600);
+        vmf.remap_ids(&remap);
+        assert_eq!(vmf.world.brushes[0].faces[0].id, 12345);
+        let overlay = vmf.entities.last().unwrap();
+        assert_eq!(overlay.id, 600);
+        assert_eq!(overlay.get("sides"), Some("12345"));
+        assert_eq!(vmf.validate_ids(), vec![]);
+    }
+}
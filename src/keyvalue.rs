@@ -1,86 +1,472 @@
-use std::{fs, io, iter::Peekable, path::Path, str::Chars};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use nalgebra_glm as glm;
 
 use crate::gameinfo::Gameinfo;
 
+pub mod de;
+pub mod document;
+pub mod ser;
+
 /// Parser for the KeyValues format
 /// https://developer.valvesoftware.com/wiki/KeyValues
-/// Conditionl statements don't work (for now)
+/// Conditional statements like [$WIN32] are evaluated while parsing, see Conditions
+/// Every node remembers where its key started in the file
+/// Values are always stored as text, value_type only matters for binary KeyValues
+/// Use document::Document to edit a file without losing comments and formatting
+/// Text borrows from the parsed content where it can, into_owned detaches it
 #[derive(Debug, Clone)]
-pub enum KeyValues {
-    Value { value: String },
-    List { subkeys: Vec<(String, KeyValues)> },
+pub enum KeyValues<'a> {
+    Value {
+        value: Cow<'a, str>,
+        value_type: ValueType,
+        span: Span,
+    },
+    List {
+        subkeys: Vec<(Cow<'a, str>, KeyValues<'a>)>,
+        span: Span,
+    },
+}
+
+/// A position inside a file
+/// Line and column start at 1, a line of 0 means the position is unknown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The type of a value in binary KeyValues
+/// Numbers are stored in their shortest text form that parses back to the same number, so nothing is lost
+/// Colors are stored as "r g b a"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueType {
+    #[default]
+    String,
+    Int,
+    Float,
+    Ptr,
+    WString,
+    Color,
+    UInt64,
+    Int64,
+}
+
+impl ValueType {
+    const NONE: u8 = 0;
+    const STRING: u8 = 1;
+    const INT: u8 = 2;
+    const FLOAT: u8 = 3;
+    const PTR: u8 = 4;
+    const WSTRING: u8 = 5;
+    const COLOR: u8 = 6;
+    const UINT64: u8 = 7;
+    const END: u8 = 8;
+    const INT64: u8 = 10;
+    // Some Steam files use this instead
+    const ALTERNATE_END: u8 = 11;
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            Self::STRING => Some(Self::String),
+            Self::INT => Some(Self::Int),
+            Self::FLOAT => Some(Self::Float),
+            Self::PTR => Some(Self::Ptr),
+            Self::WSTRING => Some(Self::WString),
+            Self::COLOR => Some(Self::Color),
+            Self::UINT64 => Some(Self::UInt64),
+            Self::INT64 => Some(Self::Int64),
+            _ => None,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::String => Self::STRING,
+            Self::Int => Self::INT,
+            Self::Float => Self::FLOAT,
+            Self::Ptr => Self::PTR,
+            Self::WString => Self::WSTRING,
+            Self::Color => Self::COLOR,
+            Self::UInt64 => Self::UINT64,
+            Self::Int64 => Self::INT64,
+        }
+    }
+}
+
+/// The symbols conditional statements are checked against
+/// Symbols are stored without the $ and are case insensitive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conditions {
+    symbols: HashSet<String>,
+}
+
+impl Conditions {
+    pub fn new(symbols: &[&str]) -> Self {
+        Self {
+            symbols: symbols.iter().map(|x| x.to_uppercase()).collect(),
+        }
+    }
+
+    pub fn windows() -> Self {
+        Self::new(&["WIN32", "WINDOWS"])
+    }
+
+    pub fn linux() -> Self {
+        Self::new(&["LINUX", "POSIX"])
+    }
+
+    pub fn osx() -> Self {
+        Self::new(&["OSX", "POSIX"])
+    }
+
+    pub fn x360() -> Self {
+        Self::new(&["X360"])
+    }
+
+    pub fn ps3() -> Self {
+        Self::new(&["PS3"])
+    }
+
+    pub fn define(&mut self, symbol: &str) {
+        self.symbols.insert(symbol.to_uppercase());
+    }
+
+    pub fn undefine(&mut self, symbol: &str) {
+        self.symbols.remove(&symbol.to_uppercase());
+    }
+
+    pub fn is_defined(&self, symbol: &str) -> bool {
+        self.symbols.contains(&symbol.to_uppercase())
+    }
+
+    /// Evaluates the inside of a conditional, like "!$X360" or "$WIN32||$OSX"
+    /// && binds stronger than ||, just like in C
+    /// Returns None if the conditional is malformed
+    pub fn evaluate(&self, condition: &str) -> Option<bool> {
+        let mut result = false;
+        for alternative in condition.split("||") {
+            let mut all = true;
+            for term in alternative.split("&&") {
+                let term = term.trim();
+                let (negated, term) = match term.strip_prefix('!') {
+                    Some(term) => (true, term.trim_start()),
+                    None => (false, term),
+                };
+                let symbol = term.strip_prefix('$')?;
+                if symbol.is_empty()
+                    || !symbol
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    return None;
+                }
+                all &= self.is_defined(symbol) != negated;
+            }
+            result |= all;
+        }
+        Some(result)
+    }
+}
+
+impl Default for Conditions {
+    /// Hammer only runs on windows, so that's what most files expect
+    fn default() -> Self {
+        Self::windows()
+    }
+}
+
+/// Where a parse error happened
+/// The include chain contains every #include/#base that lead to the file, the outermost one first
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Location {
+    pub file: String,
+    pub span: Span,
+    pub include_chain: Vec<(String, Span)>,
+}
+
+impl fmt::Display for Location {
+    /// Formatted so it can be appended to an error message, empty if nothing is known
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.span.line != 0 {
+            match self.file.as_str() {
+                "" => write!(f, " at {}", self.span)?,
+                file => write!(f, " at {}:{}", file, self.span)?,
+            }
+        }
+        for (file, span) in self.include_chain.iter().rev() {
+            write!(f, " (included from {}:{})", file, span)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(thiserror::Error, Debug, PartialEq)]
 pub enum KeyValuesError {
-    #[error("invalid escape sequence \\{0}")]
-    InvalidEscape(char),
-    #[error("unexpected end")]
-    UnexpectedEnd,
-    #[error("unexpected closing brace after {0}")]
-    UnexpectedClosingBrace(String),
-    #[error("unknown macro \"{0}\"")]
-    UnknownMacro(String),
-    #[error("invalid file \"{0}\"")]
-    InvalidFile(String),
-}
-
-impl KeyValues {
+    #[error("invalid escape sequence \\{0}{1}")]
+    InvalidEscape(char, Location),
+    #[error("unexpected end{0}")]
+    UnexpectedEnd(Location),
+    #[error("unexpected closing brace after {0}{1}")]
+    UnexpectedClosingBrace(String, Location),
+    #[error("unknown macro \"{0}\"{1}")]
+    UnknownMacro(String, Location),
+    #[error("invalid file \"{0}\"{1}")]
+    InvalidFile(String, Location),
+    #[error("invalid conditional \"{0}\"{1}")]
+    InvalidConditional(String, Location),
+    #[error("include cycle, \"{0}\" is already being parsed{1}")]
+    IncludeCycle(String, Location),
+    #[error("invalid binary KeyValues at byte {1}: {0}")]
+    InvalidBinary(String, usize),
+}
+
+/// Errors from looking up and converting values with a path
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum QueryError {
+    #[error("missing key \"{0}\"")]
+    Missing(String),
+    #[error("\"{0}\" is a value, not a block")]
+    NotABlock(String),
+    #[error("\"{0}\" is a block, not a value")]
+    NotAValue(String),
+    #[error("expected {expected} for \"{path}\", found \"{value}\"{}", describe_span(.span))]
+    InvalidValue {
+        path: String,
+        value: String,
+        expected: &'static str,
+        span: Span,
+    },
+}
+
+/// Formats a span so it can be appended to an error message, empty if it is unknown
+fn describe_span(span: &Span) -> String {
+    Location {
+        span: *span,
+        ..Default::default()
+    }
+    .to_string()
+}
+
+impl KeyValuesError {
+    /// Binary KeyValues don't have lines, so their errors have no location
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            KeyValuesError::InvalidEscape(_, location)
+            | KeyValuesError::UnexpectedEnd(location)
+            | KeyValuesError::UnexpectedClosingBrace(_, location)
+            | KeyValuesError::UnknownMacro(_, location)
+            | KeyValuesError::InvalidFile(_, location)
+            | KeyValuesError::InvalidConditional(_, location)
+            | KeyValuesError::IncludeCycle(_, location) => Some(location),
+            KeyValuesError::InvalidBinary(..) => None,
+        }
+    }
+
+    fn location_mut(&mut self) -> Option<&mut Location> {
+        match self {
+            KeyValuesError::InvalidEscape(_, location)
+            | KeyValuesError::UnexpectedEnd(location)
+            | KeyValuesError::UnexpectedClosingBrace(_, location)
+            | KeyValuesError::UnknownMacro(_, location)
+            | KeyValuesError::InvalidFile(_, location)
+            | KeyValuesError::InvalidConditional(_, location)
+            | KeyValuesError::IncludeCycle(_, location) => Some(location),
+            KeyValuesError::InvalidBinary(..) => None,
+        }
+    }
+}
+
+impl<'a> KeyValues<'a> {
     /// Reads a file and parses it into KeyValues
     /// Errors if the file has invalid syntax
-    pub fn parse(file: &Path) -> Result<Self, KeyValuesError> {
-        let content = fs::read_to_string(file)
-            .map_err(|_| KeyValuesError::InvalidFile(file.to_string_lossy().to_string()))?;
-        let mut content = content.chars().peekable();
-        Self::parse_internal(&mut content, &|x| {
+    pub fn parse(file: &Path) -> Result<KeyValues<'static>, KeyValuesError> {
+        KeyValues::parse_with_conditions(file, &Conditions::default())
+    }
+
+    /// Same as parse, but conditionals are checked against the given symbols instead of the windows ones
+    pub fn parse_with_conditions(
+        file: &Path,
+        conditions: &Conditions,
+    ) -> Result<KeyValues<'static>, KeyValuesError> {
+        Self::parse_included(file, conditions, &[])
+    }
+
+    /// chain has every file that is currently being parsed, to find include cycles
+    fn parse_included(
+        file: &Path,
+        conditions: &Conditions,
+        chain: &[PathBuf],
+    ) -> Result<KeyValues<'static>, KeyValuesError> {
+        KeyValues::parse_str_included(&Self::read_file(file)?, file, conditions, chain)
+            .map(KeyValues::into_owned)
+    }
+
+    /// Reads a file so it can be given to parse_str
+    pub fn read_file(file: &Path) -> Result<String, KeyValuesError> {
+        fs::read_to_string(file).map_err(|_| {
+            KeyValuesError::InvalidFile(file.to_string_lossy().to_string(), Location::default())
+        })
+    }
+
+    /// Parses the content of file without copying the text
+    /// Use this for big files like VMFs, when the KeyValues don't have to outlive the content
+    /// Includes are loaded relative to file
+    pub fn parse_str(
+        content: &'a str,
+        file: &Path,
+        conditions: &Conditions,
+    ) -> Result<Self, KeyValuesError> {
+        Self::parse_str_included(content, file, conditions, &[])
+    }
+
+    fn parse_str_included(
+        content: &'a str,
+        file: &Path,
+        conditions: &Conditions,
+        chain: &[PathBuf],
+    ) -> Result<Self, KeyValuesError> {
+        // The same file can be reached through different paths, so compare the canonical ones
+        let canonical = |path: &Path| fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        let mut chain = chain.to_vec();
+        chain.push(canonical(file));
+        Self::parse_internal(content, &file.to_string_lossy(), conditions, &|x| {
+            // parse_str can be given any path, a bare file name has an empty parent
             let include_path = file
                 .parent()
-                .expect("is safe if we can read the file")
-                .join(x);
-            Self::parse(&include_path)
+                .unwrap_or(Path::new(""))
+                .join(x.replace('\\', "/"));
+            if chain.contains(&canonical(&include_path)) {
+                return Err(KeyValuesError::IncludeCycle(x, Location::default()));
+            }
+            KeyValues::parse_included(&include_path, conditions, &chain)
         })
     }
 
+    /// Reads a file from the search path of a game
+    /// Includes are relative to the directory of the file, and are also loaded from the search path
     pub fn parse_from_searchpath(
         gameinfo: &Gameinfo,
         path: &str,
         extension: &str,
-    ) -> Result<Self, KeyValuesError> {
+    ) -> Result<KeyValues<'static>, KeyValuesError> {
+        Self::parse_from_searchpath_included(gameinfo, path, extension, &[])
+    }
+
+    /// chain has the lowercase path of every file that is currently being parsed
+    fn parse_from_searchpath_included(
+        gameinfo: &Gameinfo,
+        path: &str,
+        extension: &str,
+        chain: &[String],
+    ) -> Result<KeyValues<'static>, KeyValuesError> {
+        let name = format!("(SearchPath) {}.{}", path, extension);
         let content = gameinfo
             .get_file(path, extension)
-            .ok_or(KeyValuesError::InvalidFile(format!(
-                "(SearchPath) {}.{}",
-                path, extension
-            )))?;
-        let content = String::from_utf8(content).or(Err(KeyValuesError::InvalidFile(format!(
-            "(SearchPath) {}.{}",
-            path, extension
-        ))))?;
-        let mut content = content.chars().peekable();
-        Self::parse_internal(&mut content, &|x| {
-            let (p, e) = x
-                .split_once('.')
-                .ok_or(KeyValuesError::InvalidFile(format!(
-                    "(SearchPath) {} (inside {}.{})",
-                    x, path, extension
-                )))?; // Should be safe to assume filenames only contain one dot
-            Self::parse_from_searchpath(gameinfo, p, e)
+            .ok_or(KeyValuesError::InvalidFile(
+                name.clone(),
+                Location::default(),
+            ))?;
+        let content = String::from_utf8(content).or(Err(KeyValuesError::InvalidFile(
+            name.clone(),
+            Location::default(),
+        )))?;
+        let mut chain = chain.to_vec();
+        chain.push(format!("{}.{}", path, extension).to_lowercase());
+        KeyValues::parse_internal(&content, &name, gameinfo.conditions(), &|x| {
+            let include = match path.rsplit_once('/') {
+                Some((directory, _)) => format!("{}/{}", directory, x.replace('\\', "/")),
+                None => x.replace('\\', "/"),
+            };
+            if chain.contains(&include.to_lowercase()) {
+                return Err(KeyValuesError::IncludeCycle(x, Location::default()));
+            }
+            let (p, e) = include.rsplit_once('.').ok_or(KeyValuesError::InvalidFile(
+                format!("(SearchPath) {}", include),
+                Location::default(),
+            ))?;
+            KeyValues::parse_from_searchpath_included(gameinfo, p, e, &chain)
         })
+        .map(KeyValues::into_owned)
+    }
+
+    pub fn new_value(value: impl Into<Cow<'a, str>>) -> Self {
+        Self::new_typed_value(value, ValueType::String)
+    }
+
+    /// The value has to be in the text form described on ValueType, otherwise writing binary fails
+    pub fn new_typed_value(value: impl Into<Cow<'a, str>>, value_type: ValueType) -> Self {
+        Self::Value {
+            value: value.into(),
+            value_type,
+            span: Span::default(),
+        }
+    }
+
+    pub fn new_list<K: Into<Cow<'a, str>>>(subkeys: Vec<(K, Self)>) -> Self {
+        Self::List {
+            subkeys: subkeys
+                .into_iter()
+                .map(|(key, value)| (key.into(), value))
+                .collect(),
+            span: Span::default(),
+        }
+    }
+
+    /// Copies all borrowed text, so the KeyValues can outlive what they were parsed from
+    pub fn into_owned(self) -> KeyValues<'static> {
+        match self {
+            KeyValues::Value {
+                value,
+                value_type,
+                span,
+            } => KeyValues::Value {
+                value: Cow::Owned(value.into_owned()),
+                value_type,
+                span,
+            },
+            KeyValues::List { subkeys, span } => KeyValues::List {
+                subkeys: subkeys
+                    .into_iter()
+                    .map(|(key, value)| (Cow::Owned(key.into_owned()), value.into_owned()))
+                    .collect(),
+                span,
+            },
+        }
+    }
+
+    /// Gets the position of the key of this node in the file it was parsed from
+    /// Is unknown (line 0) for nodes that weren't parsed
+    pub fn span(&self) -> Span {
+        match self {
+            KeyValues::Value { span, .. } | KeyValues::List { span, .. } => *span,
+        }
     }
 
     /// Gets the first value with the specified name
     /// Return None if the name does not exist
     /// Only works on KeyValues::List
-    /// Name should always be lowercase
+    /// Names are case insensitive
     pub fn get(&self, name: &str) -> Option<&Self> {
         match self {
             KeyValues::Value { .. } => {
                 eprintln!("Tried to get element from KeyValues::Value");
                 None
             }
-            KeyValues::List { subkeys } => {
+            KeyValues::List { subkeys, .. } => {
                 for (key_name, value) in subkeys {
-                    if key_name == name {
+                    if key_name.eq_ignore_ascii_case(name) {
                         return Some(value);
                     }
                 }
@@ -91,40 +477,160 @@ impl KeyValues {
 
     /// Gets every value with the specified name
     /// Only works on KeyValues::List
-    /// Name should always be lowercase
+    /// Names are case insensitive
     pub fn get_all(&self, name: &str) -> Vec<&Self> {
         match self {
             KeyValues::Value { .. } => {
                 eprintln!("Tried to get elements from KeyValues::Value");
                 vec![]
             }
-            KeyValues::List { subkeys } => subkeys
+            KeyValues::List { subkeys, .. } => subkeys
                 .iter()
-                .filter_map(|(key_name, value)| if key_name == name { Some(value) } else { None })
+                .filter_map(|(key_name, value)| {
+                    if key_name.eq_ignore_ascii_case(name) {
+                        Some(value)
+                    } else {
+                        None
+                    }
+                })
                 .collect(),
         }
     }
 
     /// Gets every pair of key/value in this subkey
     /// Only works on KeyValues::List
-    /// Keys are always lowercase
-    pub fn get_all_kv_pairs(&self) -> Vec<(&String, &Self)> {
+    /// Keys keep the case they had in the file
+    pub fn get_all_kv_pairs(&self) -> Vec<(&str, &Self)> {
         match self {
             KeyValues::Value { .. } => {
                 eprintln!("Tried to get KVs from KeyValues::Value");
                 vec![]
             }
-            KeyValues::List { subkeys } => {
-                subkeys.iter().map(|(name, value)| (name, value)).collect()
+            KeyValues::List { subkeys, .. } => subkeys
+                .iter()
+                .map(|(name, value)| (name.as_ref(), value))
+                .collect(),
+        }
+    }
+
+    /// Gets the first node at a path like "gameinfo/filesystem/searchpaths"
+    /// Keys are case insensitive, just like in Source
+    pub fn query(&self, path: &str) -> Option<&Self> {
+        self.try_query(path).ok()
+    }
+
+    /// Same as query, but says what went wrong
+    pub fn try_query(&self, path: &str) -> Result<&Self, QueryError> {
+        let mut current = self;
+        let mut walked = String::new();
+        for key in path.split('/').filter(|x| !x.is_empty()) {
+            let KeyValues::List { subkeys, .. } = current else {
+                return Err(QueryError::NotABlock(walked));
+            };
+            if !walked.is_empty() {
+                walked.push('/');
+            }
+            walked.push_str(key);
+            current = subkeys
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+                .map(|(_, value)| value)
+                .ok_or_else(|| QueryError::Missing(walked.clone()))?;
+        }
+        Ok(current)
+    }
+
+    /// Gets the value at a path as text
+    pub fn get_str(&self, path: &str) -> Result<&str, QueryError> {
+        match self.try_query(path)? {
+            KeyValues::Value { value, .. } => Ok(value),
+            KeyValues::List { .. } => Err(QueryError::NotAValue(path.to_string())),
+        }
+    }
+
+    pub fn get_int(&self, path: &str) -> Result<i32, QueryError> {
+        self.get_parsed(path, "an integer", |x| x.trim().parse().ok())
+    }
+
+    pub fn get_float(&self, path: &str) -> Result<f32, QueryError> {
+        self.get_parsed(path, "a number", |x| x.trim().parse().ok())
+    }
+
+    /// Like Source, every integer that isn't 0 is true
+    /// Also accepts "true" and "false"
+    pub fn get_bool(&self, path: &str) -> Result<bool, QueryError> {
+        self.get_parsed(path, "a boolean", |x| {
+            let x = x.trim();
+            if x.eq_ignore_ascii_case("true") {
+                Some(true)
+            } else if x.eq_ignore_ascii_case("false") {
+                Some(false)
+            } else {
+                x.parse::<i32>().ok().map(|x| x != 0)
+            }
+        })
+    }
+
+    /// Gets a vector written as "x y z"
+    pub fn get_vec3(&self, path: &str) -> Result<glm::Vec3, QueryError> {
+        self.get_parsed(path, "a vector \"x y z\"", parse_vec3)
+    }
+
+    /// Gets a vector written as "[x y z]"
+    pub fn get_bracketed_vec3(&self, path: &str) -> Result<glm::Vec3, QueryError> {
+        self.get_parsed(path, "a vector \"[x y z]\"", parse_bracketed_vec3)
+    }
+
+    /// Gets a color written as "r g b" or "r g b a", alpha is 255 if it is missing
+    pub fn get_color(&self, path: &str) -> Result<[u8; 4], QueryError> {
+        self.get_parsed(path, "a color \"r g b\"", parse_color)
+    }
+
+    /// Gets the three points of a plane written as "(x y z) (x y z) (x y z)"
+    pub fn get_plane(&self, path: &str) -> Result<[glm::Vec3; 3], QueryError> {
+        self.get_parsed(
+            path,
+            "a plane \"(x y z) (x y z) (x y z)\"",
+            parse_plane_points,
+        )
+    }
+
+    fn get_parsed<T>(
+        &self,
+        path: &str,
+        expected: &'static str,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Result<T, QueryError> {
+        match self.try_query(path)? {
+            KeyValues::Value { value, span, .. } => {
+                parse(value).ok_or_else(|| QueryError::InvalidValue {
+                    path: path.to_string(),
+                    value: value.to_string(),
+                    expected,
+                    span: *span,
+                })
+            }
+            KeyValues::List { .. } => Err(QueryError::NotAValue(path.to_string())),
+        }
+    }
+
+    /// Gets the binary type of a value
+    /// Only works on KeyValues::Value
+    pub fn get_value_type(&self) -> Option<ValueType> {
+        match self {
+            KeyValues::Value { value_type, .. } => Some(*value_type),
+            KeyValues::List { .. } => {
+                eprintln!("Tried to get value type from KeyValues::List");
+                None
             }
         }
     }
 
     /// Gets the actual value with the specified name
     /// Only works on KeyValues::Value
-    pub fn get_value(&self) -> Option<&String> {
+    pub fn get_value(&self) -> Option<&str> {
         match self {
-            KeyValues::Value { value } => Some(value),
+            KeyValues::Value { value, .. } => Some(value),
             KeyValues::List { .. } => {
                 eprintln!("Tried to get value from KeyValues::List");
                 None
@@ -140,7 +646,7 @@ impl KeyValues {
                 eprintln!("Tried to write KeyValues::Value. Should only be KeyValues::List. Something went wrong!");
                 return Err(io::ErrorKind::InvalidData.into());
             }
-            KeyValues::List { subkeys } => subkeys
+            KeyValues::List { subkeys, .. } => subkeys
                 .iter()
                 .map(|(name, value)| value.get_string(name, 0))
                 .collect::<Vec<String>>()
@@ -150,97 +656,271 @@ impl KeyValues {
         fs::write(file, content)
     }
 
+    /// Parses the content of a file into KeyValues
+    /// file is only used for error messages
     pub fn parse_internal(
-        content: &mut Peekable<Chars>,
-        file_open_handler: &dyn Fn(String) -> Result<Self, KeyValuesError>,
+        content: &'a str,
+        file: &str,
+        conditions: &Conditions,
+        file_open_handler: &dyn Fn(String) -> Result<KeyValues<'static>, KeyValuesError>,
     ) -> Result<Self, KeyValuesError> {
-        let mut current = vec![];
-        let mut stack: Vec<(String, Vec<(String, KeyValues)>)> = vec![];
+        Self::parse_reader(
+            &mut Reader::new(content),
+            file,
+            conditions,
+            file_open_handler,
+        )
+        .map_err(|mut e| {
+            if let Some(location) = e.location_mut() {
+                if location.file.is_empty() {
+                    location.file = file.to_string();
+                }
+            }
+            e
+        })
+    }
+
+    fn parse_reader(
+        content: &mut Reader<'a>,
+        file: &str,
+        conditions: &Conditions,
+        file_open_handler: &dyn Fn(String) -> Result<KeyValues<'static>, KeyValuesError>,
+    ) -> Result<Self, KeyValuesError> {
+        let mut current: Vec<(Cow<str>, KeyValues)> = vec![];
+        // #base files of the current block, they are merged when the block ends
+        let mut bases = vec![];
+        let mut stack = vec![];
         'parse: loop {
-            skip_whitespace(content);
+            skip_trivia(content);
             match content.peek() {
                 None => {
                     break 'parse;
                 }
-                Some('}') => {
-                    let (name, new_current) = stack.pop().ok_or(KeyValuesError::UnexpectedEnd)?;
-                    let part = Self::List { subkeys: current };
+                Some(b'}') => {
+                    let Some((name, span, active, new_current, new_bases)) = stack.pop() else {
+                        return Err(KeyValuesError::UnexpectedClosingBrace(
+                            current.last().map_or(String::new(), |(x, _)| x.to_string()),
+                            content.location(),
+                        ));
+                    };
+                    merge_bases(&mut current, bases);
+                    let part = Self::List {
+                        subkeys: current,
+                        span,
+                    };
                     current = new_current;
-                    current.push((name.to_lowercase(), part));
+                    bases = new_bases;
+                    if active {
+                        current.push((name, part));
+                    }
                     content.next();
                     continue 'parse;
                 }
-                Some('/') => {
-                    content.next();
-                    match content.peek() {
-                        Some('*') => {
-                            let mut last = content.next();
-                            loop {
-                                match (last, content.next()) {
-                                    (Some('*'), Some('/')) => {
-                                        continue 'parse;
-                                    }
-                                    (_, None) => {
-                                        break 'parse;
-                                    }
-                                    (_, x) => {
-                                        last = x;
-                                    }
-                                }
-                            }
-                        }
-                        _ => {
-                            loop {
-                                // This should only trigger for 2 slashes, but there is a bug in the original parser which only looks for one
-                                match content.next() {
-                                    None => {
-                                        break 'parse;
-                                    }
-                                    Some('\n') => {
-                                        continue 'parse;
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        }
-                    }
-                }
                 _ => {}
             }
+            let span = content.span;
             let name = read_text(content)?;
             skip_whitespace(content);
-            match content.peek().ok_or(KeyValuesError::UnexpectedEnd)? {
-                '{' => {
+            // Source allows a conditional between the key and its value or block
+            let mut active = read_conditional(content, conditions)?;
+            let location = content.location();
+            match content
+                .peek()
+                .ok_or(KeyValuesError::UnexpectedEnd(location))?
+            {
+                b'{' => {
                     content.next();
-                    stack.push((name.to_lowercase(), current));
+                    stack.push((name, span, active, current, bases));
                     current = vec![];
+                    bases = vec![];
                 }
-                '}' => {
-                    return Err(KeyValuesError::UnexpectedClosingBrace(name));
+                b'}' => {
+                    return Err(KeyValuesError::UnexpectedClosingBrace(
+                        name.into_owned(),
+                        content.location(),
+                    ));
                 }
                 _ => {
                     let value = read_text(content)?;
+                    skip_whitespace(content);
+                    active &= read_conditional(content, conditions)?;
+                    if !active {
+                        continue 'parse;
+                    }
                     // Handle macros
                     if name.starts_with('#') {
-                        match name.as_str() {
+                        match name.as_ref() {
                             "#include" | "#base" => {
-                                let include_data = file_open_handler(value)?;
-                                if let Self::List { mut subkeys } = include_data {
-                                    current.append(&mut subkeys);
+                                let include_data =
+                                    file_open_handler(value.into_owned()).map_err(|mut e| {
+                                        if let Some(location) = e.location_mut() {
+                                            if location.file.is_empty() {
+                                                // The file itself couldn't be opened
+                                                location.span = span;
+                                            } else {
+                                                location
+                                                    .include_chain
+                                                    .insert(0, (file.to_string(), span));
+                                            }
+                                        }
+                                        e
+                                    })?;
+                                if name == "#base" {
+                                    bases.push(include_data);
+                                } else if let KeyValues::List { subkeys, .. } = include_data {
+                                    current.extend(subkeys);
                                 }
                             }
-                            _ => return Err(KeyValuesError::UnknownMacro(name)),
+                            _ => {
+                                return Err(KeyValuesError::UnknownMacro(
+                                    name.into_owned(),
+                                    Location {
+                                        span,
+                                        ..Default::default()
+                                    },
+                                ))
+                            }
                         }
                     } else {
-                        current.push((name.to_lowercase(), Self::Value { value }))
+                        current.push((
+                            name,
+                            Self::Value {
+                                value,
+                                value_type: ValueType::String,
+                                span,
+                            },
+                        ))
                     }
                 }
             }
         }
         if !stack.is_empty() {
-            return Err(KeyValuesError::UnexpectedEnd);
+            return Err(KeyValuesError::UnexpectedEnd(content.location()));
         }
-        Ok(Self::List { subkeys: current })
+        merge_bases(&mut current, bases);
+        Ok(Self::List {
+            subkeys: current,
+            span: Span { line: 1, column: 1 },
+        })
+    }
+
+    /// Reads a file in the binary KeyValues format
+    pub fn parse_binary(file: &Path) -> Result<KeyValues<'static>, KeyValuesError> {
+        let content = fs::read(file).map_err(|_| {
+            KeyValuesError::InvalidFile(file.to_string_lossy().to_string(), Location::default())
+        })?;
+        KeyValues::parse_binary_internal(&content)
+    }
+
+    /// Parses binary KeyValues
+    /// The top level ends at the end of the data or at an end marker
+    pub fn parse_binary_internal(content: &[u8]) -> Result<KeyValues<'static>, KeyValuesError> {
+        let mut reader = BinaryReader { content, offset: 0 };
+        let subkeys = reader.read_subkeys(true)?;
+        Ok(KeyValues::new_list(subkeys))
+    }
+
+    /// Writes KeyValues into a file in the binary format
+    /// Errors if writing fails or a value doesn't match its type
+    pub fn write_binary(&self, file: &Path) -> Result<(), io::Error> {
+        fs::write(file, self.get_binary()?)
+    }
+
+    /// Gets the binary representation of a KeyValues::List
+    /// Errors if a value doesn't match its type
+    pub fn get_binary(&self) -> Result<Vec<u8>, io::Error> {
+        let mut content = vec![];
+        match self {
+            KeyValues::Value { .. } => {
+                eprintln!("Tried to write KeyValues::Value. Should only be KeyValues::List. Something went wrong!");
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+            KeyValues::List { subkeys, .. } => {
+                for (name, value) in subkeys {
+                    value.write_binary_node(name, &mut content)?;
+                }
+            }
+        }
+        content.push(ValueType::END);
+        Ok(content)
+    }
+
+    fn write_binary_node(&self, name: &str, content: &mut Vec<u8>) -> Result<(), io::Error> {
+        let invalid = |value: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid value \"{}\" for key \"{}\"", value, name),
+            )
+        };
+        match self {
+            KeyValues::Value {
+                value, value_type, ..
+            } => {
+                content.push(value_type.to_byte());
+                write_binary_string(name, content)?;
+                match value_type {
+                    ValueType::String => write_binary_string(value, content)?,
+                    ValueType::Int => content.extend(
+                        value
+                            .parse::<i32>()
+                            .map_err(|_| invalid(value))?
+                            .to_le_bytes(),
+                    ),
+                    ValueType::Float => content.extend(
+                        value
+                            .parse::<f32>()
+                            .map_err(|_| invalid(value))?
+                            .to_le_bytes(),
+                    ),
+                    ValueType::Ptr => content.extend(
+                        value
+                            .parse::<u32>()
+                            .map_err(|_| invalid(value))?
+                            .to_le_bytes(),
+                    ),
+                    ValueType::WString => {
+                        let units: Vec<u16> = value.encode_utf16().collect();
+                        let length = u16::try_from(units.len()).map_err(|_| invalid(value))?;
+                        content.extend(length.to_le_bytes());
+                        for unit in units {
+                            content.extend(unit.to_le_bytes());
+                        }
+                    }
+                    ValueType::Color => {
+                        let color = value
+                            .split_whitespace()
+                            .map(|x| x.parse::<u8>())
+                            .collect::<Result<Vec<u8>, _>>()
+                            .map_err(|_| invalid(value))?;
+                        if color.len() != 4 {
+                            return Err(invalid(value));
+                        }
+                        content.extend(color);
+                    }
+                    ValueType::UInt64 => content.extend(
+                        value
+                            .parse::<u64>()
+                            .map_err(|_| invalid(value))?
+                            .to_le_bytes(),
+                    ),
+                    ValueType::Int64 => content.extend(
+                        value
+                            .parse::<i64>()
+                            .map_err(|_| invalid(value))?
+                            .to_le_bytes(),
+                    ),
+                }
+            }
+            KeyValues::List { subkeys, .. } => {
+                content.push(ValueType::NONE);
+                write_binary_string(name, content)?;
+                for (name, value) in subkeys {
+                    value.write_binary_node(name, content)?;
+                }
+                content.push(ValueType::END);
+            }
+        }
+        Ok(())
     }
 
     fn get_string(&self, name: &str, indent: u16) -> String {
@@ -248,10 +928,10 @@ impl KeyValues {
         let indent_str = String::from(" ").repeat((indent * 4) as usize);
         let name = escape_token(name);
         match self {
-            KeyValues::Value { value } => {
+            KeyValues::Value { value, .. } => {
                 format!("{indent_str}\"{name}\" \"{}\"", escape_token(value))
             }
-            KeyValues::List { subkeys } => {
+            KeyValues::List { subkeys, .. } => {
                 format!(
                     "{indent_str}\"{name}\"\n{indent_str}{{\n{}\n{indent_str}}}",
                     subkeys
@@ -265,69 +945,381 @@ impl KeyValues {
     }
 }
 
-fn skip_whitespace(content: &mut Peekable<Chars>) {
+/// Adds the keys of #base files like Source does
+/// Keys that already exist win, even if they come after the #base
+/// Blocks that exist in both get merged the same way
+fn merge_bases<'a>(subkeys: &mut Vec<(Cow<'a, str>, KeyValues<'a>)>, bases: Vec<KeyValues<'a>>) {
+    for base in bases {
+        let KeyValues::List {
+            subkeys: base_subkeys,
+            ..
+        } = base
+        else {
+            continue;
+        };
+        for (key, value) in base_subkeys {
+            match subkeys
+                .iter_mut()
+                .find(|(name, _)| name.eq_ignore_ascii_case(&key))
+            {
+                Some((_, KeyValues::List { subkeys, .. })) => merge_bases(subkeys, vec![value]),
+                Some(_) => {}
+                None => subkeys.push((key, value)),
+            }
+        }
+    }
+}
+
+/// Strings in binary KeyValues are null terminated, so they can't contain null
+fn write_binary_string(text: &str, content: &mut Vec<u8>) -> Result<(), io::Error> {
+    if text.contains('\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("\"{}\" contains a null character", escape_token(text)),
+        ));
+    }
+    content.extend(text.as_bytes());
+    content.push(0);
+    Ok(())
+}
+
+/// Reads binary KeyValues while keeping track of the offset for errors
+struct BinaryReader<'a> {
+    content: &'a [u8],
+    offset: usize,
+}
+
+impl BinaryReader<'_> {
+    fn error(&self, message: &str) -> KeyValuesError {
+        KeyValuesError::InvalidBinary(message.to_string(), self.offset)
+    }
+
+    fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N], KeyValuesError> {
+        let bytes = self
+            .content
+            .get(self.offset..self.offset + N)
+            .ok_or(self.error("unexpected end"))?;
+        self.offset += N;
+        Ok(bytes.try_into().expect("slice has the right length"))
+    }
+
+    fn read_string(&mut self) -> Result<String, KeyValuesError> {
+        let length = self.content[self.offset.min(self.content.len())..]
+            .iter()
+            .position(|x| *x == 0)
+            .ok_or(self.error("unterminated string"))?;
+        let text = String::from_utf8(self.content[self.offset..self.offset + length].to_vec())
+            .map_err(|_| self.error("string is not valid UTF-8"))?;
+        self.offset += length + 1;
+        Ok(text)
+    }
+
+    fn read_wstring(&mut self) -> Result<String, KeyValuesError> {
+        let length = u16::from_le_bytes(self.read_bytes()?);
+        let mut units = vec![];
+        for _ in 0..length {
+            units.push(u16::from_le_bytes(self.read_bytes()?));
+        }
+        String::from_utf16(&units).map_err(|_| self.error("wide string is not valid UTF-16"))
+    }
+
+    fn read_subkeys(
+        &mut self,
+        top_level: bool,
+    ) -> Result<Vec<(String, KeyValues<'static>)>, KeyValuesError> {
+        let mut subkeys = vec![];
+        loop {
+            let type_byte = match self.content.get(self.offset) {
+                None if top_level => return Ok(subkeys),
+                None => return Err(self.error("unexpected end")),
+                Some(byte) => *byte,
+            };
+            if type_byte == ValueType::END || type_byte == ValueType::ALTERNATE_END {
+                self.offset += 1;
+                return Ok(subkeys);
+            }
+            let type_offset = self.offset;
+            self.offset += 1;
+            let name = self.read_string()?;
+            if type_byte == ValueType::NONE {
+                let list = KeyValues::new_list(self.read_subkeys(false)?);
+                subkeys.push((name, list));
+                continue;
+            }
+            let value_type = ValueType::from_byte(type_byte).ok_or(
+                KeyValuesError::InvalidBinary(format!("unknown type {}", type_byte), type_offset),
+            )?;
+            let value = match value_type {
+                ValueType::String => self.read_string()?,
+                ValueType::Int => i32::from_le_bytes(self.read_bytes()?).to_string(),
+                ValueType::Float => f32::from_le_bytes(self.read_bytes()?).to_string(),
+                ValueType::Ptr => u32::from_le_bytes(self.read_bytes()?).to_string(),
+                ValueType::WString => self.read_wstring()?,
+                ValueType::Color => {
+                    let [r, g, b, a] = self.read_bytes()?;
+                    format!("{} {} {} {}", r, g, b, a)
+                }
+                ValueType::UInt64 => u64::from_le_bytes(self.read_bytes()?).to_string(),
+                ValueType::Int64 => i64::from_le_bytes(self.read_bytes()?).to_string(),
+            };
+            subkeys.push((name, KeyValues::new_typed_value(value, value_type)));
+        }
+    }
+}
+
+/// Walks over the bytes of a file while keeping track of the position
+/// Everything the syntax cares about is ASCII, so tokens can be sliced out of the content without copying
+#[derive(Clone)]
+struct Reader<'a> {
+    content: &'a str,
+    /// Byte offset of the next character
+    offset: usize,
+    /// Position of the next character
+    span: Span,
+}
+
+impl<'a> Reader<'a> {
+    fn new(content: &'a str) -> Self {
+        Self {
+            content,
+            offset: 0,
+            span: Span { line: 1, column: 1 },
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.content.as_bytes().get(self.offset).copied()
+    }
+
+    /// The location of the next character, without a file
+    fn location(&self) -> Location {
+        Location {
+            span: self.span,
+            ..Default::default()
+        }
+    }
+
+    /// Skips a whole character, which might be more than one byte
+    fn next_char(&mut self) {
+        self.next();
+        while self.peek().is_some_and(is_continuation_byte) {
+            self.next();
+        }
+    }
+}
+
+impl Iterator for Reader<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.offset += 1;
+        if byte == b'\n' {
+            self.span.line += 1;
+            self.span.column = 1;
+        } else if !is_continuation_byte(byte) {
+            // Columns count characters, not bytes
+            self.span.column += 1;
+        }
+        Some(byte)
+    }
+}
+
+fn is_continuation_byte(byte: u8) -> bool {
+    byte & 0b1100_0000 == 0b1000_0000
+}
+
+fn skip_whitespace(content: &mut Reader) {
+    while let Some(b' ' | b'\r' | b'\n' | b'\t') = content.peek() {
+        content.next();
+    }
+}
+
+/// Skips whitespace and comments
+fn skip_trivia(content: &mut Reader) {
+    loop {
+        skip_whitespace(content);
+        if content.peek() != Some(b'/') {
+            return;
+        }
+        content.next();
+        if content.peek() == Some(b'*') {
+            let mut last = content.next();
+            loop {
+                match (last, content.next()) {
+                    (Some(b'*'), Some(b'/')) | (_, None) => break,
+                    (_, x) => last = x,
+                }
+            }
+        } else {
+            // This should only trigger for 2 slashes, but there is a bug in the original parser which only looks for one
+            for c in content.by_ref() {
+                if c == b'\n' {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Reads a conditional like [$WIN32] if there is one and evaluates it
+/// Returns true if there is no conditional
+fn read_conditional(content: &mut Reader, conditions: &Conditions) -> Result<bool, KeyValuesError> {
+    let location = content.location();
+    let Some(condition) = read_condition_text(content)? else {
+        return Ok(true);
+    };
+    skip_whitespace(content);
+    conditions
+        .evaluate(condition)
+        .ok_or_else(|| KeyValuesError::InvalidConditional(condition.to_string(), location))
+}
+
+/// Reads the inside of a conditional without evaluating it
+fn read_condition_text<'a>(content: &mut Reader<'a>) -> Result<Option<&'a str>, KeyValuesError> {
+    if content.peek() != Some(b'[') {
+        return Ok(None);
+    }
+    content.next();
+    let start = content.offset;
     loop {
         match content.peek() {
-            Some(' ' | '\r' | '\n' | '\t') => {
+            None => return Err(KeyValuesError::UnexpectedEnd(content.location())),
+            Some(b']') => {
+                let condition = &content.content[start..content.offset];
+                content.next();
+                return Ok(Some(condition));
+            }
+            Some(_) => {
                 content.next();
             }
-            _ => return,
         }
     }
-    // the above code does the same as this, just actually changing the iter
-    // code.skip_while(|c| *c == ' ' || *c == '\r' || *c == '\n' || *c == '\t');
 }
 
-fn read_text(content: &mut Peekable<Chars>) -> Result<String, KeyValuesError> {
-    let mut text = String::new();
-    let mut require_quote = false;
-    match content.next().ok_or(KeyValuesError::UnexpectedEnd)? {
-        '"' => {
-            require_quote = true;
+/// Reads a quoted or unquoted token
+/// Borrows the text from the content, unless it has escapes that have to be replaced
+fn read_text<'a>(content: &mut Reader<'a>) -> Result<Cow<'a, str>, KeyValuesError> {
+    let quoted = match content.peek() {
+        None => return Err(KeyValuesError::UnexpectedEnd(content.location())),
+        Some(b'"') => {
+            content.next();
+            true
         }
-        c => text.push(c),
-    }
-    loop {
-        // Ugly code
+        Some(_) => {
+            // The first character is always part of an unquoted token, even if it would end it
+            content.next_char();
+            false
+        }
+    };
+    let start = if quoted {
+        content.offset
+    } else {
+        content.offset
+            - content.content[..content.offset]
+                .chars()
+                .next_back()
+                .map_or(0, char::len_utf8)
+    };
+    // Only allocated once there is an escape
+    let mut escaped: Option<String> = None;
+    let mut run_start = start;
+    let end = loop {
         match content.peek() {
             None => {
-                if require_quote {
-                    return Err(KeyValuesError::UnexpectedEnd);
+                if quoted {
+                    return Err(KeyValuesError::UnexpectedEnd(content.location()));
                 }
-                break;
-            }
-            Some('\\') => {
-                content.next(); // To acctually advance the peek
-                match content.next().ok_or(KeyValuesError::UnexpectedEnd)? {
-                    'n' => text.push('\n'),
-                    't' => text.push('\t'),
-                    '\\' => text.push('\\'),
-                    '"' => text.push('"'),
-                    c => return Err(KeyValuesError::InvalidEscape(c)),
+                break content.offset;
+            }
+            Some(b'\\') => {
+                let location = content.location();
+                let text = escaped.get_or_insert_with(String::new);
+                text.push_str(&content.content[run_start..content.offset]);
+                content.next();
+                let c = match content.peek() {
+                    None => return Err(KeyValuesError::UnexpectedEnd(content.location())),
+                    Some(b'n') => '\n',
+                    Some(b't') => '\t',
+                    Some(b'\\') => '\\',
+                    Some(b'"') => '"',
+                    Some(_) => {
+                        let c = content.content[content.offset..]
+                            .chars()
+                            .next()
+                            .expect("has been checked by peek before");
+                        return Err(KeyValuesError::InvalidEscape(c, location));
+                    }
                 };
+                text.push(c);
+                content.next();
+                run_start = content.offset;
             }
-            Some('"') => {
-                if require_quote {
+            Some(b'"') => {
+                let end = content.offset;
+                if quoted {
                     content.next();
                 }
-                break;
+                break end;
             }
-            Some(' ' | '\r' | '\n' | '\t' | '{' | '}') => {
-                if !require_quote {
-                    break;
-                }
-                text.push(content.next().expect("has been checked by peek before"));
-            }
-            Some(c) => {
-                text.push(*c);
+            Some(b' ' | b'\r' | b'\n' | b'\t' | b'{' | b'}') if !quoted => break content.offset,
+            Some(_) => {
                 content.next();
             }
         }
+    };
+    let rest = &content.content[run_start..end];
+    Ok(match escaped {
+        Some(mut text) => {
+            text.push_str(rest);
+            Cow::Owned(text)
+        }
+        None => Cow::Borrowed(rest),
+    })
+}
+
+/// Parses a fixed number of numbers separated by whitespace
+fn parse_numbers<T: std::str::FromStr + Default + Copy, const N: usize>(
+    text: &str,
+) -> Option<[T; N]> {
+    let mut numbers = [T::default(); N];
+    let mut parts = text.split_whitespace();
+    for number in numbers.iter_mut() {
+        *number = parts.next()?.parse().ok()?;
     }
-    Ok(text)
+    parts.next().is_none().then_some(numbers)
 }
 
-fn escape_token(token: &str) -> String {
+/// Parses "x y z"
+pub fn parse_vec3(text: &str) -> Option<glm::Vec3> {
+    let [x, y, z] = parse_numbers(text)?;
+    Some(glm::vec3(x, y, z))
+}
+
+/// Parses "[x y z]"
+pub fn parse_bracketed_vec3(text: &str) -> Option<glm::Vec3> {
+    parse_vec3(text.trim().strip_prefix('[')?.strip_suffix(']')?)
+}
+
+/// Parses "r g b" or "r g b a"
+pub fn parse_color(text: &str) -> Option<[u8; 4]> {
+    if let Some([r, g, b]) = parse_numbers(text) {
+        return Some([r, g, b, 255]);
+    }
+    parse_numbers(text)
+}
+
+/// Parses "(x y z) (x y z) (x y z)"
+pub fn parse_plane_points(text: &str) -> Option<[glm::Vec3; 3]> {
+    let mut parts = text.trim().split(')');
+    let mut points = [glm::Vec3::zeros(); 3];
+    for point in points.iter_mut() {
+        *point = parse_vec3(parts.next()?.trim_start().strip_prefix('(')?)?;
+    }
+    (parts.next() == Some("") && parts.next().is_none()).then_some(points)
+}
+
+pub(crate) fn escape_token(token: &str) -> String {
     token
         .replace('\\', "\\\\")
         .replace('\n', "\\n")
@@ -339,80 +1331,98 @@ fn escape_token(token: &str) -> String {
 mod tests {
     use super::*;
 
+    fn at(line: usize, column: usize) -> Location {
+        Location {
+            span: Span { line, column },
+            ..Default::default()
+        }
+    }
+
+    /// What the reader hasn't read yet
+    fn rest<'a>(reader: &Reader<'a>) -> &'a str {
+        &reader.content[reader.offset..]
+    }
+
     #[test]
     fn test_read_text_unquoted() {
         {
-            let mut iter = "Hello World".chars().peekable();
-            assert_eq!(read_text(&mut iter), Ok(String::from("Hello")));
-            assert_eq!(iter.collect::<String>(), String::from(" World"));
+            let mut iter = Reader::new("Hello World");
+            assert_eq!(read_text(&mut iter), Ok(Cow::from("Hello")));
+            assert_eq!(rest(&iter), String::from(" World"));
         }
         {
-            let mut iter = "Hello{World".chars().peekable();
-            assert_eq!(read_text(&mut iter), Ok(String::from("Hello")));
-            assert_eq!(iter.collect::<String>(), String::from("{World"));
+            let mut iter = Reader::new("Hello{World");
+            assert_eq!(read_text(&mut iter), Ok(Cow::from("Hello")));
+            assert_eq!(rest(&iter), String::from("{World"));
         }
         {
-            let mut iter = "Hello\"World".chars().peekable();
-            assert_eq!(read_text(&mut iter), Ok(String::from("Hello")));
-            assert_eq!(iter.collect::<String>(), String::from("\"World"));
+            let mut iter = Reader::new("Hello\"World");
+            assert_eq!(read_text(&mut iter), Ok(Cow::from("Hello")));
+            assert_eq!(rest(&iter), String::from("\"World"));
         }
     }
     #[test]
     fn test_read_text_quoted() {
         {
-            let mut iter = "\"Hello World\"".chars().peekable();
-            assert_eq!(read_text(&mut iter), Ok(String::from("Hello World")));
-            assert_eq!(iter.collect::<String>(), String::from(""));
+            let mut iter = Reader::new("\"Hello World\"");
+            assert_eq!(read_text(&mut iter), Ok(Cow::from("Hello World")));
+            assert_eq!(rest(&iter), String::from(""));
         }
         {
-            let mut iter = "\"Hello{World\"".chars().peekable();
-            assert_eq!(read_text(&mut iter), Ok(String::from("Hello{World")));
-            assert_eq!(iter.collect::<String>(), String::from(""));
+            let mut iter = Reader::new("\"Hello{World\"");
+            assert_eq!(read_text(&mut iter), Ok(Cow::from("Hello{World")));
+            assert_eq!(rest(&iter), String::from(""));
         }
     }
     #[test]
     fn test_read_text_escape() {
         {
-            let mut iter = "Hello\\nbeatiful World".chars().peekable();
-            assert_eq!(read_text(&mut iter), Ok(String::from("Hello\nbeatiful")));
-            assert_eq!(iter.collect::<String>(), String::from(" World"));
+            let mut iter = Reader::new("Hello\\nbeatiful World");
+            assert_eq!(read_text(&mut iter), Ok(Cow::from("Hello\nbeatiful")));
+            assert_eq!(rest(&iter), String::from(" World"));
         }
         {
-            let mut iter = "Hello\\xbeatiful World".chars().peekable();
+            let mut iter = Reader::new("Hello\\xbeatiful World");
             assert_eq!(
                 read_text(&mut iter),
-                Err(KeyValuesError::InvalidEscape('x'))
+                Err(KeyValuesError::InvalidEscape('x', at(1, 6)))
             );
         }
         {
-            let mut iter = "\"Hello\\nbeatiful World\"".chars().peekable();
-            assert_eq!(
-                read_text(&mut iter),
-                Ok(String::from("Hello\nbeatiful World"))
-            );
-            assert_eq!(iter.collect::<String>(), String::from(""));
+            let mut iter = Reader::new("\"Hello\\nbeatiful World\"");
+            assert_eq!(read_text(&mut iter), Ok(Cow::from("Hello\nbeatiful World")));
+            assert_eq!(rest(&iter), String::from(""));
         }
         {
-            let mut iter = "\"Hello\\xbeatiful World\"".chars().peekable();
+            let mut iter = Reader::new("\"Hello\\xbeatiful World\"");
             assert_eq!(
                 read_text(&mut iter),
-                Err(KeyValuesError::InvalidEscape('x'))
+                Err(KeyValuesError::InvalidEscape('x', at(1, 7)))
             );
         }
     }
     #[test]
     fn test_read_text_unexpected_end() {
         {
-            let mut iter = "".chars().peekable();
-            assert_eq!(read_text(&mut iter), Err(KeyValuesError::UnexpectedEnd));
+            let mut iter = Reader::new("");
+            assert_eq!(
+                read_text(&mut iter),
+                Err(KeyValuesError::UnexpectedEnd(at(1, 1)))
+            );
         }
         {
-            let mut iter = "Hello\\".chars().peekable();
-            assert_eq!(read_text(&mut iter), Err(KeyValuesError::UnexpectedEnd));
+            let mut iter = Reader::new("Hello\\");
+            assert_eq!(
+                read_text(&mut iter),
+                Err(KeyValuesError::UnexpectedEnd(at(1, 7)))
+            );
         }
         {
-            let mut iter = "\"Hello World".chars().peekable();
-            assert_eq!(read_text(&mut iter), Err(KeyValuesError::UnexpectedEnd));
+            let mut iter = Reader::new("\"Hello World");
+            assert_eq!(
+                read_text(&mut iter),
+                Err(KeyValuesError::UnexpectedEnd(at(1, 13)))
+            );
         }
     }
     #[test]
@@ -422,4 +1432,408 @@ mod tests {
             "Hello\\nWorld\\tHello\\\\World\\\"Hello"
         )
     }
+
+    #[test]
+    fn test_parse_borrows() {
+        let content = "\"näme\" \"välue\"\nescaped \"a\\nb\"\n";
+        let kv = KeyValues::parse_internal(content, "test.txt", &Conditions::default(), &|x| {
+            Err(KeyValuesError::InvalidFile(x, Location::default()))
+        })
+        .unwrap();
+        let KeyValues::List { subkeys, .. } = &kv else {
+            panic!("the top level is always a list");
+        };
+        assert!(matches!(subkeys[0].0, Cow::Borrowed("näme")));
+        assert!(matches!(
+            subkeys[0].1,
+            KeyValues::Value {
+                value: Cow::Borrowed("välue"),
+                ..
+            }
+        ));
+        assert!(matches!(
+            &subkeys[1].1,
+            KeyValues::Value { value: Cow::Owned(value), .. } if value == "a\nb"
+        ));
+        assert_eq!(
+            kv.into_owned().get("escaped").unwrap().get_value(),
+            Some("a\nb")
+        );
+
+        // Columns count characters, not bytes
+        assert_eq!(
+            read_text(&mut Reader::new("\"ä\\x\"")),
+            Err(KeyValuesError::InvalidEscape('x', at(1, 3)))
+        );
+        let mut reader = Reader::new("äb c");
+        assert_eq!(read_text(&mut reader), Ok(Cow::from("äb")));
+        assert_eq!(reader.span, Span { line: 1, column: 3 });
+    }
+
+    #[test]
+    fn test_parse_spans() {
+        let kv = KeyValues::parse_internal(
+            "\"a\" \"1\"\n\tb\n\t{\n\t\tc 2\n\t}\n",
+            "test.txt",
+            &Conditions::default(),
+            &|x| Err(KeyValuesError::InvalidFile(x, Location::default())),
+        )
+        .unwrap();
+        assert_eq!(kv.get("a").unwrap().span(), Span { line: 1, column: 1 });
+        assert_eq!(kv.get("b").unwrap().span(), Span { line: 2, column: 2 });
+        assert_eq!(
+            kv.get("b").unwrap().get("c").unwrap().span(),
+            Span { line: 4, column: 3 }
+        );
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let handler = |x: String| {
+            KeyValues::parse_internal("x\n{\n\ty \"\\q\"\n}", &x, &Conditions::default(), &|x| {
+                Err(KeyValuesError::InvalidFile(x, Location::default()))
+            })
+        };
+        let error = KeyValues::parse_internal(
+            "a 1\n#include \"inner.txt\"\n",
+            "outer.txt",
+            &Conditions::default(),
+            &handler,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            KeyValuesError::InvalidEscape(
+                'q',
+                Location {
+                    file: String::from("inner.txt"),
+                    span: Span { line: 3, column: 5 },
+                    include_chain: vec![(String::from("outer.txt"), Span { line: 2, column: 1 })],
+                }
+            )
+        );
+        assert_eq!(
+            error.to_string(),
+            "invalid escape sequence \\q at inner.txt:3:5 (included from outer.txt:2:1)"
+        );
+
+        let error =
+            KeyValues::parse_internal("a 1\nb {\n", "test.txt", &Conditions::default(), &handler)
+                .unwrap_err();
+        assert_eq!(error.to_string(), "unexpected end at test.txt:3:1");
+
+        let error =
+            KeyValues::parse_internal("a 1\n}\n", "test.txt", &Conditions::default(), &handler)
+                .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unexpected closing brace after a at test.txt:2:1"
+        );
+
+        let error = KeyValues::parse_internal(
+            "\n#base missing.txt",
+            "test.txt",
+            &Conditions::default(),
+            &|x| Err(KeyValuesError::InvalidFile(x, Location::default())),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid file \"missing.txt\" at test.txt:2:1"
+        );
+    }
+
+    #[test]
+    fn test_conditions_evaluate() {
+        let conditions = Conditions::windows();
+        assert_eq!(conditions.evaluate("$WIN32"), Some(true));
+        assert_eq!(conditions.evaluate("$win32"), Some(true));
+        assert_eq!(conditions.evaluate("!$WINDOWS"), Some(false));
+        assert_eq!(conditions.evaluate("$X360||$PS3"), Some(false));
+        assert_eq!(conditions.evaluate("!$X360 && !$PS3"), Some(true));
+        assert_eq!(conditions.evaluate("$OSX||$WIN32&&!$X360"), Some(true));
+        assert_eq!(Conditions::linux().evaluate("$POSIX"), Some(true));
+        assert_eq!(conditions.evaluate("WIN32"), None);
+        assert_eq!(conditions.evaluate("$"), None);
+        assert_eq!(conditions.evaluate(""), None);
+    }
+
+    #[test]
+    fn test_include_and_base() {
+        fn parse(content: &'static str) -> Result<KeyValues<'static>, KeyValuesError> {
+            KeyValues::parse_internal(content, "test.txt", &Conditions::default(), &|x| {
+                match x.as_str() {
+                    "base.txt" => parse(
+                        "material { $basetexture base $surfaceprop metal $nested { a 2 b 3 } }\nextra 1",
+                    ),
+                    "include.txt" => parse("material { $basetexture included }"),
+                    _ => Err(KeyValuesError::InvalidFile(x, Location::default())),
+                }
+            })
+        }
+
+        let kv = parse(
+            "#base base.txt\nmaterial { $basetexture own $nested { a 1 } }\n#include include.txt",
+        )
+        .unwrap();
+        let materials = kv.get_all("material");
+        assert_eq!(materials.len(), 2);
+        assert_eq!(materials[0].get_str("$basetexture"), Ok("own"));
+        assert_eq!(materials[0].get_str("$surfaceprop"), Ok("metal"));
+        assert_eq!(materials[0].get_str("$nested/a"), Ok("1"));
+        assert_eq!(materials[0].get_str("$nested/b"), Ok("3"));
+        assert_eq!(materials[1].get_str("$basetexture"), Ok("included"));
+        assert_eq!(kv.get_str("extra"), Ok("1"));
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let file = Path::new("tests/data/include/cycle_a.txt");
+        let error = KeyValues::parse(file).unwrap_err();
+        assert_eq!(
+            error,
+            KeyValuesError::IncludeCycle(
+                String::from("cycle_a.txt"),
+                Location {
+                    file: String::from("tests/data/include/cycle_b.txt"),
+                    span: Span { line: 2, column: 1 },
+                    include_chain: vec![(
+                        file.to_string_lossy().to_string(),
+                        Span { line: 1, column: 1 }
+                    )],
+                }
+            )
+        );
+        assert_eq!(
+            error.to_string(),
+            "include cycle, \"cycle_a.txt\" is already being parsed at tests/data/include/cycle_b.txt:2:1 (included from tests/data/include/cycle_a.txt:1:1)"
+        );
+    }
+
+    #[test]
+    fn test_include_without_parent() {
+        let error = KeyValues::parse_str(
+            "#include \"missing.txt\"",
+            Path::new(""),
+            &Conditions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(error, KeyValuesError::InvalidFile(x, _) if x == "missing.txt"));
+    }
+
+    #[test]
+    fn test_parse_conditionals() {
+        let content = "a 1 [$WIN32]\na 2 [$OSX]\nb [!$WINDOWS]\n{\n\tc 3\n}\nb [$WINDOWS] { c 4 [$X360||$PS3] d 5 }\n";
+        let parse = |conditions: &Conditions| {
+            KeyValues::parse_internal(content, "test.txt", conditions, &|x| {
+                Err(KeyValuesError::InvalidFile(x, Location::default()))
+            })
+            .unwrap()
+        };
+
+        let kv = parse(&Conditions::windows());
+        assert_eq!(kv.get_all("a").len(), 1);
+        assert_eq!(kv.get("a").unwrap().get_value().unwrap(), "1");
+        assert_eq!(kv.get_all("b").len(), 1);
+        assert!(kv.get("b").unwrap().get("c").is_none());
+        assert_eq!(
+            kv.get("b").unwrap().get("d").unwrap().get_value().unwrap(),
+            "5"
+        );
+
+        let kv = parse(&Conditions::osx());
+        assert_eq!(kv.get("a").unwrap().get_value().unwrap(), "2");
+        assert_eq!(
+            kv.get("b").unwrap().get("c").unwrap().get_value().unwrap(),
+            "3"
+        );
+
+        let mut conditions = Conditions::x360();
+        conditions.define("WINDOWS");
+        let kv = parse(&conditions);
+        assert!(kv.get("a").is_none());
+        assert_eq!(
+            kv.get("b").unwrap().get("c").unwrap().get_value().unwrap(),
+            "4"
+        );
+
+        let error = KeyValues::parse_internal("a 1 [WIN32]", "test.txt", &conditions, &|x| {
+            Err(KeyValuesError::InvalidFile(x, Location::default()))
+        })
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid conditional \"WIN32\" at test.txt:1:5"
+        );
+    }
+
+    #[test]
+    fn test_parse_binary() {
+        let mut content = vec![0];
+        content.extend(b"root\0");
+        content.extend(b"\x01name\0value\0");
+        content.extend(b"\x02count\0");
+        content.extend((-5i32).to_le_bytes());
+        content.extend(b"\x03scale\0");
+        content.extend(0.1f32.to_le_bytes());
+        content.extend(b"\x05wide\0\x02\0h\0i\0");
+        content.extend(b"\x06color\0\xff\x80\0\x40");
+        content.extend(b"\x07big\0");
+        content.extend(u64::MAX.to_le_bytes());
+        content.extend(b"\x08\x08");
+        let kv = KeyValues::parse_binary_internal(&content).unwrap();
+        let root = kv.get("root").unwrap();
+        let value = |name: &str| root.get(name).unwrap().get_value().unwrap();
+        assert_eq!(value("name"), "value");
+        assert_eq!(value("count"), "-5");
+        assert_eq!(value("scale").parse::<f32>().unwrap(), 0.1);
+        assert_eq!(value("wide"), "hi");
+        assert_eq!(value("color"), "255 128 0 64");
+        assert_eq!(value("big"), "18446744073709551615");
+        assert_eq!(
+            root.get("count").unwrap().get_value_type(),
+            Some(ValueType::Int)
+        );
+        assert_eq!(kv.get_binary().unwrap(), content);
+
+        assert_eq!(
+            KeyValues::parse_binary_internal(&content[..27]).unwrap_err(),
+            KeyValuesError::InvalidBinary(String::from("unexpected end"), 25)
+        );
+        assert_eq!(
+            KeyValues::parse_binary_internal(b"\x09a\0").unwrap_err(),
+            KeyValuesError::InvalidBinary(String::from("unknown type 9"), 0)
+        );
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let content =
+            "a\n{\n    \"b\" \"1\"\n    \"c\"\n    {\n        \"g\" \"\"\n    }\n}\n\"d\" \"e f\"";
+        let kv = KeyValues::parse_internal(content, "test.txt", &Conditions::default(), &|x| {
+            Err(KeyValuesError::InvalidFile(x, Location::default()))
+        })
+        .unwrap();
+        let kv = KeyValues::parse_binary_internal(&kv.get_binary().unwrap()).unwrap();
+        let KeyValues::List { subkeys, .. } = kv else {
+            panic!("top level is always a list")
+        };
+        let text = subkeys
+            .iter()
+            .map(|(name, value)| value.get_string(name, 0))
+            .collect::<Vec<String>>()
+            .join("\n");
+        assert_eq!(text, content.replace("a\n", "\"a\"\n"));
+
+        let kv = KeyValues::new_list(vec![(
+            String::from("x"),
+            KeyValues::new_typed_value(String::from("1.5"), ValueType::Int),
+        )]);
+        assert_eq!(
+            kv.get_binary().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_key_case() {
+        let content = "GameInfo\n{\n\tFileSystem\n\t{\n\t\tSearchPaths\n\t\t{\n\t\t\tGame+Mod hl2\n\t\t\tgame ep2\n\t\t}\n\t}\n}\n";
+        let kv = KeyValues::parse_internal(content, "test.txt", &Conditions::default(), &|x| {
+            Err(KeyValuesError::InvalidFile(x, Location::default()))
+        })
+        .unwrap();
+        // Lookups ignore the case, like in Source
+        let gameinfo = kv.get("GAMEINFO").unwrap();
+        let search_paths = gameinfo.query("filesystem/searchpaths").unwrap();
+        assert_eq!(search_paths.get_all("GAME").len(), 1);
+        assert_eq!(
+            search_paths.get("game+mod").and_then(KeyValues::get_value),
+            Some("hl2")
+        );
+
+        // but the keys keep the case they had in the file
+        let keys: Vec<&str> = search_paths
+            .get_all_kv_pairs()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec!["Game+Mod", "game"]);
+        let text = kv.get_string("root", 0);
+        assert!(text.contains("\"GameInfo\""));
+        assert!(text.contains("\"FileSystem\""));
+        assert!(text.contains("\"Game+Mod\" \"hl2\""));
+    }
+
+    #[test]
+    fn test_query() {
+        let content =
+            "GameInfo\n{\n\tgame \"Half-Life 2\"\n\tFileSystem\n\t{\n\t\tSteamAppId 220\n\t}\n}\n";
+        let kv = KeyValues::parse_internal(content, "test.txt", &Conditions::default(), &|x| {
+            Err(KeyValuesError::InvalidFile(x, Location::default()))
+        })
+        .unwrap();
+        assert_eq!(kv.get_str("gameinfo/GAME"), Ok("Half-Life 2"));
+        assert_eq!(kv.get_int("gameinfo/filesystem/steamappid"), Ok(220));
+        assert!(kv.query("gameinfo/filesystem").is_some());
+        assert!(kv.query("gameinfo/filesystem/searchpaths").is_none());
+        assert_eq!(
+            kv.try_query("gameinfo/filesystem/searchpaths").unwrap_err(),
+            QueryError::Missing(String::from("gameinfo/filesystem/searchpaths"))
+        );
+        assert_eq!(
+            kv.try_query("gameinfo/game/x").unwrap_err(),
+            QueryError::NotABlock(String::from("gameinfo/game"))
+        );
+        assert_eq!(
+            kv.get_str("gameinfo/filesystem").unwrap_err(),
+            QueryError::NotAValue(String::from("gameinfo/filesystem"))
+        );
+        assert_eq!(
+            kv.get_int("gameinfo/game").unwrap_err().to_string(),
+            "expected an integer for \"gameinfo/game\", found \"Half-Life 2\" at 3:2"
+        );
+    }
+
+    #[test]
+    fn test_typed_getters() {
+        let kv = KeyValues::new_list(
+            [
+                ("float", "-0.25"),
+                ("true", "1"),
+                ("false", "FALSE"),
+                ("origin", "1 -2 3.5"),
+                ("position", "[0 0 -64]"),
+                ("color", "255 128 0"),
+                ("plane", "(0 0 0) (0 64 0) (64 0 0)"),
+                ("bad_plane", "(0 0 0) (0 64 0) (64 0 0) (1 1 1)"),
+            ]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), KeyValues::new_value(value.to_string())))
+            .collect(),
+        );
+        assert_eq!(kv.get_float("float"), Ok(-0.25));
+        assert_eq!(kv.get_bool("true"), Ok(true));
+        assert_eq!(kv.get_bool("false"), Ok(false));
+        assert_eq!(kv.get_vec3("origin"), Ok(glm::vec3(1.0, -2.0, 3.5)));
+        assert_eq!(
+            kv.get_bracketed_vec3("position"),
+            Ok(glm::vec3(0.0, 0.0, -64.0))
+        );
+        assert_eq!(kv.get_color("color"), Ok([255, 128, 0, 255]));
+        assert_eq!(
+            kv.get_plane("plane"),
+            Ok([
+                glm::vec3(0.0, 0.0, 0.0),
+                glm::vec3(0.0, 64.0, 0.0),
+                glm::vec3(64.0, 0.0, 0.0)
+            ])
+        );
+        assert!(kv.get_plane("bad_plane").is_err());
+        assert!(kv.get_vec3("position").is_err());
+        assert!(kv.get_color("origin").is_err());
+        assert_eq!(
+            kv.get_bool("origin").unwrap_err().to_string(),
+            "expected a boolean for \"origin\", found \"1 -2 3.5\""
+        );
+    }
 }
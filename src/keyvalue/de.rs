@@ -0,0 +1,756 @@
+use std::{borrow::Cow, fmt};
+
+use serde::de::{
+    self, value::BorrowedStrDeserializer, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess,
+    SeqAccess, VariantAccess, Visitor,
+};
+
+use super::{describe_span, KeyValues, Span};
+
+/// Deserializes KeyValues into any type implementing Deserialize
+/// Struct fields and enum variants are matched case insensitively
+/// A struct field that is a Vec collects every key with that name, use #[serde(default)] if there can be none
+/// Numbers are parsed from the text, tuples and arrays from text separated by whitespace like "0 0 64"
+/// An enum is either a value with the variant name or a block with a single key naming the variant
+pub fn from_keyvalues<'de, T: de::Deserialize<'de>>(kv: &'de KeyValues<'de>) -> Result<T, Error> {
+    T::deserialize(NodeDeserializer(kv))
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum Error {
+    #[error("{0}")]
+    Custom(String),
+    #[error("expected {expected}, found \"{value}\"{}", describe_span(.span))]
+    InvalidValue {
+        value: String,
+        expected: &'static str,
+        span: Span,
+    },
+    #[error("expected a value, found a block{}", describe_span(.0))]
+    ExpectedValue(Span),
+    #[error("expected a block, found a value{}", describe_span(.0))]
+    ExpectedBlock(Span),
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+/// Deserializes the text of a value, or a part of it
+#[derive(Clone, Copy)]
+struct ValueDeserializer<'de> {
+    value: &'de str,
+    span: Span,
+}
+
+impl ValueDeserializer<'_> {
+    fn parse<T: std::str::FromStr>(&self, expected: &'static str) -> Result<T, Error> {
+        self.value
+            .trim()
+            .parse()
+            .map_err(|_| self.invalid(expected))
+    }
+
+    fn invalid(&self, expected: &'static str) -> Error {
+        Error::InvalidValue {
+            value: self.value.to_string(),
+            expected,
+            span: self.span,
+        }
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident, $expected:literal;)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                visitor.$visit(self.parse($expected)?)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_str(self.value)
+    }
+
+    /// Like Source, every integer that isn't 0 is true
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let value = self.value.trim();
+        if value.eq_ignore_ascii_case("true") {
+            visitor.visit_bool(true)
+        } else if value.eq_ignore_ascii_case("false") {
+            visitor.visit_bool(false)
+        } else {
+            let value: i64 = self.parse("a boolean")?;
+            visitor.visit_bool(value != 0)
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_i8 => visit_i8, "an integer";
+        deserialize_i16 => visit_i16, "an integer";
+        deserialize_i32 => visit_i32, "an integer";
+        deserialize_i64 => visit_i64, "an integer";
+        deserialize_u8 => visit_u8, "a positive integer";
+        deserialize_u16 => visit_u16, "a positive integer";
+        deserialize_u32 => visit_u32, "a positive integer";
+        deserialize_u64 => visit_u64, "a positive integer";
+        deserialize_f32 => visit_f32, "a number";
+        deserialize_f64 => visit_f64, "a number";
+        deserialize_char => visit_char, "a single character";
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_str(self.value)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_str(self.value)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_bytes(self.value.as_bytes())
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_bytes(self.value.as_bytes())
+    }
+
+    /// Missing keys are handled by serde, so everything that exists is Some
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Splits the text at whitespace
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(WordsAccess {
+            words: self.value.split_whitespace(),
+            span: self.span,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error::ExpectedBlock(self.span))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Error> {
+        Err(Error::ExpectedBlock(self.span))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(EnumDeserializer {
+            variant: find_variant(variants, self.value),
+            content: None,
+        })
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_str(self.value)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+/// Deserializes a single node
+#[derive(Clone, Copy)]
+struct NodeDeserializer<'de>(&'de KeyValues<'de>);
+
+impl<'de> NodeDeserializer<'de> {
+    fn get_value(&self) -> Result<ValueDeserializer<'de>, Error> {
+        match self.0 {
+            KeyValues::Value { value, span, .. } => Ok(ValueDeserializer { value, span: *span }),
+            KeyValues::List { span, .. } => Err(Error::ExpectedValue(*span)),
+        }
+    }
+}
+
+macro_rules! forward_to_value {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                self.get_value()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for NodeDeserializer<'de> {
+    type Error = Error;
+
+    forward_to_value! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_f32
+        deserialize_f64 deserialize_char deserialize_str deserialize_string deserialize_bytes
+        deserialize_byte_buf deserialize_identifier
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            KeyValues::Value { .. } => self.get_value()?.deserialize_any(visitor),
+            KeyValues::List { .. } => self.deserialize_map(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// A value is split at whitespace, a block gives the values of its keys in order
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            KeyValues::Value { .. } => self.get_value()?.deserialize_seq(visitor),
+            KeyValues::List { subkeys, .. } => visitor.visit_seq(NodesAccess {
+                nodes: subkeys
+                    .iter()
+                    .map(|(_, value)| value)
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            }),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    /// Keeps every key, even duplicated ones
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            KeyValues::List { subkeys, .. } => visitor.visit_map(PairsAccess {
+                pairs: subkeys.iter(),
+                value: None,
+            }),
+            KeyValues::Value { span, .. } => Err(Error::ExpectedBlock(*span)),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let subkeys = match self.0 {
+            KeyValues::List { subkeys, .. } => subkeys,
+            KeyValues::Value { span, .. } => return Err(Error::ExpectedBlock(*span)),
+        };
+        let fields = fields
+            .iter()
+            .filter_map(|field| {
+                let nodes: Vec<&KeyValues> = subkeys
+                    .iter()
+                    .filter(|(key, _)| key.eq_ignore_ascii_case(field))
+                    .map(|(_, value)| value)
+                    .collect();
+                (!nodes.is_empty()).then_some((*field, nodes))
+            })
+            .collect::<Vec<_>>();
+        visitor.visit_map(FieldsAccess {
+            fields: fields.into_iter(),
+            nodes: None,
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.0 {
+            KeyValues::Value { .. } => self.get_value()?.deserialize_enum(name, variants, visitor),
+            KeyValues::List { subkeys, span } => match subkeys.as_slice() {
+                [(key, value)] => visitor.visit_enum(EnumDeserializer {
+                    variant: find_variant(variants, key),
+                    content: Some(NodeDeserializer(value)),
+                }),
+                _ => Err(Error::Custom(format!(
+                    "expected a block with a single key for {}{}",
+                    name,
+                    describe_span(span)
+                ))),
+            },
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+/// Deserializes every occurence of a struct field
+/// Sequences get every node, everything else only the first one
+struct FieldDeserializer<'de>(Vec<&'de KeyValues<'de>>);
+
+macro_rules! forward_to_first {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                NodeDeserializer(self.0[0]).$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for FieldDeserializer<'de> {
+    type Error = Error;
+
+    forward_to_first! {
+        deserialize_any deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32
+        deserialize_i64 deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_f32 deserialize_f64 deserialize_char deserialize_str deserialize_string
+        deserialize_bytes deserialize_byte_buf deserialize_unit deserialize_map
+        deserialize_identifier deserialize_ignored_any
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(NodesAccess {
+            nodes: self.0.into_iter(),
+        })
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        NodeDeserializer(self.0[0]).deserialize_unit_struct(name, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        NodeDeserializer(self.0[0]).deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        NodeDeserializer(self.0[0]).deserialize_tuple_struct(name, len, visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        NodeDeserializer(self.0[0]).deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        NodeDeserializer(self.0[0]).deserialize_enum(name, variants, visitor)
+    }
+}
+
+/// Gives every key and value of a block, for maps
+struct PairsAccess<'de> {
+    pairs: std::slice::Iter<'de, (Cow<'de, str>, KeyValues<'de>)>,
+    value: Option<&'de KeyValues<'de>>,
+}
+
+impl<'de> MapAccess<'de> for PairsAccess<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.pairs.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(BorrowedStrDeserializer::new(key))
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = self
+            .value
+            .take()
+            .expect("next_key_seed is always called first");
+        seed.deserialize(NodeDeserializer(value))
+    }
+}
+
+/// Gives every field of a struct that exists in the block
+struct FieldsAccess<'de> {
+    fields: std::vec::IntoIter<(&'static str, Vec<&'de KeyValues<'de>>)>,
+    nodes: Option<Vec<&'de KeyValues<'de>>>,
+}
+
+impl<'de> MapAccess<'de> for FieldsAccess<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.fields.next() {
+            Some((field, nodes)) => {
+                self.nodes = Some(nodes);
+                seed.deserialize(field.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let nodes = self
+            .nodes
+            .take()
+            .expect("next_key_seed is always called first");
+        seed.deserialize(FieldDeserializer(nodes))
+    }
+}
+
+struct NodesAccess<'de> {
+    nodes: std::vec::IntoIter<&'de KeyValues<'de>>,
+}
+
+impl<'de> SeqAccess<'de> for NodesAccess<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        self.nodes
+            .next()
+            .map(|node| seed.deserialize(NodeDeserializer(node)))
+            .transpose()
+    }
+}
+
+/// Gives the parts of a value like "0 0 64"
+struct WordsAccess<'de> {
+    words: std::str::SplitWhitespace<'de>,
+    span: Span,
+}
+
+impl<'de> SeqAccess<'de> for WordsAccess<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        self.words
+            .next()
+            .map(|value| {
+                seed.deserialize(ValueDeserializer {
+                    value,
+                    span: self.span,
+                })
+            })
+            .transpose()
+    }
+}
+
+/// Source doesn't care about case, so the variant is matched case insensitively
+fn find_variant<'de>(variants: &'static [&'static str], variant: &'de str) -> &'de str {
+    variants
+        .iter()
+        .find(|x| x.eq_ignore_ascii_case(variant))
+        .copied()
+        .unwrap_or(variant)
+}
+
+struct EnumDeserializer<'de> {
+    variant: &'de str,
+    content: Option<NodeDeserializer<'de>>,
+}
+
+impl<'de> EnumAccess<'de> for EnumDeserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let variant = seed.deserialize(BorrowedStrDeserializer::new(self.variant))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> EnumDeserializer<'de> {
+    fn get_content(&self) -> Result<NodeDeserializer<'de>, Error> {
+        self.content
+            .ok_or_else(|| Error::Custom(format!("variant {} needs a block", self.variant)))
+    }
+}
+
+impl<'de> VariantAccess<'de> for EnumDeserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        match self.content {
+            None => Ok(()),
+            Some(content) => Err(Error::ExpectedValue(content.0.span())),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self.get_content()?)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_tuple(self.get_content()?, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_struct(self.get_content()?, "", fields, visitor)
+    }
+}
+
+/// Always copies the text, so the KeyValues don't depend on the deserializer
+impl<'de> de::Deserialize<'de> for KeyValues<'static> {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeyValuesVisitor;
+
+        impl<'de> Visitor<'de> for KeyValuesVisitor {
+            type Value = KeyValues<'static>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a value or a block")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<KeyValues<'static>, E> {
+                Ok(KeyValues::new_value(v.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<KeyValues<'static>, A::Error> {
+                let mut subkeys = vec![];
+                while let Some(pair) = map.next_entry::<String, _>()? {
+                    subkeys.push(pair);
+                }
+                Ok(KeyValues::new_list(subkeys))
+            }
+        }
+
+        deserializer.deserialize_any(KeyValuesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::keyvalue::{Conditions, KeyValuesError, Location};
+
+    fn parse(content: &str) -> KeyValues<'_> {
+        KeyValues::parse_internal(content, "test.vmf", &Conditions::default(), &|x| {
+            Err(KeyValuesError::InvalidFile(x, Location::default()))
+        })
+        .unwrap()
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    enum Shape {
+        Point,
+        Box { mins: [f32; 3], maxs: [f32; 3] },
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Entity {
+        classname: String,
+        id: u32,
+        spawnflags: Option<i32>,
+        origin: (f32, f32, f32),
+        startdisabled: bool,
+        #[serde(default)]
+        connection: Vec<String>,
+        shape: Shape,
+        kind: Shape,
+    }
+
+    #[test]
+    fn test_deserialize_struct() {
+        let kv = parse(
+            r#"entity
+            {
+                "ClassName" "func_door"
+                "ID" "12"
+                "origin" "0 -16 64.5"
+                "StartDisabled" "1"
+                "connection" "OnOpen relay,Trigger"
+                "connection" "OnClose relay,Disable"
+                "unknown" "ignored"
+                "kind" "point"
+                "shape" { "box" { "mins" "0 0 0" "maxs" "1 2 3" } }
+            }"#,
+        );
+        let entity: Entity = from_keyvalues(kv.get("entity").unwrap()).unwrap();
+        assert_eq!(
+            entity,
+            Entity {
+                classname: "func_door".to_string(),
+                id: 12,
+                spawnflags: None,
+                origin: (0.0, -16.0, 64.5),
+                startdisabled: true,
+                connection: vec![
+                    "OnOpen relay,Trigger".to_string(),
+                    "OnClose relay,Disable".to_string()
+                ],
+                shape: Shape::Box {
+                    mins: [0.0; 3],
+                    maxs: [1.0, 2.0, 3.0]
+                },
+                kind: Shape::Point,
+            }
+        );
+    }
+
+    #[test]
+    fn test_deserialize_errors() {
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Side {
+            id: u32,
+        }
+
+        let kv = parse("side\n{\n\tid abc\n}");
+        assert_eq!(
+            from_keyvalues::<Side>(kv.get("side").unwrap()).unwrap_err(),
+            Error::InvalidValue {
+                value: "abc".to_string(),
+                expected: "a positive integer",
+                span: Span { line: 3, column: 2 },
+            }
+        );
+        let kv = parse("side\n{\n\tid { }\n}");
+        assert!(matches!(
+            from_keyvalues::<Side>(kv.get("side").unwrap()),
+            Err(Error::ExpectedValue(_))
+        ));
+        let kv = parse("side\n{\n}");
+        assert_eq!(
+            from_keyvalues::<Side>(kv.get("side").unwrap())
+                .unwrap_err()
+                .to_string(),
+            "missing field `id`"
+        );
+    }
+
+    #[test]
+    fn test_deserialize_keyvalues() {
+        let kv = parse("a { b 1 b 2 c { d e } }");
+        let copy: KeyValues = from_keyvalues(&kv).unwrap();
+        assert_eq!(copy.get_string("", 0), kv.get_string("", 0));
+    }
+}
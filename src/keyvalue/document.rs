@@ -0,0 +1,726 @@
+use std::{fmt, fs, io, path::Path};
+
+use super::{
+    escape_token, read_condition_text, read_text, skip_trivia, skip_whitespace, KeyValuesError,
+    Location, Reader,
+};
+
+/// KeyValues file that can be edited without losing its formatting
+/// Comments, blank lines, quoting, conditionals and key order are kept as they are
+/// Writing an unchanged document gives back exactly what was parsed
+/// Macros like #include are not resolved and just show up as normal keys
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    nodes: Vec<Node>,
+    /// Whitespace and comments after the last node
+    trailing: String,
+    newline: String,
+    indent: String,
+}
+
+/// A key with either a value or a block of other nodes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    /// Whitespace and comments before the key
+    prefix: String,
+    key: Token,
+    /// Everything between the key and the value or the opening brace, including a conditional
+    key_suffix: String,
+    key_condition: Option<String>,
+    body: Body,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Body {
+    Value {
+        value: Token,
+        /// Whitespace and the conditional after the value, empty if there is none
+        suffix: String,
+        condition: Option<String>,
+    },
+    Block {
+        nodes: Vec<Node>,
+        /// Whitespace and comments before the closing brace
+        trailing: String,
+    },
+}
+
+/// Text together with the way it was written in the file
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    text: String,
+    raw: String,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum DocumentError {
+    #[error("no key at \"{0}\"")]
+    NotFound(String),
+    #[error("\"{0}\" is a value, not a block")]
+    NotABlock(String),
+    #[error("\"{0}\" is a block, not a value")]
+    NotAValue(String),
+    #[error("index {0} is out of bounds")]
+    InvalidIndex(usize),
+}
+
+impl Document {
+    /// Reads a file and parses it into a Document
+    /// Errors if the file has invalid syntax
+    pub fn parse(file: &Path) -> Result<Self, KeyValuesError> {
+        let content = fs::read_to_string(file).map_err(|_| {
+            KeyValuesError::InvalidFile(file.to_string_lossy().to_string(), Location::default())
+        })?;
+        Self::parse_internal(&content, &file.to_string_lossy())
+    }
+
+    /// Parses the content of a file into a Document
+    /// file is only used for error messages
+    pub fn parse_internal(content: &str, file: &str) -> Result<Self, KeyValuesError> {
+        let mut reader = Reader::new(content);
+        let (nodes, trailing) = parse_nodes(content, &mut reader, false).map_err(|mut e| {
+            if let Some(location) = e.location_mut() {
+                location.file = file.to_string();
+            }
+            e
+        })?;
+        let newline = if content.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        // The KeyValues writer uses 4 spaces, Valve mostly uses tabs
+        let indent = if content.lines().any(|x| x.starts_with('\t')) {
+            "\t"
+        } else {
+            "    "
+        };
+        Ok(Self {
+            nodes,
+            trailing,
+            newline: newline.to_string(),
+            indent: indent.to_string(),
+        })
+    }
+
+    /// Writes the document into a file
+    pub fn write(&self, file: &Path) -> Result<(), io::Error> {
+        fs::write(file, self.to_string())
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Gets the first node at a path like "gameinfo/filesystem/searchpaths"
+    /// Keys are case insensitive
+    pub fn get(&self, path: &str) -> Option<&Node> {
+        let mut nodes = &self.nodes;
+        let mut found = None;
+        for key in split_path(path) {
+            let node = find(nodes, key)?;
+            found = Some(node);
+            nodes = match &node.body {
+                Body::Block { nodes, .. } => nodes,
+                Body::Value { .. } => &EMPTY,
+            };
+        }
+        found
+    }
+
+    /// Gets the value at a path
+    /// Returns None if there is no key or it is a block
+    pub fn get_value(&self, path: &str) -> Option<&str> {
+        self.get(path)?.value()
+    }
+
+    /// Changes the value at a path, keeping how it was quoted
+    /// Missing keys and blocks on the way are created
+    pub fn set(&mut self, path: &str, value: &str) -> Result<(), DocumentError> {
+        let (parent, key) = split_last(path);
+        let newline = self.newline.clone();
+        let indent = self.indent.clone();
+        let mut nodes = &mut self.nodes;
+        let mut trailing = &mut self.trailing;
+        let mut depth = 0;
+        let mut walked = String::new();
+        for part in split_path(parent) {
+            push_path(&mut walked, part);
+            let index = match position(nodes, part) {
+                Some(index) => index,
+                None => {
+                    let node = Node::new_block(part, &newline, &indent.repeat(depth));
+                    insert_node(nodes, trailing, nodes.len(), node);
+                    nodes.len() - 1
+                }
+            };
+            match &mut nodes[index].body {
+                Body::Block {
+                    nodes: children,
+                    trailing: inner,
+                } => {
+                    nodes = children;
+                    trailing = inner;
+                }
+                Body::Value { .. } => return Err(DocumentError::NotABlock(walked)),
+            }
+            depth += 1;
+        }
+        match position(nodes, key) {
+            Some(index) => match &mut nodes[index].body {
+                Body::Value { value: token, .. } => {
+                    token.set(value);
+                    Ok(())
+                }
+                Body::Block { .. } => Err(DocumentError::NotAValue(path.to_string())),
+            },
+            None => {
+                let node = Node::new_value(key, value, &newline, &indent.repeat(depth));
+                insert_node(nodes, trailing, nodes.len(), node);
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds a key with a value at the end of a block, even if the key already exists
+    /// An empty path adds it to the top level
+    pub fn insert(&mut self, parent: &str, key: &str, value: &str) -> Result<(), DocumentError> {
+        let depth = split_path(parent).count();
+        let node = Node::new_value(key, value, &self.newline, &self.indent.repeat(depth));
+        let (nodes, trailing) = self.get_block_mut(parent)?;
+        insert_node(nodes, trailing, nodes.len(), node);
+        Ok(())
+    }
+
+    /// Adds an empty block at the end of another block
+    /// An empty path adds it to the top level
+    pub fn insert_block(&mut self, parent: &str, key: &str) -> Result<(), DocumentError> {
+        let depth = split_path(parent).count();
+        let node = Node::new_block(key, &self.newline, &self.indent.repeat(depth));
+        let (nodes, trailing) = self.get_block_mut(parent)?;
+        insert_node(nodes, trailing, nodes.len(), node);
+        Ok(())
+    }
+
+    /// Removes the first node at a path together with the comments right above it
+    /// Comments on the line before stay where they are
+    pub fn remove(&mut self, path: &str) -> Result<Node, DocumentError> {
+        let (parent, key) = split_last(path);
+        let (nodes, trailing) = self.get_block_mut(parent)?;
+        let index = position(nodes, key).ok_or(DocumentError::NotFound(path.to_string()))?;
+        Ok(remove_node(nodes, trailing, index))
+    }
+
+    /// Renames the first key at a path, keeping how it was quoted
+    pub fn rename(&mut self, path: &str, new_key: &str) -> Result<(), DocumentError> {
+        let (parent, key) = split_last(path);
+        let (nodes, _) = self.get_block_mut(parent)?;
+        let index = position(nodes, key).ok_or(DocumentError::NotFound(path.to_string()))?;
+        nodes[index].key.set(new_key);
+        Ok(())
+    }
+
+    /// Moves the first node at a path to another position inside the same block
+    pub fn move_to(&mut self, path: &str, index: usize) -> Result<(), DocumentError> {
+        let (parent, key) = split_last(path);
+        let line_start = format!(
+            "{}{}",
+            self.newline,
+            self.indent.repeat(split_path(parent).count())
+        );
+        let (nodes, trailing) = self.get_block_mut(parent)?;
+        let old_index = position(nodes, key).ok_or(DocumentError::NotFound(path.to_string()))?;
+        if index >= nodes.len() {
+            return Err(DocumentError::InvalidIndex(index));
+        }
+        let mut node = remove_node(nodes, trailing, old_index);
+        if !node.prefix.contains('\n') {
+            // It was at the start of the file or block
+            node.prefix.insert_str(0, &line_start);
+        }
+        insert_node(nodes, trailing, index, node);
+        Ok(())
+    }
+
+    /// Gets the nodes and the trailing text of a block, an empty path is the top level
+    fn get_block_mut(
+        &mut self,
+        path: &str,
+    ) -> Result<(&mut Vec<Node>, &mut String), DocumentError> {
+        let mut nodes = &mut self.nodes;
+        let mut trailing = &mut self.trailing;
+        let mut walked = String::new();
+        for part in split_path(path) {
+            push_path(&mut walked, part);
+            let index = position(nodes, part).ok_or(DocumentError::NotFound(walked.clone()))?;
+            match &mut nodes[index].body {
+                Body::Block {
+                    nodes: children,
+                    trailing: inner,
+                } => {
+                    nodes = children;
+                    trailing = inner;
+                }
+                Body::Value { .. } => return Err(DocumentError::NotABlock(walked)),
+            }
+        }
+        Ok((nodes, trailing))
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for node in &self.nodes {
+            write!(f, "{}", node)?;
+        }
+        write!(f, "{}", self.trailing)
+    }
+}
+
+impl Node {
+    pub fn key(&self) -> &str {
+        &self.key.text
+    }
+
+    /// Returns None for blocks
+    pub fn value(&self) -> Option<&str> {
+        match &self.body {
+            Body::Value { value, .. } => Some(&value.text),
+            Body::Block { .. } => None,
+        }
+    }
+
+    /// Returns an empty slice for values
+    pub fn nodes(&self) -> &[Node] {
+        match &self.body {
+            Body::Value { .. } => &[],
+            Body::Block { nodes, .. } => nodes,
+        }
+    }
+
+    /// The conditional after the key or value, without the brackets
+    pub fn condition(&self) -> Option<&str> {
+        match &self.body {
+            Body::Value {
+                condition: Some(condition),
+                ..
+            } => Some(condition),
+            _ => self.key_condition.as_deref(),
+        }
+    }
+
+    fn new_value(key: &str, value: &str, newline: &str, indent: &str) -> Self {
+        Self {
+            prefix: format!("{}{}", newline, indent),
+            key: Token::new(key),
+            key_suffix: String::from(" "),
+            key_condition: None,
+            body: Body::Value {
+                value: Token::new(value),
+                suffix: String::new(),
+                condition: None,
+            },
+        }
+    }
+
+    fn new_block(key: &str, newline: &str, indent: &str) -> Self {
+        Self {
+            prefix: format!("{}{}", newline, indent),
+            key: Token::new(key),
+            key_suffix: format!("{}{}", newline, indent),
+            key_condition: None,
+            body: Body::Block {
+                nodes: vec![],
+                trailing: format!("{}{}", newline, indent),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", self.prefix, self.key.raw, self.key_suffix)?;
+        match &self.body {
+            Body::Value { value, suffix, .. } => write!(f, "{}{}", value.raw, suffix),
+            Body::Block { nodes, trailing } => {
+                write!(f, "{{")?;
+                for node in nodes {
+                    write!(f, "{}", node)?;
+                }
+                write!(f, "{}}}", trailing)
+            }
+        }
+    }
+}
+
+impl Token {
+    fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            raw: format!("\"{}\"", escape_token(text)),
+        }
+    }
+
+    /// Changes the text, only keeps it unquoted if it was before and still can be
+    fn set(&mut self, text: &str) {
+        let needs_quotes = text.is_empty()
+            || text.starts_with(['[', '/'])
+            || text
+                .chars()
+                .any(|c| matches!(c, ' ' | '\r' | '\n' | '\t' | '"' | '{' | '}' | '\\'));
+        self.raw = if self.raw.starts_with('"') || needs_quotes {
+            format!("\"{}\"", escape_token(text))
+        } else {
+            text.to_string()
+        };
+        self.text = text.to_string();
+    }
+}
+
+static EMPTY: Vec<Node> = Vec::new();
+
+/// Parses nodes until the end of the file or the closing brace of the current block
+/// Follows the same rules as KeyValues::parse_internal
+fn parse_nodes(
+    content: &str,
+    reader: &mut Reader,
+    in_block: bool,
+) -> Result<(Vec<Node>, String), KeyValuesError> {
+    let mut nodes = vec![];
+    loop {
+        let start = reader.offset;
+        skip_trivia(reader);
+        let prefix = content[start..reader.offset].to_string();
+        match reader.peek() {
+            None if in_block => return Err(KeyValuesError::UnexpectedEnd(reader.location())),
+            None => return Ok((nodes, prefix)),
+            Some(b'}') if in_block => {
+                reader.next();
+                return Ok((nodes, prefix));
+            }
+            Some(b'}') => {
+                return Err(KeyValuesError::UnexpectedClosingBrace(
+                    nodes.last().map_or(String::new(), |x| x.key.text.clone()),
+                    reader.location(),
+                ))
+            }
+            _ => {}
+        }
+        let key = read_token(content, reader)?;
+
+        let start = reader.offset;
+        skip_whitespace(reader);
+        let key_condition = read_condition_text(reader)?.map(str::to_string);
+        if key_condition.is_some() {
+            skip_whitespace(reader);
+        }
+        let key_suffix = content[start..reader.offset].to_string();
+
+        let location = reader.location();
+        let body = match reader
+            .peek()
+            .ok_or(KeyValuesError::UnexpectedEnd(location))?
+        {
+            b'{' => {
+                reader.next();
+                let (nodes, trailing) = parse_nodes(content, reader, true)?;
+                Body::Block { nodes, trailing }
+            }
+            b'}' => {
+                return Err(KeyValuesError::UnexpectedClosingBrace(
+                    key.text,
+                    reader.location(),
+                ))
+            }
+            _ => {
+                let value = read_token(content, reader)?;
+                // Only take the whitespace if there is a conditional after it
+                let mut lookahead = reader.clone();
+                skip_whitespace(&mut lookahead);
+                let condition = read_condition_text(&mut lookahead)?.map(str::to_string);
+                let mut suffix = String::new();
+                if condition.is_some() {
+                    skip_whitespace(&mut lookahead);
+                    suffix = content[reader.offset..lookahead.offset].to_string();
+                    *reader = lookahead;
+                }
+                Body::Value {
+                    value,
+                    suffix,
+                    condition,
+                }
+            }
+        };
+        nodes.push(Node {
+            prefix,
+            key,
+            key_suffix,
+            key_condition,
+            body,
+        });
+    }
+}
+
+fn read_token(content: &str, reader: &mut Reader) -> Result<Token, KeyValuesError> {
+    let start = reader.offset;
+    let text = read_text(reader)?.into_owned();
+    Ok(Token {
+        text,
+        raw: content[start..reader.offset].to_string(),
+    })
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|x| !x.is_empty())
+}
+
+/// Splits "a/b/c" into "a/b" and "c"
+fn split_last(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+fn push_path(path: &mut String, part: &str) {
+    if !path.is_empty() {
+        path.push('/');
+    }
+    path.push_str(part);
+}
+
+fn position(nodes: &[Node], key: &str) -> Option<usize> {
+    nodes
+        .iter()
+        .position(|x| x.key.text.eq_ignore_ascii_case(key))
+}
+
+fn find<'a>(nodes: &'a [Node], key: &str) -> Option<&'a Node> {
+    position(nodes, key).map(|x| &nodes[x])
+}
+
+/// Splits trivia after the end of the first line, so comments behind the previous node stay with it
+fn split_first_line(trivia: &str) -> usize {
+    let mut chars = trivia.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\n' if trivia[..i].ends_with('\r') => return i - 1,
+            '\n' => return i,
+            '/' if chars.peek().map(|x| x.1) == Some('*') => {
+                chars.next();
+                let mut last = ' ';
+                for (_, c) in chars.by_ref() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            '/' => {
+                while chars.peek().map(|x| x.1).is_some_and(|x| x != '\n') {
+                    chars.next();
+                }
+            }
+            _ => {}
+        }
+    }
+    trivia.len()
+}
+
+fn remove_node(nodes: &mut Vec<Node>, trailing: &mut String, index: usize) -> Node {
+    let mut node = nodes.remove(index);
+    let at_start = index == 0 && !node.prefix.contains('\n');
+    let split = split_first_line(&node.prefix);
+    let head = node.prefix[..split].to_string();
+    node.prefix.replace_range(..split, "");
+    let next_prefix = match nodes.get_mut(index) {
+        Some(next) => &mut next.prefix,
+        None => trailing,
+    };
+    if at_start {
+        // The next node moves to the start of the file or block
+        *next_prefix = next_prefix.trim_start_matches(['\r', '\n']).to_string();
+    }
+    next_prefix.insert_str(0, &head);
+    node
+}
+
+/// Inserts a node whose prefix starts on a new line
+fn insert_node(nodes: &mut Vec<Node>, trailing: &mut String, index: usize, mut node: Node) {
+    let line_start = line_start(&node.prefix).to_string();
+    let has_next = index < nodes.len();
+    let next_prefix = match nodes.get_mut(index) {
+        Some(next) => &mut next.prefix,
+        None => &mut *trailing,
+    };
+    let at_start = index == 0 && !next_prefix.contains('\n');
+    let split = split_first_line(next_prefix);
+    let head = next_prefix[..split].to_string();
+    next_prefix.replace_range(..split, "");
+    if has_next && next_prefix.is_empty() {
+        // The next node was on the same line
+        *next_prefix = line_start.clone();
+    }
+    if at_start && line_start.trim_start_matches(['\r', '\n']).is_empty() {
+        // Nothing comes before it in the file
+        node.prefix = node.prefix.trim_start_matches(['\r', '\n']).to_string();
+    }
+    node.prefix.insert_str(0, &head);
+    nodes.insert(index, node);
+}
+
+/// The line break and indentation at the end of a prefix
+fn line_start(prefix: &str) -> &str {
+    let Some(start) = prefix.rfind('\n') else {
+        return "";
+    };
+    let start = if prefix[..start].ends_with('\r') {
+        start - 1
+    } else {
+        start
+    };
+    let end = prefix[start..]
+        .find(|c: char| !c.is_whitespace())
+        .map_or(prefix.len(), |x| start + x);
+    &prefix[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAMEINFO: &str = "// Comment at the top\r\n\"GameInfo\"\r\n{\r\n\tgame \"Half-Life 2\" // the name\r\n\ttype singleplayer_only\r\n\r\n\t/* Search\r\n\t   paths */\r\n\tFileSystem\r\n\t{\r\n\t\tSteamAppId 220 [$WIN32]\r\n\t\tSearchPaths\r\n\t\t{\r\n\t\t\tgame+mod hl2/hl2_pak.vpk\r\n\t\t}\r\n\t}\r\n}\r\n";
+
+    fn parse(content: &str) -> Document {
+        Document::parse_internal(content, "test.txt").unwrap()
+    }
+
+    #[test]
+    fn test_write_unchanged() {
+        assert_eq!(parse(GAMEINFO).to_string(), GAMEINFO);
+        let content = "a 1\nb [$X360] { c \"2\" [!$WIN32]\n\n}\n/* end";
+        assert_eq!(parse(content).to_string(), content);
+        let content = include_str!("../../tests/data/sample.vmf");
+        assert_eq!(parse(content).to_string(), content);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = Document::parse_internal("a 1\n}\n", "test.txt").unwrap_err();
+        assert!(matches!(error, KeyValuesError::UnexpectedClosingBrace(ref x, _) if x == "a"));
+        assert_eq!(
+            error,
+            crate::keyvalue::KeyValues::parse_internal(
+                "a 1\n}\n",
+                "test.txt",
+                &Default::default(),
+                &|x| Err(KeyValuesError::InvalidFile(x, Default::default()))
+            )
+            .unwrap_err()
+        );
+        assert!(matches!(
+            Document::parse_internal("a {\n", "test.txt"),
+            Err(KeyValuesError::UnexpectedEnd(_))
+        ));
+    }
+
+    #[test]
+    fn test_get() {
+        let document = parse(GAMEINFO);
+        assert_eq!(document.get_value("gameinfo/game"), Some("Half-Life 2"));
+        assert_eq!(
+            document.get_value("GameInfo/FileSystem/SteamAppId"),
+            Some("220")
+        );
+        assert_eq!(
+            document
+                .get("gameinfo/filesystem/steamappid")
+                .unwrap()
+                .condition(),
+            Some("$WIN32")
+        );
+        assert_eq!(document.get_value("gameinfo/filesystem"), None);
+        assert!(document.get("gameinfo/missing").is_none());
+        assert!(document.get("gameinfo/game/x").is_none());
+    }
+
+    #[test]
+    fn test_set() {
+        let mut document = parse(GAMEINFO);
+        document
+            .set("gameinfo/game", "Half-Life 2: Episode One")
+            .unwrap();
+        document.set("gameinfo/type", "multiplayer_only").unwrap();
+        document.set("gameinfo/type2", "x y").unwrap();
+        assert_eq!(
+            document.to_string(),
+            GAMEINFO
+                .replace("\"Half-Life 2\"", "\"Half-Life 2: Episode One\"")
+                .replace("singleplayer_only", "multiplayer_only")
+                .replace("\t}\r\n}", "\t}\r\n\t\"type2\" \"x y\"\r\n}")
+        );
+
+        let mut document = parse("a 1\n");
+        document.set("b/c", "2").unwrap();
+        assert_eq!(document.to_string(), "a 1\n\"b\"\n{\n    \"c\" \"2\"\n}\n");
+        assert_eq!(
+            document.set("a/c", "3"),
+            Err(DocumentError::NotABlock(String::from("a")))
+        );
+        assert_eq!(
+            document.set("b", "3"),
+            Err(DocumentError::NotAValue(String::from("b")))
+        );
+
+        let mut document = Document::parse_internal("", "").unwrap();
+        document.set("a", "1").unwrap();
+        assert_eq!(document.to_string(), "\"a\" \"1\"");
+    }
+
+    #[test]
+    fn test_remove_and_rename() {
+        let mut document = parse(GAMEINFO);
+        let node = document.remove("gameinfo/filesystem").unwrap();
+        assert_eq!(node.nodes().len(), 2);
+        assert_eq!(
+            document.to_string(),
+            "// Comment at the top\r\n\"GameInfo\"\r\n{\r\n\tgame \"Half-Life 2\" // the name\r\n\ttype singleplayer_only\r\n}\r\n"
+        );
+        document.remove("gameinfo/type").unwrap();
+        assert_eq!(
+            document.to_string(),
+            "// Comment at the top\r\n\"GameInfo\"\r\n{\r\n\tgame \"Half-Life 2\" // the name\r\n}\r\n"
+        );
+        document.rename("gameinfo/game", "title").unwrap();
+        document.rename("gameinfo", "Info").unwrap();
+        assert_eq!(
+            document.to_string(),
+            "// Comment at the top\r\n\"Info\"\r\n{\r\n\ttitle \"Half-Life 2\" // the name\r\n}\r\n"
+        );
+        assert_eq!(
+            document.remove("info/game"),
+            Err(DocumentError::NotFound(String::from("info/game")))
+        );
+    }
+
+    #[test]
+    fn test_insert_and_move() {
+        let mut document = parse("a 1 // first\nb 2\nc 3\n");
+        document.move_to("c", 0).unwrap();
+        assert_eq!(document.to_string(), "c 3\na 1 // first\nb 2\n");
+        document.move_to("c", 2).unwrap();
+        assert_eq!(document.to_string(), "a 1 // first\nb 2\nc 3\n");
+        document.move_to("b", 0).unwrap();
+        assert_eq!(document.to_string(), "b 2\na 1 // first\nc 3\n");
+        document.insert("", "a", "again").unwrap();
+        document.insert_block("", "d").unwrap();
+        document.insert("d", "e", "").unwrap();
+        assert_eq!(
+            document.to_string(),
+            "b 2\na 1 // first\nc 3\n\"a\" \"again\"\n\"d\"\n{\n    \"e\" \"\"\n}\n"
+        );
+        assert_eq!(document.get_value("a"), Some("1"));
+        assert_eq!(
+            document.move_to("a", 9),
+            Err(DocumentError::InvalidIndex(9))
+        );
+    }
+}
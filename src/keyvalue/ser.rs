@@ -0,0 +1,507 @@
+use std::fmt;
+
+use serde::ser::{self, Serialize};
+
+use super::KeyValues;
+
+/// Serializes any type implementing Serialize into KeyValues
+/// Uses the same rules as de::from_keyvalues, so the result can be read back
+/// The top level has to be a struct or a map
+/// Booleans are written as 1 and 0, None is left out (also inside a sequence)
+/// An empty sequence leaves out the key too, its field needs #[serde(default)] to be read back
+pub fn to_keyvalues<T: Serialize + ?Sized>(value: &T) -> Result<KeyValues<'static>, Error> {
+    match value.serialize(ValueSerializer)? {
+        Serialized::Node(kv @ KeyValues::List { .. }) => Ok(kv),
+        _ => Err(Error::InvalidTopLevel),
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum Error {
+    #[error("{0}")]
+    Custom(String),
+    #[error("the top level has to be a struct or a map")]
+    InvalidTopLevel,
+    #[error("keys have to be text or numbers")]
+    InvalidKey,
+    #[error("tuples can only contain text or numbers")]
+    InvalidTupleElement,
+    #[error("sequences can't contain other sequences")]
+    NestedSequence,
+    #[error("bytes have to be valid UTF-8")]
+    InvalidBytes,
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+impl Serialize for KeyValues<'_> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            KeyValues::Value { value, .. } => serializer.serialize_str(value),
+            KeyValues::List { subkeys, .. } => {
+                use ser::SerializeMap;
+                let mut map = serializer.serialize_map(Some(subkeys.len()))?;
+                for (key, value) in subkeys {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+/// What a Rust value turns into
+enum Serialized {
+    Node(KeyValues<'static>),
+    /// Every node gets the same key
+    Repeated(Vec<KeyValues<'static>>),
+    /// The key is left out
+    Skip,
+}
+
+impl Serialized {
+    fn value(value: impl ToString) -> Self {
+        Self::Node(KeyValues::new_value(value.to_string()))
+    }
+
+    /// Wraps the node in a block with the variant as key
+    fn variant(variant: &str, node: KeyValues<'static>) -> Self {
+        Self::Node(KeyValues::new_list(vec![(variant.to_string(), node)]))
+    }
+
+    fn into_text(self, error: Error) -> Result<String, Error> {
+        match self {
+            Serialized::Node(KeyValues::Value { value, .. }) => Ok(value.into_owned()),
+            _ => Err(error),
+        }
+    }
+}
+
+struct ValueSerializer;
+
+macro_rules! serialize_display {
+    ($($method:ident: $type:ty)*) => {
+        $(
+            fn $method(self, v: $type) -> Result<Serialized, Error> {
+                Ok(Serialized::value(v))
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = TupleSerializer;
+    type SerializeTupleStruct = TupleSerializer;
+    type SerializeTupleVariant = TupleSerializer;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = MapSerializer;
+
+    serialize_display! {
+        serialize_i8: i8 serialize_i16: i16 serialize_i32: i32 serialize_i64: i64
+        serialize_u8: u8 serialize_u16: u16 serialize_u32: u32 serialize_u64: u64
+        serialize_f32: f32 serialize_f64: f64 serialize_char: char serialize_str: &str
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<Serialized, Error> {
+        Ok(Serialized::value(if v { "1" } else { "0" }))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Serialized, Error> {
+        Ok(Serialized::value(
+            std::str::from_utf8(v).map_err(|_| Error::InvalidBytes)?,
+        ))
+    }
+
+    fn serialize_none(self) -> Result<Serialized, Error> {
+        Ok(Serialized::Skip)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Serialized, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Serialized, Error> {
+        Ok(Serialized::Skip)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Serialized, Error> {
+        Ok(Serialized::Skip)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Serialized, Error> {
+        Ok(Serialized::value(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Serialized, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Serialized, Error> {
+        match value.serialize(self)? {
+            Serialized::Node(node) => Ok(Serialized::variant(variant, node)),
+            _ => Ok(Serialized::variant(
+                variant,
+                KeyValues::new_list(Vec::<(String, _)>::new()),
+            )),
+        }
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SeqSerializer, Error> {
+        Ok(SeqSerializer { nodes: vec![] })
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<TupleSerializer, Error> {
+        Ok(TupleSerializer {
+            words: vec![],
+            variant: None,
+        })
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<TupleSerializer, Error> {
+        self.serialize_tuple(_len)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<TupleSerializer, Error> {
+        Ok(TupleSerializer {
+            words: vec![],
+            variant: Some(variant),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapSerializer, Error> {
+        Ok(MapSerializer {
+            subkeys: vec![],
+            key: None,
+            variant: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<MapSerializer, Error> {
+        self.serialize_map(None)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<MapSerializer, Error> {
+        Ok(MapSerializer {
+            subkeys: vec![],
+            key: None,
+            variant: Some(variant),
+        })
+    }
+}
+
+/// A sequence turns into the same key repeated for every element
+struct SeqSerializer {
+    nodes: Vec<KeyValues<'static>>,
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        match value.serialize(ValueSerializer)? {
+            Serialized::Node(node) => self.nodes.push(node),
+            Serialized::Repeated(_) => return Err(Error::NestedSequence),
+            Serialized::Skip => {}
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        Ok(Serialized::Repeated(self.nodes))
+    }
+}
+
+/// A tuple turns into a single value separated by spaces, like "0 0 64"
+struct TupleSerializer {
+    words: Vec<String>,
+    variant: Option<&'static str>,
+}
+
+impl TupleSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let word = value
+            .serialize(ValueSerializer)?
+            .into_text(Error::InvalidTupleElement)?;
+        self.words.push(word);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Serialized, Error> {
+        let node = KeyValues::new_value(self.words.join(" "));
+        Ok(match self.variant {
+            Some(variant) => Serialized::variant(variant, node),
+            None => Serialized::Node(node),
+        })
+    }
+}
+
+impl ser::SerializeTuple for TupleSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for TupleSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for TupleSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        self.finish()
+    }
+}
+
+/// Maps and structs turn into a block
+struct MapSerializer {
+    subkeys: Vec<(String, KeyValues<'static>)>,
+    key: Option<String>,
+    variant: Option<&'static str>,
+}
+
+impl MapSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), Error> {
+        match value.serialize(ValueSerializer)? {
+            Serialized::Node(node) => self.subkeys.push((key.to_string(), node)),
+            Serialized::Repeated(nodes) => self
+                .subkeys
+                .extend(nodes.into_iter().map(|node| (key.to_string(), node))),
+            Serialized::Skip => {}
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Serialized, Error> {
+        let node = KeyValues::new_list(self.subkeys);
+        Ok(match self.variant {
+            Some(variant) => Serialized::variant(variant, node),
+            None => Serialized::Node(node),
+        })
+    }
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(
+            key.serialize(ValueSerializer)?
+                .into_text(Error::InvalidKey)?,
+        );
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_key is always called first");
+        self.push(&key, value)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for MapSerializer {
+    type Ok = Serialized;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Serialized, Error> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::keyvalue::de::from_keyvalues;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Kind {
+        Point,
+        Sphere { radius: f32 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Solid {
+        id: u32,
+        visible: bool,
+        origin: [f32; 3],
+        name: Option<String>,
+        side: Vec<Side>,
+        kind: Kind,
+        shape: Kind,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Side {
+        material: String,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let solid = Solid {
+            id: 3,
+            visible: true,
+            origin: [0.0, 1.5, -2.0],
+            name: None,
+            side: vec![
+                Side {
+                    material: "tools/toolsnodraw".to_string(),
+                },
+                Side {
+                    material: "dev/dev_measuregeneric01".to_string(),
+                },
+            ],
+            kind: Kind::Point,
+            shape: Kind::Sphere { radius: 8.0 },
+        };
+        let kv = to_keyvalues(&solid).unwrap();
+        assert_eq!(kv.query("visible").unwrap().get_value().unwrap(), "1");
+        assert_eq!(kv.query("origin").unwrap().get_value().unwrap(), "0 1.5 -2");
+        assert!(kv.query("name").is_none());
+        assert_eq!(kv.get_all("side").len(), 2);
+        assert_eq!(kv.query("kind").unwrap().get_value().unwrap(), "Point");
+        assert_eq!(kv.get_str("shape/Sphere/radius").unwrap(), "8");
+        assert_eq!(from_keyvalues::<Solid>(&kv).unwrap(), solid);
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Group {
+        name: String,
+        #[serde(default)]
+        items: Vec<i32>,
+    }
+
+    #[test]
+    fn test_round_trip_empty() {
+        let group = Group {
+            name: "empty".to_string(),
+            items: vec![],
+        };
+        let kv = to_keyvalues(&group).unwrap();
+        assert!(kv.query("items").is_none());
+        assert_eq!(from_keyvalues::<Group>(&kv).unwrap(), group);
+
+        // Without the default the missing key is an error
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Strict {
+            name: String,
+            items: Vec<i32>,
+        }
+        assert!(from_keyvalues::<Strict>(&kv).is_err());
+
+        let kv = to_keyvalues(&std::collections::BTreeMap::from([(
+            "a",
+            vec![None, Some(1)],
+        )]))
+        .unwrap();
+        assert_eq!(kv.get_all("a").len(), 1);
+    }
+
+    #[test]
+    fn test_serialize_errors() {
+        assert_eq!(to_keyvalues(&5).unwrap_err(), Error::InvalidTopLevel);
+        assert_eq!(
+            to_keyvalues(&std::collections::BTreeMap::from([((), 3)])).unwrap_err(),
+            Error::InvalidKey
+        );
+        assert_eq!(
+            to_keyvalues(&std::collections::BTreeMap::from([("a", vec![vec![1]])])).unwrap_err(),
+            Error::NestedSequence
+        );
+    }
+}
@@ -1,7 +1,14 @@
+pub mod csg;
+pub mod document;
 pub mod fgd;
 pub mod gameinfo;
+pub mod ids;
 pub mod keyvalue;
 pub mod material;
 pub mod math;
+pub mod mesh;
+pub mod primitives;
+#[cfg(test)]
+mod test_utils;
 pub mod vmf;
 pub mod vpk;
@@ -7,12 +7,16 @@ use screwdriver::{
     fgd::FGD,
     gameinfo::Gameinfo,
     material::Material,
-    vmf::{Brush, Face, VMF},
+    mesh::{self, Mesh},
+    vmf::{Brush, CordonBox, Visgroup, VMF},
 };
 use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
 use sdl2::video::GLProfile;
-use std::{collections::HashMap, f32::consts::PI};
+use std::{
+    collections::{HashMap, HashSet},
+    f32::consts::PI,
+};
 use std::{env, path::Path};
 use vtflib::VtfLib;
 
@@ -25,7 +29,11 @@ fn main() {
 
     let gameinfo = Gameinfo::parse(Path::new(&args[2])).unwrap();
 
-    let vmf = VMF::parse(Path::new(&args[1])).unwrap();
+    let vmf_path = Path::new(&args[1]);
+    let (vmf, warnings) = VMF::parse_lenient(vmf_path).unwrap();
+    for warning in warnings {
+        eprintln!("Skipped broken part of the map: {warning}");
+    }
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -61,7 +69,19 @@ fn main() {
     let vtf = vtflib.new_vtf_file();
     let mut vtf = vtf.bind(&mut guard);
 
-    let vertex_data = get_vertexdatas(&gameinfo, &renderer, &vmf.worldbrushes);
+    // Visgroups hidden with the checkboxes, hiding a visgroup also hides the ones inside it
+    let mut hidden_visgroups = HashSet::new();
+    let cordon_boxes = vmf
+        .cordons
+        .as_ref()
+        .map(|cordons| cordons.get_active_boxes())
+        .unwrap_or_default();
+    let mut clip_to_cordons = false;
+    let brushes = get_visible_brushes(&vmf, &vmf.get_hidden_visgroups(&hidden_visgroups), &[]);
+    let mut walkable_view = false;
+    let (mut vertex_data, mut walkable_data) =
+        get_vertexdatas(&gameinfo, &renderer, &brushes, walkable_view);
+    let cordon_lines = get_box_lines(&renderer, &cordon_boxes);
 
     let mut textures = HashMap::new();
     for material in vertex_data.keys() {
@@ -95,6 +115,9 @@ fn main() {
         ],
     )
     .expect("Data is safe");
+    // Red, yellow and green, in the order of Walkability
+    let walkable_textures = [[255, 64, 64, 255], [255, 255, 64, 255], [64, 255, 64, 255]]
+        .map(|color| Texture::create_from_data(&renderer, 1, 1, &color).expect("Data is safe"));
 
     let mut lightmappedgeneric = renderer::Shader::create(
         &renderer,
@@ -116,20 +139,41 @@ fn main() {
     )
     .unwrap();
 
+    let mut color = renderer::Shader::create(
+        &renderer,
+        include_str!("shaders/color-vert.glsl"),
+        include_str!("shaders/color-frag.glsl"),
+    )
+    .unwrap();
+
     let proj = glm::perspective::<f32>(1280.0 / 720.0, f32::to_radians(45.0), 1.0, 16384.0);
     renderer.enable_depth_test(true);
     renderer.enable_backface_culling(true);
 
+    // Start where the 3D view of Hammer was when the map got saved
     let mut camera_pos = glm::vec3(0.0, 0.0, 0.0);
     let mut camera_pitch: f32 = 0.0;
     let mut camera_yaw: f32 = 0.0;
+    let mut active_camera = None;
+    if let Some(cameras) = &vmf.cameras {
+        if let Some(camera) = cameras.active() {
+            camera_pos = camera.position;
+            (camera_yaw, camera_pitch) = camera.yaw_pitch();
+            active_camera = Some(cameras.active_camera as usize);
+        }
+    }
 
-    let camera_speed = 64.0;
+    // Move one grid step at a time
+    let camera_speed = vmf
+        .viewsettings
+        .as_ref()
+        .map_or(64.0, |viewsettings| viewsettings.grid_spacing as f32);
     let camera_rotate_speed = 0.1;
     let camera_up = glm::vec3(0.0, 0.0, 1.0);
 
     let mut draw_tool = true;
     let mut draw_missing = true;
+    let mut draw_cordons = true;
 
     let mut event_pump = sdl_context.event_pump().unwrap();
     'main_loop: loop {
@@ -179,6 +223,53 @@ fn main() {
         ui.window("View settings").build(|| {
             ui.checkbox("Draw Tool Textures", &mut draw_tool);
             ui.checkbox("Draw missing Materials", &mut draw_missing);
+            let mut changed = ui.checkbox("Displacement walkable view", &mut walkable_view);
+            let visgroups = vmf.visgroups.as_deref().unwrap_or_default();
+            if !visgroups.is_empty() {
+                ui.separator();
+                ui.text("Visgroups");
+                for visgroup in visgroups {
+                    changed |= visgroup_checkboxes(ui, visgroup, &mut hidden_visgroups);
+                }
+            }
+            if !cordon_boxes.is_empty() {
+                ui.separator();
+                ui.text("Cordons");
+                ui.checkbox("Draw cordons", &mut draw_cordons);
+                changed |= ui.checkbox("Clip to cordons", &mut clip_to_cordons);
+                if ui.button("Export cordoned VMF") {
+                    let path = vmf_path.with_extension("cordon.vmf");
+                    let cordoned = vmf.get_cordoned().expect("There are active cordons");
+                    match cordoned.write(&path) {
+                        Ok(()) => println!("Exported cordoned map to {}", path.display()),
+                        Err(e) => eprintln!("Couldn't export cordoned map: {e}"),
+                    }
+                }
+            }
+            if changed {
+                let brushes = get_visible_brushes(
+                    &vmf,
+                    &vmf.get_hidden_visgroups(&hidden_visgroups),
+                    if clip_to_cordons { &cordon_boxes } else { &[] },
+                );
+                (vertex_data, walkable_data) =
+                    get_vertexdatas(&gameinfo, &renderer, &brushes, walkable_view);
+            }
+            if let Some(cameras) = vmf.cameras.as_ref().filter(|x| !x.cameras.is_empty()) {
+                ui.separator();
+                ui.text("Cameras");
+                for (i, camera) in cameras.cameras.iter().enumerate() {
+                    if ui
+                        .selectable_config(format!("Camera {}##camera{}", i + 1, i))
+                        .selected(active_camera == Some(i))
+                        .build()
+                    {
+                        camera_pos = camera.position;
+                        (camera_yaw, camera_pitch) = camera.yaw_pitch();
+                        active_camera = Some(i);
+                    }
+                }
+            }
             // imgui::Image::new(
             //     TextureId::new(texture.get_id() as usize),
             //     [texture.width as f32, texture.height as f32],
@@ -204,6 +295,8 @@ fn main() {
         worldvertextransition.set_uniform_mat4("projection", &proj);
         worldvertextransition.set_uniform_mat4("view", &view);
         worldvertextransition.set_uniform_mat3("normal_transform", &normal_transform);
+        color.set_uniform_mat4("projection", &proj);
+        color.set_uniform_mat4("view", &view);
 
         for (material, data) in &vertex_data {
             if draw_tool || !material.is_tool() {
@@ -254,270 +347,196 @@ fn main() {
                 }
             }
         }
+        if walkable_view {
+            for (data, texture) in walkable_data.iter().zip(&walkable_textures) {
+                lightmappedgeneric.set_uniform_texture("basetexture", texture, 0);
+                lightmappedgeneric.set_uniform_vec2("tex_size", &glm::vec2(1.0, 1.0));
+                renderer.draw(data, &lightmappedgeneric);
+            }
+        }
+
+        if draw_cordons {
+            color.set_uniform_vec3("color", &glm::vec3(1.0, 0.0, 0.0));
+            renderer.draw_lines(&cordon_lines, &color);
+        }
 
         imgui_renderer.render(draw_data).unwrap();
         window.gl_swap_window();
     }
 }
 
+/// Shows a checkbox for the visgroup and, indented below it, the ones inside it
+/// Returns whether any of them got toggled
+fn visgroup_checkboxes(ui: &imgui::Ui, visgroup: &Visgroup, hidden: &mut HashSet<i32>) -> bool {
+    let mut shown = !hidden.contains(&visgroup.id);
+    let mut changed = false;
+    if ui.checkbox(
+        format!("{}##visgroup{}", visgroup.name, visgroup.id),
+        &mut shown,
+    ) {
+        if shown {
+            hidden.remove(&visgroup.id);
+        } else {
+            hidden.insert(visgroup.id);
+        }
+        changed = true;
+    }
+    if !visgroup.children.is_empty() {
+        ui.indent();
+        for child in &visgroup.children {
+            changed |= visgroup_checkboxes(ui, child, hidden);
+        }
+        ui.unindent();
+    }
+    changed
+}
+
+/// Gets the brushes of the world and the entities that aren't in a hidden visgroup
+/// If there are cordon boxes, brushes outside all of them are left out as well
+fn get_visible_brushes<'a>(
+    vmf: &'a VMF,
+    hidden_visgroups: &HashSet<i32>,
+    cordon_boxes: &[&CordonBox],
+) -> Vec<&'a Brush> {
+    vmf.world
+        .brushes
+        .iter()
+        .chain(
+            vmf.entities
+                .iter()
+                .filter(|entity| entity.is_visible(hidden_visgroups))
+                .flat_map(|entity| &entity.brushes),
+        )
+        .filter(|brush| brush.is_visible(hidden_visgroups))
+        .filter(|brush| {
+            cordon_boxes.is_empty()
+                || brush.get_bounds().is_some_and(|(mins, maxs)| {
+                    cordon_boxes.iter().any(|x| x.intersects(&mins, &maxs))
+                })
+        })
+        .collect()
+}
+
+/// Builds the 12 edges of every box as lines
+fn get_box_lines(renderer: &Renderer, boxes: &[&CordonBox]) -> VertexData {
+    let mut positions = vec![];
+    for cordon_box in boxes {
+        let corner = |i: usize| {
+            glm::vec3(
+                if i & 1 == 0 {
+                    cordon_box.mins.x
+                } else {
+                    cordon_box.maxs.x
+                },
+                if i & 2 == 0 {
+                    cordon_box.mins.y
+                } else {
+                    cordon_box.maxs.y
+                },
+                if i & 4 == 0 {
+                    cordon_box.mins.z
+                } else {
+                    cordon_box.maxs.z
+                },
+            )
+        };
+        // Corners whose index differs in exactly one bit share an edge
+        for i in 0..8 {
+            for bit in [1, 2, 4] {
+                if i & bit == 0 {
+                    positions.extend(corner(i).iter());
+                    positions.extend(corner(i | bit).iter());
+                }
+            }
+        }
+    }
+    let mut vertex_data = VertexData::create(renderer).unwrap();
+    vertex_data
+        .add_data(&positions, renderer::VertexSize::VEC3, 0)
+        .unwrap();
+    vertex_data
+}
+
+/// How displacement triangles get coloured in the walkable view, like in Hammer
+#[derive(Clone, Copy)]
+enum Walkability {
+    NotWalkable,
+    Walkable,
+    /// Walkable and buildable
+    Buildable,
+}
+
+/// Builds the vertex data of the brushes, sorted by material
+/// In the walkable view, displacements get left out and returned separately, sorted by Walkability
 fn get_vertexdatas(
     gameinfo: &Gameinfo,
     renderer: &Renderer,
-    brushes: &Vec<Brush>,
-) -> HashMap<Material, VertexData> {
-    let mut data: HashMap<String, (Vec<f32>, Vec<f32>, Vec<f32>, Vec<f32>)> = HashMap::new(); // Positions, Normals, UVs, Alphas
-    for brush in brushes {
-        for (info, face) in &brush.shape.faces {
-            let info = info
-                .as_ref()
-                .expect("Invalid Brush: Face not clipped (Brush may be too big)");
-            if !data.contains_key(&info.material) {
-                data.insert(info.material.clone(), (vec![], vec![], vec![], vec![]));
+    brushes: &[&Brush],
+    walkable_view: bool,
+) -> (HashMap<Material, VertexData>, Vec<VertexData>) {
+    let mut data: HashMap<String, Mesh> = HashMap::new();
+    let mut walkable_data: [Mesh; 3] = Default::default();
+    for (brush, meshes) in brushes.iter().zip(mesh::get_meshes(brushes)) {
+        let meshes = match meshes {
+            Ok(meshes) => meshes,
+            Err(e) => {
+                eprintln!("Skipped drawing solid {}: {e}", brush.id);
+                continue;
             }
-            let material_data = data
-                .get_mut(&info.material)
-                .expect("Has been inserted before");
-            if !brush.has_displacement() {
-                let brush = &brush.shape;
-                let normal = glm::normalize(&glm::cross(
-                    &(brush.vertices[face[1]] - brush.vertices[face[0]]),
-                    &(brush.vertices[face[2]] - brush.vertices[face[0]]),
-                ));
-                for i in 2..face.len() {
-                    material_data
-                        .0
-                        .extend_from_slice(glm::value_ptr(&brush.vertices[face[0]]));
-                    material_data
-                        .0
-                        .extend_from_slice(glm::value_ptr(&brush.vertices[face[i - 1]]));
-                    material_data
-                        .0
-                        .extend_from_slice(glm::value_ptr(&brush.vertices[face[i]]));
-                    material_data.1.extend_from_slice(glm::value_ptr(&normal));
-                    material_data.1.extend_from_slice(glm::value_ptr(&normal));
-                    material_data.1.extend_from_slice(glm::value_ptr(&normal));
-                    material_data
-                        .2
-                        .extend_from_slice(glm::value_ptr(&get_uv_point(
-                            info,
-                            &brush.vertices[face[0]],
-                        )));
-                    material_data
-                        .2
-                        .extend_from_slice(glm::value_ptr(&get_uv_point(
-                            info,
-                            &brush.vertices[face[i - 1]],
-                        )));
-                    material_data
-                        .2
-                        .extend_from_slice(glm::value_ptr(&get_uv_point(
-                            info,
-                            &brush.vertices[face[i]],
-                        )));
-                    material_data.3.push(0.0);
-                    material_data.3.push(0.0);
-                    material_data.3.push(0.0);
-                }
-            } else if let Some(dispinfo) = &info.dispinfo {
-                /* This code is a mess
-                s=start_point r=row_point o=opposite_point c=column_point
-                left->right = column
-                down->up = row
-
-                r---o
-                |\  |
-                | \ |
-                |  \|
-                s---c
-
-                */
-                let brush = &brush.shape;
-                let start_point = face
-                    .iter()
-                    .position(|x| (brush.vertices[*x] - dispinfo.startpos).norm_squared() < 1.0)
-                    .expect(&format!(
-                        "A valid displacement should have this {}",
-                        info.id
-                    ));
-
-                let column_point = brush.vertices[face[(start_point + 1) % face.len()]];
-                let opposite_point = brush.vertices[face[(start_point + 2) % face.len()]];
-                let row_point = brush.vertices[face[(start_point + 3) % face.len()]];
-                let start_point = brush.vertices[face[start_point]];
-
-                for row in 0..(1 << dispinfo.power) {
-                    for column in 0..(1 << dispinfo.power) {
-                        let n1 = dispinfo.normals[row][column];
-                        let p1 = get_disp_point(
-                            row,
-                            column,
-                            dispinfo.power,
-                            &start_point,
-                            &row_point,
-                            &opposite_point,
-                            &column_point,
-                        ) + dispinfo.offsets[row][column]
-                            + n1 * (dispinfo.distances[row][column] + dispinfo.elevation);
-                        let n2 = dispinfo.normals[row + 1][column];
-                        let p2 = get_disp_point(
-                            row + 1,
-                            column,
-                            dispinfo.power,
-                            &start_point,
-                            &row_point,
-                            &opposite_point,
-                            &column_point,
-                        ) + dispinfo.offsets[row + 1][column]
-                            + n2 * (dispinfo.distances[row + 1][column] + dispinfo.elevation);
-                        let n3 = dispinfo.normals[row + 1][column + 1];
-                        let p3 = get_disp_point(
-                            row + 1,
-                            column + 1,
-                            dispinfo.power,
-                            &start_point,
-                            &row_point,
-                            &opposite_point,
-                            &column_point,
-                        ) + dispinfo.offsets[row + 1][column + 1]
-                            + n3 * (dispinfo.distances[row + 1][column + 1] + dispinfo.elevation);
-                        let n4 = dispinfo.normals[row][column + 1];
-                        let p4 = get_disp_point(
-                            row,
-                            column + 1,
-                            dispinfo.power,
-                            &start_point,
-                            &row_point,
-                            &opposite_point,
-                            &column_point,
-                        ) + dispinfo.offsets[row][column + 1]
-                            + n4 * (dispinfo.distances[row][column + 1] + dispinfo.elevation);
-                        // println!("{p1} {p2} {p3} {p4}");
-                        material_data.0.extend_from_slice(glm::value_ptr(&p1));
-                        material_data.0.extend_from_slice(glm::value_ptr(&p4));
-                        material_data.0.extend_from_slice(glm::value_ptr(&p2));
-                        material_data.0.extend_from_slice(glm::value_ptr(&p2));
-                        material_data.0.extend_from_slice(glm::value_ptr(&p4));
-                        material_data.0.extend_from_slice(glm::value_ptr(&p3));
-                        material_data.1.extend_from_slice(glm::value_ptr(&n1));
-                        material_data.1.extend_from_slice(glm::value_ptr(&n4));
-                        material_data.1.extend_from_slice(glm::value_ptr(&n2));
-                        material_data.1.extend_from_slice(glm::value_ptr(&n2));
-                        material_data.1.extend_from_slice(glm::value_ptr(&n4));
-                        material_data.1.extend_from_slice(glm::value_ptr(&n3));
-                        material_data
-                            .2
-                            .extend_from_slice(glm::value_ptr(&get_uv_point(info, &p1)));
-                        material_data
-                            .2
-                            .extend_from_slice(glm::value_ptr(&get_uv_point(info, &p4)));
-                        material_data
-                            .2
-                            .extend_from_slice(glm::value_ptr(&get_uv_point(info, &p2)));
-                        material_data
-                            .2
-                            .extend_from_slice(glm::value_ptr(&get_uv_point(info, &p2)));
-                        material_data
-                            .2
-                            .extend_from_slice(glm::value_ptr(&get_uv_point(info, &p4)));
-                        material_data
-                            .2
-                            .extend_from_slice(glm::value_ptr(&get_uv_point(info, &p3)));
-                        material_data
-                            .3
-                            .push(dispinfo.alphas[row][column] as f32 / 255.0);
-                        material_data
-                            .3
-                            .push(dispinfo.alphas[row][column + 1] as f32 / 255.0);
-                        material_data
-                            .3
-                            .push(dispinfo.alphas[row + 1][column] as f32 / 255.0);
-                        material_data
-                            .3
-                            .push(dispinfo.alphas[row + 1][column] as f32 / 255.0);
-                        material_data
-                            .3
-                            .push(dispinfo.alphas[row][column + 1] as f32 / 255.0);
-                        material_data
-                            .3
-                            .push(dispinfo.alphas[row + 1][column + 1] as f32 / 255.0);
-                    }
-                }
+        };
+        for (info, mut mesh) in meshes {
+            let material_data = data.entry(info.material.clone()).or_default();
+            if !walkable_view || info.dispinfo.is_none() {
+                material_data.append(&mut mesh);
+                continue;
+            }
+            for triangle in 0..mesh.triangle_count() {
+                let tag = mesh.triangle_tags[triangle].unwrap_or_default();
+                let normal = mesh.get_triangle_normal(triangle);
+                let walkability = match (tag.is_walkable(&normal), tag.is_buildable(&normal)) {
+                    (false, _) => Walkability::NotWalkable,
+                    (true, false) => Walkability::Walkable,
+                    (true, true) => Walkability::Buildable,
+                };
+                walkable_data[walkability as usize].push_triangle_from(&mesh, triangle);
             }
         }
     }
-    let mut missing_material_positions = vec![];
-    let mut missing_material_normals = vec![];
-    let mut missing_material_uvs = vec![];
-    let mut missing_material_alphas = vec![];
+    let mut missing_material = Mesh::default();
     let mut renderer_data = HashMap::new();
-    for (material, (mut positions, mut normals, mut uvs, mut alphas)) in data {
+    for (material, mut mesh) in data {
         if let Some(material) = Material::parse(gameinfo, &material) {
-            let mut vertex_data = VertexData::create(renderer).unwrap();
-            vertex_data
-                .add_data(&positions, renderer::VertexSize::VEC3, 0)
-                .unwrap();
-            vertex_data
-                .add_data(&normals, renderer::VertexSize::VEC3, 1)
-                .unwrap();
-            vertex_data
-                .add_data(&uvs, renderer::VertexSize::VEC2, 2)
-                .unwrap();
-            vertex_data
-                .add_data(&alphas, renderer::VertexSize::VEC1, 3)
-                .unwrap();
-            renderer_data.insert(material, vertex_data);
+            renderer_data.insert(material, create_vertex_data(renderer, &mesh));
         } else {
             eprintln!("material {material} not found");
-            missing_material_positions.append(&mut positions);
-            missing_material_normals.append(&mut normals);
-            missing_material_uvs.append(&mut uvs);
-            missing_material_alphas.append(&mut alphas);
+            missing_material.append(&mut mesh);
         }
     }
+    renderer_data.insert(
+        Material::MissingMaterial,
+        create_vertex_data(renderer, &missing_material),
+    );
+
+    let walkable_data = walkable_data
+        .iter()
+        .map(|data| create_vertex_data(renderer, data))
+        .collect();
+    (renderer_data, walkable_data)
+}
 
+fn create_vertex_data(renderer: &Renderer, mesh: &Mesh) -> VertexData {
     let mut vertex_data = VertexData::create(renderer).unwrap();
     vertex_data
-        .add_data(&missing_material_positions, renderer::VertexSize::VEC3, 0)
+        .add_data(&mesh.positions, renderer::VertexSize::VEC3, 0)
         .unwrap();
     vertex_data
-        .add_data(&missing_material_normals, renderer::VertexSize::VEC3, 1)
+        .add_data(&mesh.normals, renderer::VertexSize::VEC3, 1)
         .unwrap();
     vertex_data
-        .add_data(&missing_material_uvs, renderer::VertexSize::VEC2, 2)
+        .add_data(&mesh.uvs, renderer::VertexSize::VEC2, 2)
         .unwrap();
     vertex_data
-        .add_data(&missing_material_alphas, renderer::VertexSize::VEC1, 3)
+        .add_data(&mesh.alphas, renderer::VertexSize::VEC1, 3)
         .unwrap();
-    renderer_data.insert(Material::MissingMaterial, vertex_data);
-
-    renderer_data
-}
-
-fn get_uv_point(info: &Face, point: &glm::Vec3) -> glm::Vec2 {
-    glm::vec2(
-        glm::dot(point, &info.uaxis.dir)
-            / glm::dot(&info.uaxis.dir, &info.uaxis.dir)
-            / info.uaxis.scaling
-            + info.uaxis.translation,
-        glm::dot(point, &info.vaxis.dir)
-            / glm::dot(&info.vaxis.dir, &info.vaxis.dir)
-            / info.vaxis.scaling
-            + info.vaxis.translation,
-    )
-}
-
-fn get_disp_point(
-    row: usize,
-    column: usize,
-    power: u8,
-    start_point: &glm::Vec3,
-    row_point: &glm::Vec3,
-    opposite_point: &glm::Vec3,
-    column_point: &glm::Vec3,
-) -> glm::Vec3 {
-    let max_i = (1 << power) as f32;
-    //println!("{} {} {}", row as f32 / max_i, row as f32 / max_i, max_i);
-    let p1 = glm::lerp(start_point, row_point, row as f32 / max_i);
-    let p2 = glm::lerp(column_point, opposite_point, row as f32 / max_i);
-    glm::lerp(&p1, &p2, column as f32 / max_i)
+    vertex_data
 }
@@ -1,17 +1,28 @@
-use crate::{gameinfo::Gameinfo, keyvalue::KeyValues};
+use serde::{Deserialize, Deserializer};
 
-#[derive(Eq, Hash, PartialEq)]
+use crate::{
+    gameinfo::Gameinfo,
+    keyvalue::{de, KeyValues},
+};
+
+/// The shader is the only key of a material file, so it's used as the variant
+#[derive(Deserialize, Eq, Hash, PartialEq)]
 pub enum Material {
     LightmappedGeneric {
+        #[serde(rename = "$basetexture", deserialize_with = "lowercase")]
         basetexture: String,
     },
     UnlitGeneric {
+        #[serde(rename = "$basetexture", deserialize_with = "lowercase")]
         basetexture: String,
     },
     WorldVertexTransition {
+        #[serde(rename = "$basetexture", deserialize_with = "lowercase")]
         basetexture: String,
+        #[serde(rename = "$basetexture2", deserialize_with = "lowercase")]
         basetexture2: String,
     },
+    #[serde(skip)]
     MissingMaterial,
 }
 
@@ -19,26 +30,10 @@ impl Material {
     pub fn parse(gameinfo: &Gameinfo, name: &String) -> Option<Self> {
         let kv = KeyValues::parse_from_searchpath(gameinfo, &format!("materials/{}", name), "vmt")
             .ok()?;
-        let (shader, properties) = kv.get_all_kv_pairs()[0]; // A material file shouldn't be empty
-        match shader.to_lowercase().as_str() {
-            "lightmappedgeneric" => {
-                let basetexture = properties.get("$basetexture")?.get_value()?.to_lowercase();
-                Some(Self::LightmappedGeneric { basetexture })
-            }
-            "unlitgeneric" => {
-                let basetexture = properties.get("$basetexture")?.get_value()?.to_lowercase();
-                Some(Self::UnlitGeneric { basetexture })
-            }
-            "worldvertextransition" => {
-                let basetexture = properties.get("$basetexture")?.get_value()?.to_lowercase();
-                let basetexture2 = properties.get("$basetexture2")?.get_value()?.to_lowercase();
-                Some(Self::WorldVertexTransition {
-                    basetexture,
-                    basetexture2,
-                })
-            }
-            x => {
-                eprintln!("Unknown shader {} in {}", x, name);
+        match de::from_keyvalues(&kv) {
+            Ok(material) => Some(material),
+            Err(err) => {
+                eprintln!("Couldn't load material {}: {}", name, err);
                 None
             }
         }
@@ -56,6 +51,12 @@ impl Material {
         }
     }
 
+    /// The size of the base texture in pixels, None if its VTF can't be found
+    pub fn get_texture_size(&self, gameinfo: &Gameinfo) -> Option<(u32, u32)> {
+        let texture = self.get_all_textures().into_iter().next()?;
+        get_vtf_size(&gameinfo.get_file(&format!("materials/{}", texture), "vtf")?)
+    }
+
     pub fn is_tool(&self) -> bool {
         match self {
             Material::LightmappedGeneric { basetexture } => {
@@ -75,3 +76,57 @@ impl Material {
         }
     }
 }
+
+/// Reads the width and height from the header of a VTF file, without loading the image
+pub fn get_vtf_size(data: &[u8]) -> Option<(u32, u32)> {
+    if data.get(0..4)? != b"VTF\0" {
+        return None;
+    }
+    let width = u16::from_le_bytes(data.get(16..18)?.try_into().ok()?);
+    let height = u16::from_le_bytes(data.get(18..20)?.try_into().ok()?);
+    Some((width as u32, height as u32))
+}
+
+fn lowercase<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(String::deserialize(deserializer)?.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyvalue::{Conditions, KeyValuesError, Location};
+
+    fn parse(content: &str) -> Result<Material, de::Error> {
+        let kv = KeyValues::parse_internal(content, "test.vmt", &Conditions::default(), &|x| {
+            Err(KeyValuesError::InvalidFile(x, Location::default()))
+        })
+        .unwrap();
+        de::from_keyvalues(&kv)
+    }
+
+    #[test]
+    fn test_parse_material() {
+        let material = parse(
+            "worldvertextransition\n{\n\t$BaseTexture \"Nature/Grass\"\n\t$basetexture2 nature/dirt\n\t$surfaceprop dirt\n}",
+        );
+        assert!(
+            material
+                == Ok(Material::WorldVertexTransition {
+                    basetexture: "nature/grass".to_string(),
+                    basetexture2: "nature/dirt".to_string()
+                })
+        );
+        assert!(parse("VertexLitGeneric { $basetexture a }").is_err());
+        assert!(parse("UnlitGeneric { $color \"[1 1 1]\" }").is_err());
+    }
+
+    #[test]
+    fn test_vtf_size() {
+        let mut header = b"VTF\0".to_vec();
+        header.extend([7, 0, 0, 0, 2, 0, 0, 0, 80, 0, 0, 0]);
+        header.extend([0, 2, 128, 0]);
+        assert_eq!(get_vtf_size(&header), Some((512, 128)));
+        assert_eq!(get_vtf_size(&header[..18]), None);
+        assert_eq!(get_vtf_size(b"VTX\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0"), None);
+    }
+}
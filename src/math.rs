@@ -1,5 +1,3 @@
-use std::collections::{HashMap, VecDeque};
-
 use nalgebra_glm as glm;
 
 pub fn line_plane_intersection(
@@ -31,142 +29,394 @@ pub fn line_segment_plane_intersection(
     line_plane_intersection(line.0, &(line.1 - line.0), plane_point, plane_normal)
 }
 
-pub fn clip_polyhedron_to_plane<T: Clone>(
-    polyhedron: &mut Polyhedron<T>,
-    plane_point: &glm::Vec3,
-    plane_normal: &glm::Vec3,
-    new_face_info: T,
-) {
-    let mut new_faces: Vec<(T, Vec<usize>)> = vec![];
-    let mut new_face_edges = HashMap::new();
-    for (info, face) in &polyhedron.faces {
-        let mut sides = vec![];
-        for vertex in face {
-            sides.push(f32::signum(glm::dot(
-                &(polyhedron.vertices[*vertex] - plane_point),
-                plane_normal,
-            )));
-        }
-        if !sides.contains(&-1.0) {
-            // All in front of plane, can be ignored
-            continue;
-        } else if !sides.contains(&1.0) {
-            // All behind plane, can just be kept
-            new_faces.push((info.clone(), face.clone()));
-            continue;
-        }
-        let mut iter = sides.iter().enumerate().cycle();
-        let mut inside_vertices = VecDeque::new();
-        let mut last = iter
-            .next()
-            .expect("Invalid polyhedron: Face with no vertices");
-        let mut started = false;
-        for (i, side) in iter {
-            if !started {
-                if last.1 == &1.0 && side == &-1.0 {
-                    inside_vertices.push_back(face[last.0]);
-                    inside_vertices.push_back(face[i]);
-                    started = true;
-                }
-                last = (i, side);
-            } else {
-                inside_vertices.push_back(face[i]);
-                if side == &1.0 {
-                    break;
-                }
-            }
+/// A plane made of all points p where dot(normal, p) == distance
+/// For brush sides the normal points out of the brush
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plane {
+    pub normal: glm::DVec3,
+    pub distance: f64,
+}
+
+impl Plane {
+    /// Makes the plane through three points, ordered clockwise when looking at the front like in the VMF
+    /// Returns None if the points are on a line
+    pub fn from_points(a: &glm::DVec3, b: &glm::DVec3, c: &glm::DVec3) -> Option<Self> {
+        let normal = glm::cross(&(c - a), &(b - a));
+        let length = normal.norm();
+        if !length.is_normal() {
+            return None;
         }
-        let new_vertex_one = line_segment_plane_intersection(
-            (
-                &polyhedron.vertices[inside_vertices
-                    .pop_front()
-                    .expect("There needs to be at least two intersections")],
-                &polyhedron.vertices[*inside_vertices
-                    .front()
-                    .expect("There needs to be at least two intersections")],
-            ),
-            plane_point,
-            plane_normal,
-        )
-        .expect("The sides have been checked before, must intersect");
-        let new_vertex_one = match polyhedron
-            .vertices
+        let normal = normal / length;
+        Some(Self {
+            normal,
+            distance: glm::dot(&normal, a),
+        })
+    }
+
+    /// Positive in front of the plane, negative behind it
+    pub fn signed_distance(&self, point: &glm::DVec3) -> f64 {
+        glm::dot(&self.normal, point) - self.distance
+    }
+
+    /// The same plane facing the other way
+    pub fn flipped(&self) -> Self {
+        Self {
+            normal: -self.normal,
+            distance: -self.distance,
+        }
+    }
+
+    /// Whether both planes are in the same place and face the same way
+    pub fn is_coplanar(&self, other: &Plane, epsilons: &Epsilons) -> bool {
+        (self.normal - other.normal).norm() <= epsilons.coplanar_normal
+            && (self.distance - other.distance).abs() <= epsilons.on_plane
+    }
+
+    /// Three points on the plane close to near, in the order from_points takes them
+    pub fn get_points(&self, near: &glm::DVec3) -> [glm::DVec3; 3] {
+        let (u, v) = self.get_axes();
+        let center = near - self.normal * self.signed_distance(near);
+        [center, center + v * 64.0, center + u * 64.0]
+    }
+
+    /// Two directions along the plane, with cross(u, v) == normal
+    fn get_axes(&self) -> (glm::DVec3, glm::DVec3) {
+        // Pick the axis furthest away from the normal, so the cross products are well defined
+        let axis = self.normal.iamin();
+        let mut up = glm::DVec3::zeros();
+        up[axis] = 1.0;
+        let u = glm::normalize(&glm::cross(&up, &self.normal));
+        (u, glm::cross(&self.normal, &u))
+    }
+
+    /// A square on the plane with its corners counter-clockwise when looking at the front
+    /// Every point inside the ±extent cube that is on the plane is inside the square
+    fn base_winding(&self, extent: f64) -> Vec<glm::DVec3> {
+        let (u, v) = self.get_axes();
+        let (u, v) = (u * extent * 2.0, v * extent * 2.0);
+        let center = self.normal * self.distance;
+        vec![
+            center + u + v,
+            center - u + v,
+            center - u - v,
+            center + u - v,
+        ]
+    }
+}
+
+/// Tolerances used when building a polyhedron out of planes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Epsilons {
+    /// Points closer than this to a plane count as being on it
+    pub on_plane: f64,
+    /// Vertices closer than this to each other become one vertex
+    pub vertex_merge: f64,
+    /// Planes with normals closer than this (and distances closer than on_plane) get merged
+    pub coplanar_normal: f64,
+}
+
+impl Default for Epsilons {
+    fn default() -> Self {
+        Self {
+            on_plane: 0.01,
+            vertex_merge: 0.01,
+            coplanar_normal: 1e-5,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum PolyhedronError {
+    #[error("side {0} has an invalid plane")]
+    InvalidPlane(usize),
+    #[error("the sides don't enclose any volume")]
+    Empty,
+    #[error("the sides don't close the solid or it reaches past ±{0}")]
+    Unbounded(f64),
+}
+
+/// Builds the convex polyhedron behind all the planes, each face gets the info of its plane
+/// Planes that are coplanar with an earlier one get merged into it, planes that don't touch
+/// the polyhedron are left out. Faces are counter-clockwise when looking at them from outside.
+/// Everything has to fit in the cube from -extent to extent on every axis
+pub fn polyhedron_from_planes<T: Clone>(
+    planes: &[(Plane, T)],
+    extent: f64,
+    epsilons: &Epsilons,
+) -> Result<Polyhedron<T>, PolyhedronError> {
+    let mut kept: Vec<usize> = vec![];
+    for (i, (plane, _)) in planes.iter().enumerate() {
+        let valid = plane.normal.iter().all(|x| x.is_finite())
+            && plane.distance.is_finite()
+            && (plane.normal.norm() - 1.0).abs() < 1e-6;
+        if !valid {
+            return Err(PolyhedronError::InvalidPlane(i));
+        }
+        if !kept
             .iter()
-            // .position(|x| x == &new_vertex_one)
-            .position(|x| (x - new_vertex_one).norm_squared() < 0.0001)
+            .any(|&j| plane.is_coplanar(&planes[j].0, epsilons))
         {
-            Some(i) => i,
-            None => {
-                polyhedron.vertices.push(new_vertex_one);
-                polyhedron.vertices.len() - 1
+            kept.push(i);
+        }
+    }
+
+    let mut windings = vec![];
+    for &i in &kept {
+        let plane = &planes[i].0;
+        let mut winding = plane.base_winding(extent);
+        for &j in &kept {
+            if i != j {
+                winding = clip_winding(&winding, &planes[j].0, epsilons.on_plane);
             }
-        };
-        let new_vertex_two = line_segment_plane_intersection(
-            (
-                &polyhedron.vertices[inside_vertices
-                    .pop_back()
-                    .expect("There needs to be at least two intersections")],
-                &polyhedron.vertices[*inside_vertices
-                    .back()
-                    .expect("There needs to be at least two intersections")],
-            ),
-            plane_point,
-            plane_normal,
-        )
-        .expect("The sides have been checked before, must intersect");
-        let new_vertex_two = match polyhedron
-            .vertices
-            .iter()
-            .position(|x| (x - new_vertex_two).norm_squared() < 0.0001)
-        {
-            Some(i) => i,
-            None => {
-                polyhedron.vertices.push(new_vertex_two);
-                polyhedron.vertices.len() - 1
+        }
+        if !winding.is_empty() {
+            windings.push((i, winding));
+        }
+    }
+
+    let mut vertices: Vec<glm::DVec3> = vec![];
+    let mut faces = vec![];
+    for (i, winding) in windings {
+        let mut face: Vec<usize> = vec![];
+        for point in winding {
+            if point.iter().any(|x| x.abs() > extent) {
+                return Err(PolyhedronError::Unbounded(extent));
+            }
+            let index = match vertices
+                .iter()
+                .position(|x| (x - point).norm() <= epsilons.vertex_merge)
+            {
+                Some(index) => index,
+                None => {
+                    vertices.push(point);
+                    vertices.len() - 1
+                }
+            };
+            if face.last() != Some(&index) {
+                face.push(index);
             }
-        };
-        if new_vertex_one != new_vertex_two {
-            // If we don't insert, it should still be fine
-            new_face_edges.insert(new_vertex_one, new_vertex_two);
-        }
-        inside_vertices.push_front(new_vertex_one);
-        inside_vertices.push_front(new_vertex_two);
-        new_faces.push((info.clone(), inside_vertices.into()));
-    }
-    if !new_face_edges.is_empty() {
-        let start = *new_face_edges
-            .keys()
-            .next()
-            .expect("Invalid polyhedron: Unconnected vertex");
-        let mut edge_loop = vec![start];
-        let mut current = new_face_edges[&start];
-        while current != start {
-            edge_loop.push(current);
-            current = new_face_edges[&current];
         }
-        assert_eq!(
-            new_face_edges.len(),
-            edge_loop.len(),
-            "{:?}\n{:?}",
-            new_face_edges,
-            edge_loop
-        );
-        new_faces.push((new_face_info, edge_loop));
+        while face.len() > 1 && face.first() == face.last() {
+            face.pop();
+        }
+        if face.len() >= 3 {
+            faces.push((i, face));
+        }
+    }
+
+    // Every face needs a vertex clearly behind it, otherwise the polyhedron is flat
+    let has_volume = faces.len() >= 4
+        && faces.iter().all(|(i, _)| {
+            vertices
+                .iter()
+                .any(|x| planes[*i].0.signed_distance(x) < -epsilons.on_plane)
+        });
+    if !has_volume {
+        return Err(PolyhedronError::Empty);
     }
-    polyhedron.faces = new_faces
+    Ok(Polyhedron {
+        vertices: vertices.iter().map(|x| x.cast()).collect(),
+        faces: faces
+            .into_iter()
+            .map(|(i, face)| (planes[i].1.clone(), face))
+            .collect(),
+    })
 }
 
-#[derive(Debug)]
+/// Cuts away the part of the polygon in front of the plane
+/// Points closer than on_plane to the plane are kept as they are
+fn clip_winding(winding: &[glm::DVec3], plane: &Plane, on_plane: f64) -> Vec<glm::DVec3> {
+    let distances: Vec<f64> = winding.iter().map(|x| plane.signed_distance(x)).collect();
+    if distances.iter().all(|&d| d <= on_plane) {
+        return winding.to_vec();
+    }
+    let mut clipped = vec![];
+    for i in 0..winding.len() {
+        let next = (i + 1) % winding.len();
+        let (d1, d2) = (distances[i], distances[next]);
+        if d1 <= on_plane {
+            clipped.push(winding[i]);
+        }
+        // Only edges going clearly from one side to the other get split
+        if (d1 < -on_plane && d2 > on_plane) || (d1 > on_plane && d2 < -on_plane) {
+            let t = d1 / (d1 - d2);
+            clipped.push(winding[i] + (winding[next] - winding[i]) * t);
+        }
+    }
+    if clipped.len() < 3 {
+        clipped.clear();
+    }
+    clipped
+}
+
+#[derive(Debug, Clone)]
 pub struct Polyhedron<T> {
     pub vertices: Vec<glm::Vec3>,
     pub faces: Vec<(T, Vec<usize>)>,
 }
 
+/// Where a ray or segment first crosses the surface of a polyhedron
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayHit {
+    /// Index into Polyhedron::faces
+    pub face: usize,
+    /// How far along the ray the hit is, in units
+    pub distance: f32,
+    pub point: glm::Vec3,
+}
+
+impl<T> Polyhedron<T> {
+    /// Gets the smallest and largest corner of the bounding box
+    /// Returns None if there are no faces
+    pub fn get_bounds(&self) -> Option<(glm::Vec3, glm::Vec3)> {
+        let mut vertices = self
+            .faces
+            .iter()
+            .flat_map(|(_, face)| face)
+            .map(|&i| self.vertices[i]);
+        let first = vertices.next()?;
+        Some(vertices.fold((first, first), |(mins, maxs), vertex| {
+            (glm::min2(&mins, &vertex), glm::max2(&maxs, &vertex))
+        }))
+    }
+
+    /// The plane through a face, facing the same way as the winding
+    /// None if the face has no area
+    pub fn get_face_plane(&self, face: usize) -> Option<Plane> {
+        let face = &self.faces.get(face)?.1;
+        let points: Vec<glm::DVec3> = face.iter().map(|&i| self.vertices[i].cast()).collect();
+        // Newell's method, so faces that aren't quite flat still get a sensible normal
+        let mut normal = glm::DVec3::zeros();
+        let mut center = glm::DVec3::zeros();
+        for (i, point) in points.iter().enumerate() {
+            normal += glm::cross(point, &points[(i + 1) % points.len()]);
+            center += point;
+        }
+        let length = normal.norm();
+        if !length.is_normal() {
+            return None;
+        }
+        let normal = normal / length;
+        Some(Plane {
+            normal,
+            distance: glm::dot(&normal, &(center / points.len() as f64)),
+        })
+    }
+
+    /// The volume enclosed by the faces, they have to be counter-clockwise seen from outside
+    pub fn get_volume(&self) -> f32 {
+        self.get_volume_and_centroid().0 as f32
+    }
+
+    /// The center of mass, None if there's no volume
+    pub fn get_centroid(&self) -> Option<glm::Vec3> {
+        let (volume, centroid) = self.get_volume_and_centroid();
+        if volume.abs() < 1e-9 {
+            return None;
+        }
+        Some(centroid.cast())
+    }
+
+    /// Splits the faces into tetrahedrons with the first vertex, they add up to the whole
+    fn get_volume_and_centroid(&self) -> (f64, glm::DVec3) {
+        let Some(&origin) = self.vertices.first() else {
+            return (0.0, glm::DVec3::zeros());
+        };
+        let origin: glm::DVec3 = origin.cast();
+        let mut volume = 0.0;
+        let mut weighted = glm::DVec3::zeros();
+        for (_, face) in &self.faces {
+            let point = |i: usize| -> glm::DVec3 { self.vertices[face[i]].cast() - origin };
+            for i in 1..face.len().saturating_sub(1) {
+                let (a, b, c) = (point(0), point(i), point(i + 1));
+                let tetrahedron = glm::dot(&a, &glm::cross(&b, &c)) / 6.0;
+                volume += tetrahedron;
+                weighted += (a + b + c) / 4.0 * tetrahedron;
+            }
+        }
+        if volume == 0.0 {
+            return (0.0, origin);
+        }
+        (volume, origin + weighted / volume)
+    }
+
+    /// Whether no vertex is further than epsilon in front of any face
+    pub fn is_convex(&self, epsilon: f32) -> bool {
+        (0..self.faces.len()).all(|face| {
+            self.get_face_plane(face).is_some_and(|plane| {
+                self.vertices
+                    .iter()
+                    .all(|x| plane.signed_distance(&x.cast()) <= epsilon as f64)
+            })
+        })
+    }
+
+    /// Whether the point is inside or at most epsilon outside, only works for convex polyhedrons
+    pub fn contains_point(&self, point: &glm::Vec3, epsilon: f32) -> bool {
+        !self.faces.is_empty()
+            && (0..self.faces.len()).all(|face| {
+                self.get_face_plane(face)
+                    .is_some_and(|plane| plane.signed_distance(&point.cast()) <= epsilon as f64)
+            })
+    }
+
+    /// The first face the ray crosses, the one it leaves through if it starts inside
+    /// Only works for convex polyhedrons
+    pub fn ray_intersection(&self, origin: &glm::Vec3, direction: &glm::Vec3) -> Option<RayHit> {
+        let origin: glm::DVec3 = origin.cast();
+        let direction = glm::normalize(&direction.cast::<f64>());
+        if !direction.iter().all(|x| x.is_finite()) {
+            return None;
+        }
+        let (mut enter, mut exit) = ((f64::NEG_INFINITY, None), (f64::INFINITY, None));
+        for face in 0..self.faces.len() {
+            let Some(plane) = self.get_face_plane(face) else {
+                continue;
+            };
+            let distance = plane.signed_distance(&origin);
+            let speed = glm::dot(&plane.normal, &direction);
+            if speed == 0.0 {
+                if distance > 0.0 {
+                    return None;
+                }
+                continue;
+            }
+            let t = -distance / speed;
+            if speed < 0.0 && t > enter.0 {
+                enter = (t, Some(face));
+            } else if speed > 0.0 && t < exit.0 {
+                exit = (t, Some(face));
+            }
+        }
+        if enter.0 > exit.0 {
+            return None;
+        }
+        let (t, face) = if enter.0 >= 0.0 { enter } else { exit };
+        if t < 0.0 {
+            return None;
+        }
+        Some(RayHit {
+            face: face?,
+            distance: t as f32,
+            point: (origin + direction * t).cast(),
+        })
+    }
+
+    /// Like ray_intersection, but only hits between start and end count
+    pub fn segment_intersection(&self, start: &glm::Vec3, end: &glm::Vec3) -> Option<RayHit> {
+        self.ray_intersection(start, &(end - start))
+            .filter(|hit| hit.distance <= glm::distance(start, end))
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+
     use glm::vec3;
 
     use super::*;
+    use crate::test_utils::Rng;
 
     #[test]
     fn test_line_plane_intersection() {
@@ -185,7 +435,314 @@ mod tests {
         );
     }
 
-    // fn test_clip_polyhedron_to_plane() {
+    /// A box turned by a random rotation, so none of its planes are on the grid
+    fn random_box(rng: &mut Rng, center: &glm::DVec3) -> Vec<Plane> {
+        let rotation = glm::rotation(rng.range(0.0, 6.3), &rng.direction());
+        let mut planes = vec![];
+        for axis in 0..3 {
+            for sign in [-1.0, 1.0] {
+                let mut normal = glm::DVec3::zeros();
+                normal[axis] = sign;
+                let normal = (rotation * normal.push(0.0)).xyz();
+                planes.push(Plane {
+                    normal,
+                    distance: glm::dot(&normal, center) + rng.range(8.0, 300.0),
+                });
+            }
+        }
+        planes
+    }
+
+    /// Checks the polyhedron is closed, convex and matches the planes it was built from
+    fn check_polyhedron(polyhedron: &Polyhedron<Plane>, planes: &[Plane], inside: &glm::DVec3) {
+        let epsilon = 0.02;
+        let vertices: Vec<glm::DVec3> = polyhedron.vertices.iter().map(|x| x.cast()).collect();
+        let mut edges = HashSet::new();
+        for (plane, face) in &polyhedron.faces {
+            assert!(face.len() >= 3);
+            for (i, &vertex) in face.iter().enumerate() {
+                assert!(plane.signed_distance(&vertices[vertex]).abs() < epsilon);
+                // Every edge shows up once in each direction
+                assert!(edges.insert((vertex, face[(i + 1) % face.len()])));
+            }
+            let winding_normal =
+                face.iter()
+                    .enumerate()
+                    .fold(glm::DVec3::zeros(), |acc, (i, &a)| {
+                        acc + glm::cross(&vertices[a], &vertices[face[(i + 1) % face.len()]])
+                    });
+            assert!(glm::dot(&winding_normal, &plane.normal) > 0.0);
+        }
+        for (a, b) in &edges {
+            assert!(edges.contains(&(*b, *a)));
+        }
+        assert_eq!(
+            vertices.len() + polyhedron.faces.len(),
+            edges.len() / 2 + 2,
+            "Euler characteristic"
+        );
+        for plane in planes {
+            assert!(plane.signed_distance(inside) < 0.0);
+            for vertex in &vertices {
+                assert!(plane.signed_distance(vertex) < epsilon);
+            }
+        }
+    }
+
+    fn with_info(planes: &[Plane]) -> Vec<(Plane, Plane)> {
+        planes.iter().map(|x| (*x, *x)).collect()
+    }
+
+    #[test]
+    fn test_plane_from_points() {
+        let plane = Plane::from_points(
+            &glm::vec3(-64.0, 64.0, 64.0),
+            &glm::vec3(64.0, 64.0, 64.0),
+            &glm::vec3(64.0, -64.0, 64.0),
+        )
+        .unwrap();
+        assert_eq!(plane.normal, glm::vec3(0.0, 0.0, 1.0));
+        assert_eq!(plane.distance, 64.0);
+        assert_eq!(plane.signed_distance(&glm::vec3(5.0, 3.0, 0.0)), -64.0);
+        let point = glm::vec3(1.0, 2.0, 3.0);
+        assert_eq!(
+            Plane::from_points(&point, &(point * 2.0), &(point * 3.0)),
+            None
+        );
+    }
+
+    fn box_polyhedron(mins: glm::DVec3, maxs: glm::DVec3) -> Polyhedron<Plane> {
+        let mut planes = vec![];
+        for axis in 0..3 {
+            let mut normal = glm::DVec3::zeros();
+            normal[axis] = 1.0;
+            planes.push(Plane {
+                normal,
+                distance: maxs[axis],
+            });
+            planes.push(Plane {
+                normal: -normal,
+                distance: -mins[axis],
+            });
+        }
+        polyhedron_from_planes(&with_info(&planes), 16384.0, &Epsilons::default()).unwrap()
+    }
 
-    // }
+    #[test]
+    fn test_polyhedron_queries() {
+        let polyhedron = box_polyhedron(glm::vec3(-64.0, 0.0, 16.0), glm::vec3(64.0, 32.0, 80.0));
+        assert_eq!(
+            polyhedron.get_bounds(),
+            Some((vec3(-64.0, 0.0, 16.0), vec3(64.0, 32.0, 80.0)))
+        );
+        assert_eq!(polyhedron.get_volume(), 128.0 * 32.0 * 64.0);
+        assert!((polyhedron.get_centroid().unwrap() - vec3(0.0, 16.0, 48.0)).norm() < 1e-4);
+        assert!(polyhedron.is_convex(0.01));
+        for (i, (plane, _)) in polyhedron.faces.iter().enumerate() {
+            let face_plane = polyhedron.get_face_plane(i).unwrap();
+            assert!((face_plane.normal - plane.normal).norm() < 1e-9);
+            assert!((face_plane.distance - plane.distance).abs() < 1e-6);
+        }
+
+        assert!(polyhedron.contains_point(&vec3(0.0, 16.0, 48.0), 0.0));
+        assert!(polyhedron.contains_point(&vec3(64.0, 32.0, 80.0), 0.01));
+        assert!(!polyhedron.contains_point(&vec3(64.5, 16.0, 48.0), 0.01));
+
+        // From outside it hits the side facing it
+        let hit = polyhedron
+            .ray_intersection(&vec3(-200.0, 16.0, 48.0), &vec3(2.0, 0.0, 0.0))
+            .unwrap();
+        assert_eq!(
+            polyhedron.faces[hit.face].0.normal,
+            glm::vec3(-1.0, 0.0, 0.0)
+        );
+        assert_eq!(hit.distance, 136.0);
+        assert_eq!(hit.point, vec3(-64.0, 16.0, 48.0));
+        // From inside it leaves through the top
+        let hit = polyhedron
+            .ray_intersection(&vec3(0.0, 16.0, 48.0), &vec3(0.0, 0.0, 1.0))
+            .unwrap();
+        assert_eq!(
+            polyhedron.faces[hit.face].0.normal,
+            glm::vec3(0.0, 0.0, 1.0)
+        );
+        assert_eq!(hit.distance, 32.0);
+        assert_eq!(
+            polyhedron.ray_intersection(&vec3(-200.0, 16.0, 48.0), &vec3(-1.0, 0.0, 0.0)),
+            None
+        );
+        assert_eq!(
+            polyhedron.ray_intersection(&vec3(-200.0, 16.0, 48.0), &vec3(1.0, 1.0, 0.0)),
+            None
+        );
+        assert!(polyhedron
+            .segment_intersection(&vec3(0.0, -10.0, 48.0), &vec3(0.0, 10.0, 48.0))
+            .is_some());
+        assert_eq!(
+            polyhedron.segment_intersection(&vec3(0.0, -10.0, 48.0), &vec3(0.0, -1.0, 48.0)),
+            None
+        );
+
+        // Pushing a corner in makes a dent
+        let mut dented = polyhedron.clone();
+        let corner = dented
+            .vertices
+            .iter()
+            .position(|x| x == &vec3(64.0, 32.0, 80.0))
+            .unwrap();
+        dented.vertices[corner] = vec3(32.0, 16.0, 48.0);
+        assert!(!dented.is_convex(0.01));
+        assert!(dented.get_volume() < polyhedron.get_volume());
+    }
+
+    #[test]
+    fn test_random_polyhedron_queries() {
+        let mut rng = Rng::new(7);
+        for _ in 0..100 {
+            let center = glm::vec3(rng.range(-100.0, 100.0), 0.0, rng.range(-100.0, 100.0));
+            let planes = random_box(&mut rng, &center);
+            let polyhedron =
+                polyhedron_from_planes(&with_info(&planes), 16384.0, &Epsilons::default()).unwrap();
+            // The opposite sides of the box are the distance sums apart
+            let sizes: Vec<f64> = (0..3)
+                .map(|axis| {
+                    planes[axis * 2].distance + planes[axis * 2 + 1].distance
+                        - glm::dot(
+                            &(planes[axis * 2].normal + planes[axis * 2 + 1].normal),
+                            &center,
+                        )
+                })
+                .collect();
+            let volume = sizes.iter().product::<f64>() as f32;
+            assert!((polyhedron.get_volume() - volume).abs() / volume < 1e-4);
+            assert!(polyhedron.is_convex(0.01));
+            let centroid = polyhedron.get_centroid().unwrap();
+            assert!(polyhedron.contains_point(&centroid, 0.0));
+            let (mins, maxs) = polyhedron.get_bounds().unwrap();
+            assert!(centroid > mins && centroid < maxs);
+            // Any ray from the centroid leaves through a face, and coming back hits the same one
+            let direction: glm::Vec3 = rng.direction().cast();
+            let hit = polyhedron.ray_intersection(&centroid, &direction).unwrap();
+            let outside = hit.point + direction * 10.0;
+            let back = polyhedron.ray_intersection(&outside, &-direction).unwrap();
+            assert_eq!(back.face, hit.face);
+            assert!((back.distance - 10.0).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn test_box_polyhedron() {
+        let mut planes: Vec<Plane> = (0..6)
+            .map(|i| {
+                let mut normal = glm::DVec3::zeros();
+                normal[i / 2] = if i % 2 == 0 { -1.0 } else { 1.0 };
+                Plane {
+                    normal,
+                    distance: [8.0, 16.0, 32.0][i / 2],
+                }
+            })
+            .collect();
+        // The same plane again slightly off gets merged
+        let mut extra = planes[0];
+        extra.distance += 0.001;
+        planes.push(extra);
+        let polyhedron =
+            polyhedron_from_planes(&with_info(&planes), 16384.0, &Epsilons::default()).unwrap();
+        assert_eq!(polyhedron.vertices.len(), 8);
+        assert_eq!(polyhedron.faces.len(), 6);
+        check_polyhedron(&polyhedron, &planes, &glm::vec3(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_broken_polyhedrons() {
+        let epsilons = Epsilons::default();
+        let mut planes = random_box(&mut Rng::new(2), &glm::vec3(0.0, 0.0, 0.0));
+        assert_eq!(
+            polyhedron_from_planes(&with_info(&planes[1..]), 16384.0, &epsilons).unwrap_err(),
+            PolyhedronError::Unbounded(16384.0)
+        );
+        // Flipping a side makes it face away, leaving a slab of no volume or nothing at all
+        planes[0] = planes[1].flipped();
+        assert_eq!(
+            polyhedron_from_planes(&with_info(&planes), 16384.0, &epsilons).unwrap_err(),
+            PolyhedronError::Empty
+        );
+        planes[0].distance -= 1.0;
+        assert_eq!(
+            polyhedron_from_planes(&with_info(&planes), 16384.0, &epsilons).unwrap_err(),
+            PolyhedronError::Empty
+        );
+        planes[0].normal = glm::vec3(f64::NAN, 0.0, 0.0);
+        assert_eq!(
+            polyhedron_from_planes(&with_info(&planes), 16384.0, &epsilons).unwrap_err(),
+            PolyhedronError::InvalidPlane(0)
+        );
+    }
+
+    #[test]
+    fn test_random_polyhedrons() {
+        let mut rng = Rng::new(3);
+        let epsilons = Epsilons::default();
+        for _ in 0..500 {
+            let center = glm::vec3(
+                rng.range(-8000.0, 8000.0),
+                rng.range(-8000.0, 8000.0),
+                rng.range(-8000.0, 8000.0),
+            );
+            let mut planes = random_box(&mut rng, &center);
+            // Cut off random parts, some of them missing the box
+            for _ in 0..rng.range(0.0, 12.0) as usize {
+                let normal = rng.direction();
+                planes.push(Plane {
+                    normal,
+                    distance: glm::dot(&normal, &center) + rng.range(4.0, 400.0),
+                });
+            }
+            let polyhedron = polyhedron_from_planes(&with_info(&planes), 16384.0, &epsilons);
+            check_polyhedron(&polyhedron.unwrap(), &planes, &center);
+        }
+    }
+
+    #[test]
+    fn test_near_degenerate_polyhedrons() {
+        let mut rng = Rng::new(1);
+        let epsilons = Epsilons::default();
+        for _ in 0..500 {
+            let center = glm::vec3(rng.range(-100.0, 100.0), 0.0, rng.range(-100.0, 100.0));
+            let mut planes = random_box(&mut rng, &center);
+            let polyhedron =
+                polyhedron_from_planes(&with_info(&planes), 16384.0, &epsilons).unwrap();
+            // Planes going almost through a corner, almost along an edge and
+            // almost matching a side, like off grid brushes in Hammer end up with
+            let corner: glm::DVec3 = polyhedron.vertices[0].cast();
+            let normal = rng.direction();
+            planes.push(Plane {
+                normal,
+                distance: glm::dot(&normal, &corner) + rng.range(-0.02, 0.02),
+            });
+            let (edge_plane, face) = &polyhedron.faces[0];
+            let a: glm::DVec3 = polyhedron.vertices[face[0]].cast();
+            let b: glm::DVec3 = polyhedron.vertices[face[1]].cast();
+            let normal = glm::normalize(&(edge_plane.normal + rng.direction() * 0.001));
+            planes.push(Plane {
+                normal,
+                distance: glm::dot(&normal, &((a + b) / 2.0)) + rng.range(-0.02, 0.02),
+            });
+            let mut near = planes[rng.range(0.0, 6.0) as usize];
+            near.normal = glm::normalize(&(near.normal + rng.direction() * 1e-6));
+            near.distance += rng.range(-0.005, 0.005);
+            planes.push(near);
+            // Has to come back without panicking, and be valid if it has any volume
+            if let Ok(polyhedron) = polyhedron_from_planes(&with_info(&planes), 16384.0, &epsilons)
+            {
+                for (plane, face) in &polyhedron.faces {
+                    assert!(face.len() >= 3);
+                    for &vertex in face {
+                        let vertex: glm::DVec3 = polyhedron.vertices[vertex].cast();
+                        assert!(plane.signed_distance(&vertex).abs() < 0.05);
+                    }
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,789 @@
+use std::collections::HashMap;
+
+use nalgebra_glm as glm;
+
+use crate::vmf::{Brush, Dispinfo, Face, TriangleTag};
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum MeshError {
+    #[error("displacement on side {id} has {corners} corners instead of 4")]
+    NotFourSided { id: i32, corners: usize },
+}
+
+/// Triangles ready to be uploaded to a renderer, three vertices per triangle
+/// Vertex data is stored flat, e.g. positions holds x, y and z of every vertex
+#[derive(Debug, Default, Clone)]
+pub struct Mesh {
+    pub positions: Vec<f32>,
+    pub normals: Vec<f32>,
+    pub uvs: Vec<f32>,
+    pub alphas: Vec<f32>,
+    /// The id of the side each triangle belongs to, one per triangle
+    pub face_ids: Vec<i32>,
+    /// One per triangle, None for triangles that aren't part of a displacement
+    pub triangle_tags: Vec<Option<TriangleTag>>,
+}
+
+/// A vertex of a mesh: position, normal, uv and alpha
+type Vertex = (glm::Vec3, glm::Vec3, glm::Vec2, f32);
+
+impl Mesh {
+    pub fn triangle_count(&self) -> usize {
+        self.face_ids.len()
+    }
+
+    fn push_triangle(&mut self, vertices: [Vertex; 3], face_id: i32, tag: Option<TriangleTag>) {
+        for (position, normal, uv, alpha) in vertices {
+            self.positions.extend_from_slice(glm::value_ptr(&position));
+            self.normals.extend_from_slice(glm::value_ptr(&normal));
+            self.uvs.extend_from_slice(glm::value_ptr(&uv));
+            self.alphas.push(alpha);
+        }
+        self.face_ids.push(face_id);
+        self.triangle_tags.push(tag);
+    }
+
+    /// Copies one triangle of another mesh to the end of this one
+    pub fn push_triangle_from(&mut self, other: &Mesh, triangle: usize) {
+        let vertices = triangle * 3..triangle * 3 + 3;
+        self.positions
+            .extend_from_slice(&other.positions[vertices.start * 3..vertices.end * 3]);
+        self.normals
+            .extend_from_slice(&other.normals[vertices.start * 3..vertices.end * 3]);
+        self.uvs
+            .extend_from_slice(&other.uvs[vertices.start * 2..vertices.end * 2]);
+        self.alphas.extend_from_slice(&other.alphas[vertices]);
+        self.face_ids.push(other.face_ids[triangle]);
+        self.triangle_tags.push(other.triangle_tags[triangle]);
+    }
+
+    /// Moves all triangles of another mesh to the end of this one
+    pub fn append(&mut self, other: &mut Mesh) {
+        self.positions.append(&mut other.positions);
+        self.normals.append(&mut other.normals);
+        self.uvs.append(&mut other.uvs);
+        self.alphas.append(&mut other.alphas);
+        self.face_ids.append(&mut other.face_ids);
+        self.triangle_tags.append(&mut other.triangle_tags);
+    }
+
+    /// Gets the corners of a triangle
+    pub fn get_triangle(&self, triangle: usize) -> [glm::Vec3; 3] {
+        [0, 1, 2].map(|i| {
+            let start = (triangle * 3 + i) * 3;
+            glm::make_vec3(&self.positions[start..start + 3])
+        })
+    }
+
+    /// Gets the normal of the plane a triangle lies in, pointing to the side it is visible from
+    pub fn get_triangle_normal(&self, triangle: usize) -> glm::Vec3 {
+        let [a, b, c] = self.get_triangle(triangle);
+        glm::normalize(&glm::cross(&(b - a), &(c - a)))
+    }
+}
+
+/// Builds a mesh for every side of the brush that gets drawn
+/// Like in Source, only the displacements of a brush with displacements are drawn
+/// Normals only get smoothed inside the brush, use get_meshes to smooth them across brushes
+pub fn get_brush_meshes(brush: &Brush) -> Result<Vec<(&Face, Mesh)>, MeshError> {
+    get_meshes(&[brush]).remove(0)
+}
+
+/// Builds the meshes of all brushes, with one result per brush
+/// Displacement normals get smoothed across neighbouring displacements, and
+/// sides get smooth normals where they touch a side with a shared smoothing group
+pub fn get_meshes<'a>(brushes: &[&'a Brush]) -> Vec<Result<Vec<(&'a Face, Mesh)>, MeshError>> {
+    let mut results = vec![];
+    // Displacements are turned into meshes once all grids are known, so they get smoothed together
+    let mut grids = vec![];
+    let mut grid_locations = vec![];
+    for brush in brushes {
+        let has_displacement = brush.has_displacement();
+        let mut meshes = vec![];
+        let result = (|| {
+            for (info, face) in &brush.shape.faces {
+                let vertices: Vec<glm::Vec3> =
+                    face.iter().map(|&x| brush.shape.vertices[x]).collect();
+                match &info.dispinfo {
+                    Some(dispinfo) => {
+                        grids.push(get_displacement_grid(&vertices, info, dispinfo)?);
+                        grid_locations.push((results.len(), meshes.len()));
+                        meshes.push((info, Mesh::default()));
+                    }
+                    None if !has_displacement => {
+                        meshes.push((info, get_face_mesh(&vertices, info)))
+                    }
+                    None => {}
+                }
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            // Broken brushes don't get drawn, so their displacements shouldn't affect others
+            grids.truncate(grid_locations.partition_point(|(x, _)| *x < results.len()));
+            grid_locations.truncate(grids.len());
+        }
+        results.push(result.map(|_| meshes));
+    }
+
+    smooth_displacement_normals(&mut grids);
+    for ((brush, face), grid) in grid_locations.into_iter().zip(grids) {
+        if let Ok(meshes) = &mut results[brush] {
+            meshes[face].1 = grid.to_mesh();
+        }
+    }
+
+    let mut flat: Vec<(&Face, &mut Mesh)> = results
+        .iter_mut()
+        .flatten()
+        .flatten()
+        .filter(|(info, _)| info.dispinfo.is_none())
+        .map(|(info, mesh)| (*info, mesh))
+        .collect();
+    apply_smoothing_groups(&mut flat);
+    results
+}
+
+/// Splits the polygon of a side into triangles, all with the normal of the side
+pub fn get_face_mesh(vertices: &[glm::Vec3], info: &Face) -> Mesh {
+    let mut mesh = Mesh::default();
+    if vertices.len() < 3 {
+        return mesh;
+    }
+    let normal = glm::normalize(&glm::cross(
+        &(vertices[1] - vertices[0]),
+        &(vertices[2] - vertices[0]),
+    ));
+    let vertex = |position: &glm::Vec3| (*position, normal, get_uv_point(info, position), 0.0);
+    for i in 2..vertices.len() {
+        mesh.push_triangle(
+            [
+                vertex(&vertices[0]),
+                vertex(&vertices[i - 1]),
+                vertex(&vertices[i]),
+            ],
+            info.id,
+            None,
+        );
+    }
+    mesh
+}
+
+/// Rounds a position, so that positions which are the same up to float errors are equal
+fn position_key(position: &glm::Vec3) -> [i64; 3] {
+    [0, 1, 2].map(|i| (position[i] * 100.0).round() as i64)
+}
+
+/// Gives vertices of sides the average normal of all touching sides that share a smoothing group
+/// Sides without smoothing groups stay flat
+fn apply_smoothing_groups(meshes: &mut [(&Face, &mut Mesh)]) {
+    // Every side adds its normal once for each of its corners
+    let mut normals: HashMap<[i64; 3], Vec<(i32, i32, glm::Vec3)>> = HashMap::new();
+    for (info, mesh) in meshes.iter() {
+        if info.smoothing_groups == 0 {
+            continue;
+        }
+        let normal = info.normal();
+        for vertex in mesh.positions.chunks(3) {
+            let entry = normals
+                .entry(position_key(&glm::make_vec3(vertex)))
+                .or_default();
+            if !entry.iter().any(|(id, ..)| *id == info.id) {
+                entry.push((info.id, info.smoothing_groups, normal));
+            }
+        }
+    }
+    for (info, mesh) in meshes.iter_mut() {
+        if info.smoothing_groups == 0 {
+            continue;
+        }
+        for (position, normal) in mesh.positions.chunks(3).zip(mesh.normals.chunks_mut(3)) {
+            let sum: glm::Vec3 = normals[&position_key(&glm::make_vec3(position))]
+                .iter()
+                .filter(|(_, groups, _)| groups & info.smoothing_groups != 0)
+                .map(|(.., normal)| normal)
+                .sum();
+            normal.copy_from_slice(glm::normalize(&sum).as_slice());
+        }
+    }
+}
+
+/// A displaced grid of vertices, with rows and columns like in Dispinfo
+#[derive(Debug, Clone)]
+pub struct DisplacementGrid {
+    pub face_id: i32,
+    pub power: u8,
+    pub positions: Vec<Vec<glm::Vec3>>,
+    /// The normals from the file at first, smooth_displacement_normals replaces them
+    pub normals: Vec<Vec<glm::Vec3>>,
+    pub uvs: Vec<Vec<glm::Vec2>>,
+    pub alphas: Vec<Vec<f32>>,
+    /// One row for each row of quads, with two triangles per quad
+    pub triangle_tags: Vec<Vec<TriangleTag>>,
+}
+
+impl DisplacementGrid {
+    fn size(&self) -> usize {
+        (1 << self.power) + 1
+    }
+
+    /// The two triangles of the quad at row and column, as (row, column) of their corners
+    fn quad_triangles(row: usize, column: usize) -> [[(usize, usize); 3]; 2] {
+        let v1 = (row, column);
+        let v2 = (row + 1, column);
+        let v3 = (row + 1, column + 1);
+        let v4 = (row, column + 1);
+        [[v1, v4, v2], [v2, v4, v3]]
+    }
+
+    /// Gets the (row, column) of the vertices along an edge, from one corner to the next
+    /// Edges go around the grid: start to column corner, to opposite corner, to row corner and back
+    pub fn get_edge(&self, edge: usize) -> Vec<(usize, usize)> {
+        let last = self.size() - 1;
+        (0..=last)
+            .map(|i| match edge % 4 {
+                0 => (0, i),
+                1 => (i, last),
+                2 => (last, last - i),
+                _ => (last - i, 0),
+            })
+            .collect()
+    }
+
+    /// The first vertex of get_edge, without building the whole edge
+    fn get_corner(&self, corner: usize) -> (usize, usize) {
+        let last = self.size() - 1;
+        [(0, 0), (0, last), (last, last), (last, 0)][corner % 4]
+    }
+
+    fn get_position(&self, (row, column): (usize, usize)) -> glm::Vec3 {
+        self.positions[row][column]
+    }
+
+    pub fn to_mesh(&self) -> Mesh {
+        let mut mesh = Mesh::default();
+        let vertex = |(row, column): (usize, usize)| {
+            (
+                self.positions[row][column],
+                self.normals[row][column],
+                self.uvs[row][column],
+                self.alphas[row][column],
+            )
+        };
+        for row in 0..self.size() - 1 {
+            for column in 0..self.size() - 1 {
+                for (second, triangle) in Self::quad_triangles(row, column).iter().enumerate() {
+                    mesh.push_triangle(
+                        triangle.map(vertex),
+                        self.face_id,
+                        Some(self.triangle_tags[row][column * 2 + second]),
+                    );
+                }
+            }
+        }
+        mesh
+    }
+}
+
+/// Builds the displaced grid of a side
+///
+/// s=start corner r=row corner o=opposite corner c=column corner
+/// left->right = column
+/// down->up = row
+/// ```text
+/// r---o
+/// |\  |
+/// | \ |
+/// |  \|
+/// s---c
+/// ```
+pub fn get_displacement_grid(
+    vertices: &[glm::Vec3],
+    info: &Face,
+    dispinfo: &Dispinfo,
+) -> Result<DisplacementGrid, MeshError> {
+    if vertices.len() != 4 {
+        return Err(MeshError::NotFourSided {
+            id: info.id,
+            corners: vertices.len(),
+        });
+    }
+    let start = find_start_corner(vertices, &dispinfo.startpos);
+    let corners = [0, 1, 2, 3].map(|i| vertices[(start + i) % 4]);
+    let (normals, distances) = dispinfo.get_normalized(&info.normal());
+
+    let size = (1 << dispinfo.power) + 1;
+    let positions: Vec<Vec<glm::Vec3>> = (0..size)
+        .map(|row| {
+            (0..size)
+                .map(|column| {
+                    get_disp_point(row, column, dispinfo.power, &corners)
+                        + dispinfo
+                            .offsets
+                            .as_ref()
+                            .map_or(glm::Vec3::zeros(), |x| x[row][column])
+                        + normals[row][column] * (distances[row][column] + dispinfo.elevation)
+                })
+                .collect()
+        })
+        .collect();
+    Ok(DisplacementGrid {
+        face_id: info.id,
+        power: dispinfo.power,
+        uvs: positions
+            .iter()
+            .map(|row| row.iter().map(|x| get_uv_point(info, x)).collect())
+            .collect(),
+        positions,
+        normals,
+        alphas: dispinfo
+            .alphas
+            .iter()
+            .map(|row| row.iter().map(|alpha| alpha / 255.0).collect())
+            .collect(),
+        triangle_tags: (0..size - 1)
+            .map(|row| {
+                (0..(size - 1) * 2)
+                    .map(|i| dispinfo.get_triangle_tag(row, i / 2, i % 2 == 1))
+                    .collect()
+            })
+            .collect(),
+    })
+}
+
+/// Builds the displaced grid of a side as triangles, with the normals from the file
+pub fn get_displacement_mesh(
+    vertices: &[glm::Vec3],
+    info: &Face,
+    dispinfo: &Dispinfo,
+) -> Result<Mesh, MeshError> {
+    Ok(get_displacement_grid(vertices, info, dispinfo)?.to_mesh())
+}
+
+/// Two displacements that share an edge, Source sews them together so there are no cracks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SewnEdge {
+    /// Indices into the displacement grids
+    pub grids: (usize, usize),
+    /// The edge of each grid, see DisplacementGrid::get_edge
+    pub edges: (usize, usize),
+}
+
+/// Finds all edges that are shared by two displacements, in either direction
+pub fn find_sewn_edges(grids: &[DisplacementGrid]) -> Vec<SewnEdge> {
+    // The endpoints are sorted, so edges going the other way get the same key
+    type Endpoints = ([i64; 3], [i64; 3]);
+    let mut edges: HashMap<Endpoints, Vec<(usize, usize)>> = HashMap::new();
+    for (grid_index, grid) in grids.iter().enumerate() {
+        for edge in 0..4 {
+            let start = position_key(&grid.get_position(grid.get_corner(edge)));
+            let end = position_key(&grid.get_position(grid.get_corner(edge + 1)));
+            edges
+                .entry((start.min(end), start.max(end)))
+                .or_default()
+                .push((grid_index, edge));
+        }
+    }
+    let mut sewn = vec![];
+    for shared in edges.values() {
+        for (i, &(a, edge_a)) in shared.iter().enumerate() {
+            for &(b, edge_b) in &shared[i + 1..] {
+                if a != b {
+                    sewn.push(SewnEdge {
+                        grids: (a, b),
+                        edges: (edge_a, edge_b),
+                    });
+                }
+            }
+        }
+    }
+    sewn.sort_by_key(|x| (x.grids, x.edges));
+    sewn
+}
+
+/// Replaces the normals of the grids with ones worked out from the displaced positions
+/// Vertices on sewn edges and shared corners get the same normal in all grids, so there are no seams
+pub fn smooth_displacement_normals(grids: &mut [DisplacementGrid]) {
+    // Every vertex gets the normals of the triangles around it, bigger triangles count more
+    let mut sums: Vec<Vec<Vec<glm::Vec3>>> = grids
+        .iter()
+        .map(|grid| vec![vec![glm::Vec3::zeros(); grid.size()]; grid.size()])
+        .collect();
+    for (grid, sum) in grids.iter().zip(&mut sums) {
+        for row in 0..grid.size() - 1 {
+            for column in 0..grid.size() - 1 {
+                for triangle in DisplacementGrid::quad_triangles(row, column) {
+                    let [a, b, c] = triangle.map(|x| grid.get_position(x));
+                    let normal = glm::cross(&(b - a), &(c - a));
+                    for (row, column) in triangle {
+                        sum[row][column] += normal;
+                    }
+                }
+            }
+        }
+    }
+
+    // Vertices of different grids that are sewn together share their sums
+    let mut shared: HashMap<(usize, usize, usize), usize> = HashMap::new();
+    let mut groups: Vec<Vec<(usize, usize, usize)>> = vec![];
+    let mut join = |a: (usize, usize, usize), b: (usize, usize, usize)| match (
+        shared.get(&a).copied(),
+        shared.get(&b).copied(),
+    ) {
+        (Some(x), Some(y)) if x != y => {
+            let moved = std::mem::take(&mut groups[y]);
+            for vertex in &moved {
+                shared.insert(*vertex, x);
+            }
+            groups[x].extend(moved);
+        }
+        (Some(_), Some(_)) => {}
+        (Some(x), None) | (None, Some(x)) => {
+            let new = if shared.contains_key(&a) { b } else { a };
+            shared.insert(new, x);
+            groups[x].push(new);
+        }
+        (None, None) => {
+            shared.insert(a, groups.len());
+            shared.insert(b, groups.len());
+            groups.push(vec![a, b]);
+        }
+    };
+    for edge in find_sewn_edges(grids) {
+        let (a, b) = edge.grids;
+        let edge_b = grids[b].get_edge(edge.edges.1);
+        for (row, column) in grids[a].get_edge(edge.edges.0) {
+            let key = position_key(&grids[a].positions[row][column]);
+            // Grids with different powers only share some of the vertices
+            if let Some(&(other_row, other_column)) = edge_b
+                .iter()
+                .find(|&&x| position_key(&grids[b].get_position(x)) == key)
+            {
+                join((a, row, column), (b, other_row, other_column));
+            }
+        }
+    }
+    // Displacements that only touch at a corner
+    let mut corners: HashMap<[i64; 3], Vec<(usize, usize, usize)>> = HashMap::new();
+    for (grid_index, grid) in grids.iter().enumerate() {
+        for corner in 0..4 {
+            let (row, column) = grid.get_corner(corner);
+            corners
+                .entry(position_key(&grid.positions[row][column]))
+                .or_default()
+                .push((grid_index, row, column));
+        }
+    }
+    // Sorted so the normals come out the same every time
+    let mut corners: Vec<_> = corners.into_values().collect();
+    corners.sort();
+    for shared in &corners {
+        for (i, &a) in shared.iter().enumerate() {
+            for &b in &shared[i + 1..] {
+                if a.0 != b.0 {
+                    join(a, b);
+                }
+            }
+        }
+    }
+    for group in &groups {
+        let sum: glm::Vec3 = group
+            .iter()
+            .map(|&(grid, row, column)| sums[grid][row][column])
+            .sum();
+        for &(grid, row, column) in group {
+            sums[grid][row][column] = sum;
+        }
+    }
+
+    for (grid, sum) in grids.iter_mut().zip(sums) {
+        for (row, row_sum) in sum.into_iter().enumerate() {
+            for (column, normal) in row_sum.into_iter().enumerate() {
+                if normal.norm_squared() > 0.0 {
+                    grid.normals[row][column] = glm::normalize(&normal);
+                }
+            }
+        }
+    }
+}
+
+/// Finds the corner the displacement starts at
+/// Hammer doesn't always write startposition exactly, so this is the corner closest to it
+pub fn find_start_corner(vertices: &[glm::Vec3], startpos: &glm::Vec3) -> usize {
+    vertices
+        .iter()
+        .map(|vertex| (vertex - startpos).norm_squared())
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+        .unwrap_or_default()
+}
+
+/// Gets the position of a vertex of the undisplaced grid
+/// corners are start, column, opposite and row corner, see get_displacement_mesh
+pub fn get_disp_point(row: usize, column: usize, power: u8, corners: &[glm::Vec3; 4]) -> glm::Vec3 {
+    let [start_point, column_point, opposite_point, row_point] = corners;
+    let max_i = (1 << power) as f32;
+    let p1 = glm::lerp(start_point, row_point, row as f32 / max_i);
+    let p2 = glm::lerp(column_point, opposite_point, row as f32 / max_i);
+    glm::lerp(&p1, &p2, column as f32 / max_i)
+}
+
+/// Gets the texture coordinate of a point on the side, in pixels
+pub fn get_uv_point(info: &Face, point: &glm::Vec3) -> glm::Vec2 {
+    glm::vec2(info.uaxis.get_uv(point), info.vaxis.get_uv(point))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 128x128 box with a displacement of the given power on top
+    fn disp_brush(power: u8, startpos: glm::Vec3) -> Brush {
+        // Every row is one unit higher than the one before
+        disp_brush_at(power, startpos, glm::vec3(0.0, 0.0, 0.0), |row, _| {
+            row as f32
+        })
+    }
+
+    /// A 128x128 box moved by offset, with a displacement on top that gets its distances from height
+    fn disp_brush_at(
+        power: u8,
+        startpos: glm::Vec3,
+        offset: glm::Vec3,
+        height: impl Fn(usize, usize) -> f32,
+    ) -> Brush {
+        let mut brush = Brush::new_box(
+            1,
+            1,
+            &(glm::vec3(-64.0, -64.0, 0.0) + offset),
+            &(glm::vec3(64.0, 64.0, 64.0) + offset),
+            "NATURE/BLENDGROUNDTOGRASS001",
+        )
+        .unwrap();
+        let size = (1 << power) + 1;
+        let dispinfo = Dispinfo {
+            power,
+            startpos: startpos + offset,
+            flags: Some(0),
+            elevation: 0.0,
+            subdiv: false,
+            normals: vec![vec![glm::vec3(0.0, 0.0, 1.0); size]; size],
+            distances: (0..size)
+                .map(|row| (0..size).map(|column| height(row, column)).collect())
+                .collect(),
+            offsets: Some(vec![vec![glm::vec3(0.0, 0.0, 0.0); size]; size]),
+            offset_normals: None,
+            alphas: vec![vec![255.0; size]; size],
+            multiblend: None,
+            triangle_tags: None,
+            allowed_verts: None,
+            extra: vec![],
+        };
+        for (face, _) in &mut brush.shape.faces {
+            if face.normal().z > 0.5 {
+                face.dispinfo = Some(dispinfo.clone());
+            }
+        }
+        brush
+    }
+
+    #[test]
+    fn test_find_start_corner() {
+        let vertices = [
+            glm::vec3(64.0, 64.0, 64.0),
+            glm::vec3(64.0, -64.0, 64.0),
+            glm::vec3(-64.0, -64.0, 64.0),
+            glm::vec3(-64.0, 64.0, 64.0),
+        ];
+        assert_eq!(
+            find_start_corner(&vertices, &glm::vec3(-64.0, -64.0, 64.0)),
+            2
+        );
+        // Slightly off, like Hammer sometimes writes it
+        assert_eq!(
+            find_start_corner(&vertices, &glm::vec3(63.5, 64.2, 64.0)),
+            0
+        );
+        assert_eq!(
+            find_start_corner(&vertices[1..], &glm::vec3(-64.0, 64.0, 64.0)),
+            2
+        );
+    }
+
+    #[test]
+    fn test_displacement_grids() {
+        for power in 2..=4 {
+            let brush = disp_brush(power, glm::vec3(-64.0, -64.0, 64.0));
+            let meshes = get_brush_meshes(&brush).unwrap();
+            // Only the displacement gets drawn
+            assert_eq!(meshes.len(), 1);
+            let (face, mesh) = &meshes[0];
+            let quads = 1 << (power * 2);
+            assert_eq!(mesh.triangle_count(), quads * 2);
+            assert_eq!(mesh.positions.len(), quads * 2 * 3 * 3);
+            assert_eq!(mesh.uvs.len(), quads * 2 * 3 * 2);
+            assert!(mesh.face_ids.iter().all(|&id| id == face.id));
+            assert!(mesh
+                .triangle_tags
+                .iter()
+                .all(|&tag| tag == Some(TriangleTag::default())));
+
+            // The first triangle starts at the start corner, the rows go along y
+            let step = 128.0 / (1 << power) as f32;
+            let [a, b, c] = mesh.get_triangle(0);
+            assert_eq!(a, glm::vec3(-64.0, -64.0, 64.0));
+            assert_eq!(b, glm::vec3(-64.0 + step, -64.0, 64.0));
+            assert_eq!(c, glm::vec3(-64.0, -64.0 + step, 65.0));
+            assert!(mesh.get_triangle_normal(0).z > 0.0);
+
+            // The last triangle ends at the opposite corner, at the height of the last row
+            let [.., last] = mesh.get_triangle(mesh.triangle_count() - 1);
+            assert_eq!(last, glm::vec3(64.0, 64.0, 64.0 + (1 << power) as f32));
+        }
+    }
+
+    #[test]
+    fn test_displacement_start_corner() {
+        // Starting at another corner turns the grid, rows now go along x
+        let brush = disp_brush(2, glm::vec3(-64.0, 64.0, 64.0));
+        let meshes = get_brush_meshes(&brush).unwrap();
+        let [a, b, c] = meshes[0].1.get_triangle(0);
+        assert_eq!(a, glm::vec3(-64.0, 64.0, 64.0));
+        assert_eq!(b, glm::vec3(-64.0, 32.0, 64.0));
+        assert_eq!(c, glm::vec3(-32.0, 64.0, 65.0));
+    }
+
+    #[test]
+    fn test_face_mesh() {
+        let brush = Brush::new_box(
+            1,
+            1,
+            &glm::vec3(0.0, 0.0, 0.0),
+            &glm::vec3(64.0, 64.0, 64.0),
+            "DEV/DEV_MEASUREGENERIC01B",
+        )
+        .unwrap();
+        let meshes = get_brush_meshes(&brush).unwrap();
+        assert_eq!(meshes.len(), 6);
+        for (face, mesh) in &meshes {
+            assert_eq!(mesh.triangle_count(), 2);
+            assert!(mesh.alphas.iter().all(|&alpha| alpha == 0.0));
+            for triangle in 0..2 {
+                assert!((mesh.get_triangle_normal(triangle) - face.normal()).norm() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sewn_normals() {
+        // Two displacements forming a ridge along x = 0
+        let start = glm::vec3(-64.0, -64.0, 64.0);
+        let left = disp_brush_at(2, start, glm::vec3(-64.0, 0.0, 0.0), |_, column| {
+            column as f32 * 8.0
+        });
+        let right = disp_brush_at(2, start, glm::vec3(64.0, 0.0, 0.0), |_, column| {
+            32.0 - column as f32 * 8.0
+        });
+        let top = |brush: &Brush| {
+            let (info, face) = brush
+                .shape
+                .faces
+                .iter()
+                .find(|(info, _)| info.dispinfo.is_some())
+                .unwrap();
+            let vertices: Vec<_> = face.iter().map(|&x| brush.shape.vertices[x]).collect();
+            get_displacement_grid(&vertices, info, info.dispinfo.as_ref().unwrap()).unwrap()
+        };
+        // A flat one only touching the right one at its corner
+        let corner = disp_brush_at(2, start, glm::vec3(192.0, 128.0, 0.0), |_, _| 0.0);
+        let mut grids = vec![top(&left), top(&right), top(&corner)];
+        assert_eq!(
+            find_sewn_edges(&grids),
+            vec![SewnEdge {
+                grids: (0, 1),
+                edges: (1, 3)
+            }]
+        );
+
+        smooth_displacement_normals(&mut grids);
+        let vertices: Vec<_> = (0..5)
+            .flat_map(|row| (0..5).map(move |column| (row, column)))
+            .collect();
+        let mut touching = 0;
+        for &a in &vertices {
+            for &b in &vertices {
+                if grids[1].get_position(a) == grids[2].get_position(b) {
+                    touching += 1;
+                    let normal = |grid: &DisplacementGrid, (row, column): (usize, usize)| {
+                        grid.normals[row][column]
+                    };
+                    assert!((normal(&grids[1], a) - normal(&grids[2], b)).norm() < 1e-6);
+                }
+            }
+        }
+        assert_eq!(touching, 1);
+        for row in 0..5 {
+            // The ridge is the same in both, and away from the ends it points straight up
+            let normal = grids[0].normals[row][4];
+            assert!((normal - grids[1].normals[row][0]).norm() < 1e-6);
+            if (1..4).contains(&row) {
+                assert!((normal - glm::vec3(0.0, 0.0, 1.0)).norm() < 1e-6);
+            }
+            // The slopes point away from the ridge
+            assert!(grids[0].normals[row][2].x < 0.0);
+            assert!(grids[1].normals[row][2].x > 0.0);
+        }
+
+        // The same goes for the meshes built from the brushes
+        let meshes = get_meshes(&[&left, &right]);
+        let normal_at = |mesh: &Mesh, position: glm::Vec3| {
+            let vertex = mesh
+                .positions
+                .chunks(3)
+                .position(|x| glm::make_vec3(x) == position)
+                .unwrap();
+            glm::make_vec3(&mesh.normals[vertex * 3..vertex * 3 + 3])
+        };
+        let ridge = glm::vec3(0.0, 0.0, 96.0);
+        let left_normal = normal_at(&meshes[0].as_ref().unwrap()[0].1, ridge);
+        let right_normal = normal_at(&meshes[1].as_ref().unwrap()[0].1, ridge);
+        assert!((left_normal - right_normal).norm() < 1e-6);
+    }
+
+    #[test]
+    fn test_smoothing_groups() {
+        let mut brush = Brush::new_box(
+            1,
+            1,
+            &glm::vec3(0.0, 0.0, 0.0),
+            &glm::vec3(64.0, 64.0, 64.0),
+            "DEV/DEV_MEASUREGENERIC01B",
+        )
+        .unwrap();
+        for (face, _) in &mut brush.shape.faces {
+            // The top and the sides on x share a group, the rest is flat
+            face.smoothing_groups = match face.normal() {
+                normal if normal.z > 0.5 => 1,
+                normal if normal.x.abs() > 0.5 => 1 | 2,
+                _ => 0,
+            };
+        }
+        let meshes = get_brush_meshes(&brush).unwrap();
+        for (face, mesh) in &meshes {
+            for (position, normal) in mesh.positions.chunks(3).zip(mesh.normals.chunks(3)) {
+                let (position, normal) = (glm::make_vec3(position), glm::make_vec3(normal));
+                let expected = match face.smoothing_groups {
+                    0 => face.normal(),
+                    _ if position.z == 64.0 => glm::normalize(&glm::vec3(
+                        if position.x == 0.0 { -1.0 } else { 1.0 },
+                        0.0,
+                        1.0,
+                    )),
+                    _ => face.normal(),
+                };
+                assert!((normal - expected).norm() < 1e-6, "{face:?} {position}");
+            }
+        }
+    }
+}
@@ -0,0 +1,521 @@
+use nalgebra_glm as glm;
+
+use crate::{
+    math::PolyhedronError,
+    vmf::{Brush, Face},
+};
+
+// Like the CSG functions, the brushes and sides made here have the id 0,
+// they need new ids before saving
+
+/// Vertices closer than this are the same, e.g. at the poles of a sphere
+const SAME_VERTEX_EPSILON: f64 = 0.001;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum PrimitiveError {
+    #[error(transparent)]
+    Polyhedron(#[from] PolyhedronError),
+    #[error("invalid {0} for the primitive")]
+    InvalidParameter(&'static str),
+}
+
+/// The settings of Hammer's arch dialog
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArchParams {
+    /// How many brushes the arch is made of
+    pub sides: u32,
+    /// How thick the arch is, measured inwards from the bounding box
+    pub wall_width: f32,
+    /// How far around the arch goes in degrees, 360 makes a ring
+    pub arc: f32,
+    /// Where the arch starts in degrees, counter-clockwise from +x
+    pub start_angle: f32,
+    /// How much higher each brush is than the one before, for spiral stairs
+    pub add_height: f32,
+}
+
+impl Default for ArchParams {
+    fn default() -> Self {
+        Self {
+            sides: 8,
+            wall_width: 32.0,
+            arc: 360.0,
+            start_angle: 0.0,
+            add_height: 0.0,
+        }
+    }
+}
+
+/// The settings of Hammer's torus dialog
+/// The ring goes around like an arch, the tube is centered on the height of the bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TorusParams {
+    pub ring: ArchParams,
+    /// How many brushes go around the tube
+    pub tube_sides: u32,
+    /// At most half the height of the bounding box, bigger ones get made smaller
+    pub tube_radius: f32,
+}
+
+impl Default for TorusParams {
+    fn default() -> Self {
+        Self {
+            ring: ArchParams {
+                sides: 16,
+                wall_width: 16.0,
+                ..Default::default()
+            },
+            tube_sides: 8,
+            tube_radius: 32.0,
+        }
+    }
+}
+
+/// The same box as Brush::new_box
+pub fn block(mins: &glm::Vec3, maxs: &glm::Vec3, material: &str) -> Result<Brush, PrimitiveError> {
+    check_bounds(mins, maxs)?;
+    Ok(Brush::new_box(0, 0, mins, maxs, material)?)
+}
+
+/// A block with the top sloping down towards +x
+pub fn wedge(mins: &glm::Vec3, maxs: &glm::Vec3, material: &str) -> Result<Brush, PrimitiveError> {
+    let (mins, maxs) = check_bounds(mins, maxs)?;
+    hexahedron(
+        |x, y, z| {
+            // The top corners on +x are pulled back to -x, leaving a ridge
+            let x = if z == 1 { mins.x } else { [mins.x, maxs.x][x] };
+            glm::vec3(x, [mins.y, maxs.y][y], [mins.z, maxs.z][z])
+        },
+        material,
+    )
+}
+
+/// An upright cylinder fitting the bounding box, with an ellipse if it isn't square
+pub fn cylinder(
+    mins: &glm::Vec3,
+    maxs: &glm::Vec3,
+    sides: u32,
+    material: &str,
+) -> Result<Brush, PrimitiveError> {
+    if sides < 3 {
+        return Err(PrimitiveError::InvalidParameter("side count"));
+    }
+    let (mins, maxs) = check_bounds(mins, maxs)?;
+    let base = get_ellipse(&mins, &maxs, sides);
+    let top: Vec<_> = base.iter().map(|x| glm::vec3(x.x, x.y, maxs.z)).collect();
+    let mut polygons = vec![base.clone(), top.clone()];
+    for i in 0..base.len() {
+        let next = (i + 1) % base.len();
+        polygons.push(vec![base[i], base[next], top[next], top[i]]);
+    }
+    brush_from_polygons(&polygons, material)
+}
+
+/// A cone with its point at the top center of the bounding box
+pub fn spike(
+    mins: &glm::Vec3,
+    maxs: &glm::Vec3,
+    sides: u32,
+    material: &str,
+) -> Result<Brush, PrimitiveError> {
+    if sides < 3 {
+        return Err(PrimitiveError::InvalidParameter("side count"));
+    }
+    let (mins, maxs) = check_bounds(mins, maxs)?;
+    let base = get_ellipse(&mins, &maxs, sides);
+    let tip = glm::vec3((mins.x + maxs.x) / 2.0, (mins.y + maxs.y) / 2.0, maxs.z);
+    let mut polygons = vec![base.clone()];
+    for i in 0..base.len() {
+        polygons.push(vec![base[i], base[(i + 1) % base.len()], tip]);
+    }
+    brush_from_polygons(&polygons, material)
+}
+
+/// A sphere, or an ellipsoid if the bounding box isn't a cube
+/// sides goes around the middle, there are half as many rings from bottom to top
+pub fn sphere(
+    mins: &glm::Vec3,
+    maxs: &glm::Vec3,
+    sides: u32,
+    material: &str,
+) -> Result<Brush, PrimitiveError> {
+    if sides < 4 {
+        return Err(PrimitiveError::InvalidParameter("side count"));
+    }
+    let (mins, maxs) = check_bounds(mins, maxs)?;
+    let center = (mins + maxs) / 2.0;
+    let radii = (maxs - mins) / 2.0;
+    let rings = sides / 2;
+    let point = |ring: u32, side: u32| {
+        let pitch = std::f64::consts::PI * (ring as f64 / rings as f64 - 0.5);
+        let yaw = std::f64::consts::TAU * side as f64 / sides as f64;
+        let direction = glm::vec3(
+            pitch.cos() * yaw.cos(),
+            pitch.cos() * yaw.sin(),
+            pitch.sin(),
+        );
+        center + radii.component_mul(&direction)
+    };
+    let mut polygons = vec![];
+    for ring in 0..rings {
+        for side in 0..sides {
+            // Quads on a sphere are flat, the ones at the poles become triangles
+            polygons.push(vec![
+                point(ring, side),
+                point(ring, side + 1),
+                point(ring + 1, side + 1),
+                point(ring + 1, side),
+            ]);
+        }
+    }
+    brush_from_polygons(&polygons, material)
+}
+
+/// A ring of brushes following the outline of the bounding box, see ArchParams
+pub fn arch(
+    mins: &glm::Vec3,
+    maxs: &glm::Vec3,
+    params: &ArchParams,
+    material: &str,
+) -> Result<Vec<Brush>, PrimitiveError> {
+    let (mins, maxs) = check_bounds(mins, maxs)?;
+    let radii = (maxs - mins).xy() / 2.0;
+    let wall_width = params.wall_width as f64;
+    if wall_width <= 0.0 || wall_width > radii.min() {
+        return Err(PrimitiveError::InvalidParameter("wall width"));
+    }
+    ring_pieces(params, |angle, z| {
+        let center = (mins + maxs) / 2.0;
+        let corner = |radius: usize, side: usize, top: usize| {
+            let radii = radii.add_scalar(-wall_width * radius as f64);
+            let angle = angle[side];
+            glm::vec3(
+                center.x + radii.x * angle.cos(),
+                center.y + radii.y * angle.sin(),
+                [mins.z, maxs.z][top] + z,
+            )
+        };
+        hexahedron(corner, material)
+    })
+}
+
+/// A ring of tube pieces, see TorusParams
+pub fn torus(
+    mins: &glm::Vec3,
+    maxs: &glm::Vec3,
+    params: &TorusParams,
+    material: &str,
+) -> Result<Vec<Brush>, PrimitiveError> {
+    let (mins, maxs) = check_bounds(mins, maxs)?;
+    let tube_radius = (params.tube_radius as f64).min((maxs.z - mins.z) / 2.0);
+    let wall_width = params.ring.wall_width as f64;
+    // The middle of the tube, the outside of the tube touches the bounding box
+    let radii = ((maxs - mins).xy() / 2.0).add_scalar(-tube_radius);
+    if tube_radius <= 0.0 || radii.min() <= 0.0 {
+        return Err(PrimitiveError::InvalidParameter("tube radius"));
+    }
+    if wall_width <= 0.0 || wall_width > tube_radius {
+        return Err(PrimitiveError::InvalidParameter("wall width"));
+    }
+    if params.tube_sides < 3 {
+        return Err(PrimitiveError::InvalidParameter("tube side count"));
+    }
+    let center = (mins + maxs) / 2.0;
+    let tube_step = std::f64::consts::TAU / params.tube_sides as f64;
+    let mut brushes = vec![];
+    for tube_side in 0..params.tube_sides {
+        let tube_angle = [
+            tube_side as f64 * tube_step,
+            (tube_side + 1) as f64 * tube_step,
+        ];
+        brushes.extend(ring_pieces(&params.ring, |angle, z| {
+            let corner = |radius: usize, side: usize, tube: usize| {
+                let distance = tube_radius - wall_width * radius as f64;
+                let (angle, tube_angle) = (angle[side], tube_angle[tube]);
+                let radii = radii.add_scalar(distance * tube_angle.cos());
+                glm::vec3(
+                    center.x + radii.x * angle.cos(),
+                    center.y + radii.y * angle.sin(),
+                    center.z + distance * tube_angle.sin() + z,
+                )
+            };
+            hexahedron(corner, material)
+        })?);
+    }
+    Ok(brushes)
+}
+
+/// Calls make_piece for each piece going around, with its start and end angle in radians
+/// and how much it's raised
+fn ring_pieces(
+    params: &ArchParams,
+    make_piece: impl Fn([f64; 2], f64) -> Result<Brush, PrimitiveError>,
+) -> Result<Vec<Brush>, PrimitiveError> {
+    if params.sides == 0 {
+        return Err(PrimitiveError::InvalidParameter("side count"));
+    }
+    // Pieces spanning half a circle or more wouldn't be convex
+    let step = params.arc as f64 / params.sides as f64;
+    if params.arc <= 0.0 || params.arc > 360.0 || step >= 180.0 {
+        return Err(PrimitiveError::InvalidParameter("arc"));
+    }
+    (0..params.sides)
+        .map(|i| {
+            let angle = |i: u32| (params.start_angle as f64 + step * i as f64).to_radians();
+            make_piece(
+                [angle(i), angle(i + 1)],
+                params.add_height as f64 * i as f64,
+            )
+        })
+        .collect()
+}
+
+/// The bounding box in double precision, it has to have some size on every axis
+fn check_bounds(
+    mins: &glm::Vec3,
+    maxs: &glm::Vec3,
+) -> Result<(glm::DVec3, glm::DVec3), PrimitiveError> {
+    if mins.iter().zip(maxs).any(|(min, max)| min >= max) {
+        return Err(PrimitiveError::InvalidParameter("bounding box"));
+    }
+    Ok((mins.cast(), maxs.cast()))
+}
+
+/// Points going around the outline of the bounding box at the bottom
+fn get_ellipse(mins: &glm::DVec3, maxs: &glm::DVec3, sides: u32) -> Vec<glm::DVec3> {
+    let center = (mins + maxs) / 2.0;
+    let radii = (maxs - mins) / 2.0;
+    (0..sides)
+        .map(|i| {
+            let angle = std::f64::consts::TAU * i as f64 / sides as f64;
+            glm::vec3(
+                center.x + radii.x * angle.cos(),
+                center.y + radii.y * angle.sin(),
+                mins.z,
+            )
+        })
+        .collect()
+}
+
+/// A brush shaped like a deformed box, corner(x, y, z) gives its corners with 0 or 1 for each
+/// Corners may be the same point, e.g. to make a wedge
+fn hexahedron(
+    corner: impl Fn(usize, usize, usize) -> glm::DVec3,
+    material: &str,
+) -> Result<Brush, PrimitiveError> {
+    let mut polygons = vec![];
+    for axis in 0..3 {
+        for value in 0..2 {
+            let polygon = [(0, 0), (1, 0), (1, 1), (0, 1)]
+                .iter()
+                .map(|&(a, b)| match axis {
+                    0 => corner(value, a, b),
+                    1 => corner(a, value, b),
+                    _ => corner(a, b, value),
+                })
+                .collect();
+            polygons.push(polygon);
+        }
+    }
+    brush_from_polygons(&polygons, material)
+}
+
+/// Makes a side for every polygon and builds the brush out of them like a loaded one
+/// The polygons can go either way around, they get turned to face away from the middle
+fn brush_from_polygons(
+    polygons: &[Vec<glm::DVec3>],
+    material: &str,
+) -> Result<Brush, PrimitiveError> {
+    let vertices = polygons.iter().flatten();
+    let middle = vertices.clone().sum::<glm::DVec3>() / vertices.count().max(1) as f64;
+    let mut faces = vec![];
+    for polygon in polygons {
+        let mut points: Vec<glm::DVec3> = vec![];
+        for point in polygon {
+            if points
+                .last()
+                .is_none_or(|x| (x - point).norm() > SAME_VERTEX_EPSILON)
+            {
+                points.push(*point);
+            }
+        }
+        while points.len() > 1
+            && (points[0] - points[points.len() - 1]).norm() <= SAME_VERTEX_EPSILON
+        {
+            points.pop();
+        }
+        if points.len() < 3 {
+            continue;
+        }
+        // The largest triangle from the first point gives the most precise plane
+        let (b, c) = (1..points.len() - 1)
+            .map(|i| (points[i], points[i + 1]))
+            .max_by(|(a, b), (c, d)| {
+                let area = |x: &glm::DVec3, y: &glm::DVec3| {
+                    glm::cross(&(x - points[0]), &(y - points[0])).norm()
+                };
+                area(a, b).total_cmp(&area(c, d))
+            })
+            .expect("there are at least 3 points");
+        let a = points[0];
+        let normal = glm::cross(&(b - a), &(c - a));
+        // The VMF wants the points clockwise when looking at the outside
+        let (b, c) = if glm::dot(&normal, &(a - middle)) > 0.0 {
+            (c, b)
+        } else {
+            (b, c)
+        };
+        if let Some(face) = Face::from_points(0, (a.cast(), b.cast(), c.cast()), material) {
+            faces.push(face);
+        }
+    }
+    Ok(Brush::from_faces(0, faces)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vmf::UVAxis;
+
+    use super::*;
+
+    /// Checks the brush came out valid, fits the bounding box and has world aligned textures
+    fn check_brush(brush: &Brush, mins: &glm::Vec3, maxs: &glm::Vec3) {
+        let (brush_mins, brush_maxs) = brush.get_bounds().unwrap();
+        assert!(brush_mins.iter().zip(mins).all(|(a, b)| a - b > -0.01));
+        assert!(brush_maxs.iter().zip(maxs).all(|(a, b)| a - b < 0.01));
+        assert!(brush.shape.is_convex(0.01));
+        assert!(brush.shape.get_volume() > 0.0);
+        assert_eq!(brush.faces.len(), brush.shape.faces.len());
+        for face in &brush.faces {
+            assert_eq!(face.material, "BRICK/BRICKWALL001A");
+            let (uaxis, vaxis) = UVAxis::world_aligned(&face.normal());
+            assert_eq!(face.uaxis.dir, uaxis.dir);
+            assert_eq!(face.vaxis.dir, vaxis.dir);
+        }
+    }
+
+    const MATERIAL: &str = "BRICK/BRICKWALL001A";
+
+    #[test]
+    fn test_simple_primitives() {
+        let mins = glm::vec3(-64.0, -32.0, 0.0);
+        let maxs = glm::vec3(64.0, 32.0, 128.0);
+        let volume = 128.0 * 64.0 * 128.0;
+
+        let brush = block(&mins, &maxs, MATERIAL).unwrap();
+        check_brush(&brush, &mins, &maxs);
+        assert_eq!(brush.shape.get_volume(), volume);
+
+        let brush = wedge(&mins, &maxs, MATERIAL).unwrap();
+        check_brush(&brush, &mins, &maxs);
+        assert_eq!(brush.faces.len(), 5);
+        assert_eq!(brush.shape.get_volume(), volume / 2.0);
+
+        let brush = cylinder(&mins, &maxs, 12, MATERIAL).unwrap();
+        check_brush(&brush, &mins, &maxs);
+        assert_eq!(brush.faces.len(), 14);
+        // A 12 sided polygon has 3 times the area of the square around its radius
+        assert!((brush.shape.get_volume() - volume / 4.0 * 3.0).abs() < 1.0);
+
+        let brush = spike(&mins, &maxs, 12, MATERIAL).unwrap();
+        check_brush(&brush, &mins, &maxs);
+        assert_eq!(brush.faces.len(), 13);
+        assert!((brush.shape.get_volume() - volume / 4.0).abs() < 1.0);
+
+        let brush = sphere(&mins, &maxs, 16, MATERIAL).unwrap();
+        check_brush(&brush, &mins, &maxs);
+        assert_eq!(brush.faces.len(), 16 * 8);
+        // Somewhat less than an actual ellipsoid
+        let ellipsoid = std::f32::consts::PI / 6.0 * volume;
+        let ratio = brush.shape.get_volume() / ellipsoid;
+        assert!((0.9..1.0).contains(&ratio), "{ratio}");
+
+        assert_eq!(
+            cylinder(&mins, &maxs, 2, MATERIAL).unwrap_err(),
+            PrimitiveError::InvalidParameter("side count")
+        );
+        assert_eq!(
+            block(&mins, &glm::vec3(64.0, 32.0, 0.0), MATERIAL).unwrap_err(),
+            PrimitiveError::InvalidParameter("bounding box")
+        );
+    }
+
+    #[test]
+    fn test_arch() {
+        let mins = glm::vec3(-128.0, -128.0, 0.0);
+        let maxs = glm::vec3(128.0, 128.0, 16.0);
+        let brushes = arch(&mins, &maxs, &ArchParams::default(), MATERIAL).unwrap();
+        assert_eq!(brushes.len(), 8);
+        for brush in &brushes {
+            check_brush(brush, &mins, &maxs);
+            assert_eq!(brush.faces.len(), 6);
+        }
+        // An octagon with a hole, the outer one is 2 * sqrt(2) * radius²
+        let area = |radius: f32| 2.0 * 2.0f32.sqrt() * radius * radius;
+        let volume: f32 = brushes.iter().map(|x| x.shape.get_volume()).sum();
+        assert!((volume - (area(128.0) - area(96.0)) * 16.0).abs() < 1.0);
+
+        // Half an arch going up like stairs
+        let params = ArchParams {
+            sides: 4,
+            arc: 180.0,
+            start_angle: 90.0,
+            add_height: 16.0,
+            ..Default::default()
+        };
+        let brushes = arch(&mins, &maxs, &params, MATERIAL).unwrap();
+        assert_eq!(brushes.len(), 4);
+        let (first_mins, _) = brushes[0].get_bounds().unwrap();
+        let (last_mins, last_maxs) = brushes[3].get_bounds().unwrap();
+        assert_eq!(last_mins.z, 48.0);
+        assert!(first_mins.y > -0.01 && last_maxs.y < 0.01);
+        assert!(last_maxs.x < 0.01);
+
+        let params = ArchParams {
+            wall_width: 200.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            arch(&mins, &maxs, &params, MATERIAL).unwrap_err(),
+            PrimitiveError::InvalidParameter("wall width")
+        );
+        let params = ArchParams {
+            sides: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            arch(&mins, &maxs, &params, MATERIAL).unwrap_err(),
+            PrimitiveError::InvalidParameter("arc")
+        );
+    }
+
+    #[test]
+    fn test_torus() {
+        let mins = glm::vec3(-256.0, -256.0, -64.0);
+        let maxs = glm::vec3(256.0, 256.0, 64.0);
+        let brushes = torus(&mins, &maxs, &TorusParams::default(), MATERIAL).unwrap();
+        assert_eq!(brushes.len(), 16 * 8);
+        for brush in &brushes {
+            check_brush(brush, &mins, &maxs);
+        }
+        // Nothing goes through the middle of the tube
+        let tube_center = glm::vec3(224.0, 0.0, 0.0);
+        assert!(brushes
+            .iter()
+            .all(|x| !x.shape.contains_point(&tube_center, -0.01)));
+
+        // The tube gets thinner to fit a flat box
+        let maxs = glm::vec3(256.0, 256.0, -16.0);
+        let brushes = torus(&mins, &maxs, &TorusParams::default(), MATERIAL).unwrap();
+        for brush in &brushes {
+            check_brush(brush, &mins, &maxs);
+        }
+
+        // A tube as wide as the box leaves no room for the ring
+        let narrow = glm::vec3(32.0, 32.0, 64.0);
+        assert_eq!(
+            torus(&-narrow, &narrow, &TorusParams::default(), MATERIAL).unwrap_err(),
+            PrimitiveError::InvalidParameter("tube radius")
+        );
+    }
+}
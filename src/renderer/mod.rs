@@ -54,6 +54,13 @@ impl Renderer {
         unsafe { self.context.draw_arrays(glow::TRIANGLES, 0, count) };
     }
 
+    /// Draws every two vertices as a line
+    pub fn draw_lines(&self, data: &VertexData, shader: &Shader) {
+        let count = data.prepare_rendering();
+        shader.bind();
+        unsafe { self.context.draw_arrays(glow::LINES, 0, count) };
+    }
+
     pub fn enable_depth_test(&self, enable: bool) {
         match enable {
             true => unsafe {
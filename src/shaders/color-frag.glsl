@@ -0,0 +1,6 @@
+#version 330 core
+out vec4 out_color;
+uniform vec3 color;
+void main() {
+    out_color = vec4(color, 1.0);
+}
\ No newline at end of file
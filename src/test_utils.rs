@@ -0,0 +1,58 @@
+use nalgebra_glm as glm;
+
+/// xorshift, good enough to make up solids and commands
+/// Always made with new, a state of 0 would stay 0 forever
+pub struct Rng(u64);
+
+impl Rng {
+    const SPREAD: u64 = 0x9E3779B97F4A7C15;
+
+    /// Spreads small seeds over all bits, xorshift takes a while to get going from them
+    /// Only 0 maps to 0, so it is swapped for another seed
+    pub fn new(seed: u64) -> Self {
+        match seed.wrapping_mul(Self::SPREAD) {
+            0 => Self(Self::SPREAD),
+            x => Self(x),
+        }
+    }
+
+    pub fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn range(&mut self, min: f64, max: f64) -> f64 {
+        min + self.next() * (max - min)
+    }
+
+    /// A number from 0 to n - 1
+    pub fn below(&mut self, n: usize) -> usize {
+        ((self.next() * n as f64) as usize).min(n.saturating_sub(1))
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        items.get(self.below(items.len()))
+    }
+
+    pub fn direction(&mut self) -> glm::DVec3 {
+        loop {
+            let v = glm::vec3(
+                self.range(-1.0, 1.0),
+                self.range(-1.0, 1.0),
+                self.range(-1.0, 1.0),
+            );
+            if (0.01..1.0).contains(&v.norm_squared()) {
+                return glm::normalize(&v);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_zero_seed() {
+    let mut rng = Rng::new(0);
+    assert_ne!(rng.next(), rng.next());
+    assert!(rng.direction().norm() > 0.5);
+}
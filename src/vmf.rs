@@ -1,287 +1,2197 @@
 use glm::Vec3;
 use nalgebra_glm as glm;
 use scanf::sscanf;
-use std::path::Path;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path,
+    str::FromStr,
+};
 
 use crate::{
-    keyvalue::KeyValues,
-    math::{self, Polyhedron},
+    ids::IdRemap,
+    keyvalue::{self, escape_token, Conditions, KeyValues, KeyValuesError, Span},
+    math::{self, Epsilons, Plane, Polyhedron, PolyhedronError},
 };
 
-const MAX_MAP_EXTENT: f32 = 16384.0;
+const MAX_MAP_EXTENT: f64 = 16384.0;
+/// Brushes may stick out of the map a bit, anything bigger is most likely not closed
+const MAX_BRUSH_EXTENT: f64 = 4.0 * MAX_MAP_EXTENT;
+/// How thick the brushes sealing an exported cordon are
+const CORDON_SHELL_THICKNESS: f32 = 16.0;
+
+/// The order Hammer writes the keys of a side in
+const FACE_KEY_ORDER: [&str; 9] = [
+    "id",
+    "plane",
+    "material",
+    "uaxis",
+    "vaxis",
+    "rotation",
+    "lightmapscale",
+    "smoothing_groups",
+    "dispinfo",
+];
+
+/// The order Hammer writes the keys of an editor block in
+const EDITOR_KEY_ORDER: [&str; 7] = [
+    "color",
+    "visgroupid",
+    "groupid",
+    "visgroupshown",
+    "visgroupautoshown",
+    "comments",
+    "logicalpos",
+];
+
+/// The order Hammer writes the keys of the viewsettings in
+const VIEWSETTINGS_KEY_ORDER: [&str; 5] = [
+    "bSnapToGrid",
+    "bShowGrid",
+    "bShowLogicalGrid",
+    "nGridSpacing",
+    "bShow3DGrid",
+];
+
+/// The order Hammer writes the keys of a dispinfo in
+const DISPINFO_KEY_ORDER: [&str; 18] = [
+    "power",
+    "startposition",
+    "flags",
+    "elevation",
+    "subdiv",
+    "normals",
+    "distances",
+    "offsets",
+    "offset_normals",
+    "alphas",
+    "multiblend",
+    "alphablend",
+    "multiblend_color_0",
+    "multiblend_color_1",
+    "multiblend_color_2",
+    "multiblend_color_3",
+    "triangle_tags",
+    "allowed_verts",
+];
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum VmfError {
+    #[error(transparent)]
+    KeyValues(#[from] KeyValuesError),
+    #[error("missing block \"{0}\"")]
+    MissingBlock(String),
+    #[error("missing key \"{0}\" in block at {1}")]
+    MissingKey(String, Span),
+    #[error("invalid value \"{value}\" for key \"{key}\" at {span}")]
+    InvalidValue {
+        key: String,
+        value: String,
+        span: Span,
+    },
+    #[error("bad plane \"{0}\" at {1}")]
+    InvalidPlane(String, Span),
+    #[error("broken solid at {1}: {0}")]
+    InvalidShape(PolyhedronError, Span),
+    #[error("malformed uv axis \"{0}\" at {1}")]
+    InvalidUVAxis(String, Span),
+    #[error("malformed connection \"{output}\" \"{value}\" at {span}")]
+    InvalidConnection {
+        output: String,
+        value: String,
+        span: Span,
+    },
+    #[error("wrong number of rows in \"{key}\" (expected {expected}, found {found}) at {span}")]
+    WrongRowCount {
+        key: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    #[error("wrong number of values in \"{key}\" row {row} (expected {expected}, found {found}) at {span}")]
+    WrongRowLength {
+        key: String,
+        row: usize,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    #[error("in entity {id}: {source}")]
+    InEntity { id: i32, source: Box<VmfError> },
+    #[error("in solid {id}: {source}")]
+    InSolid { id: i32, source: Box<VmfError> },
+    #[error("in side {id}: {source}")]
+    InSide { id: i32, source: Box<VmfError> },
+}
+
+/// The keys and blocks a part of the VMF doesn't understand, in the order they were in the file
+/// They get written back as they were
+pub type Extra = Vec<(String, KeyValues<'static>)>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct VMF {
-    pub worldbrushes: Vec<Brush>,
+    pub versioninfo: Option<KeyValues<'static>>,
+    /// None if the file has no visgroups block
+    pub visgroups: Option<Vec<Visgroup>>,
+    pub viewsettings: Option<ViewSettings>,
+    /// The worldspawn entity, which owns all world brushes
+    pub world: Entity,
+    /// In the order they are in the file, including hidden ones
+    pub entities: Vec<Entity>,
+    pub cameras: Option<Cameras>,
+    pub cordons: Option<Cordons>,
+    pub extra: Extra,
+}
+
+impl VMF {
+    /// Reads a file and parses it into a VMF
+    /// Errors if anything in the file is invalid
+    pub fn parse(file: &Path) -> Result<Self, VmfError> {
+        let content = KeyValues::read_file(file)?;
+        let kv = KeyValues::parse_str(&content, file, &Conditions::default())?;
+        Self::from_keyvalues(&kv)
+    }
+
+    /// Reads a file and parses it into a VMF
+    /// Broken solids and entities are skipped, the errors are returned as warnings instead
+    pub fn parse_lenient(file: &Path) -> Result<(Self, Vec<VmfError>), VmfError> {
+        let content = KeyValues::read_file(file)?;
+        let kv = KeyValues::parse_str(&content, file, &Conditions::default())?;
+        Self::from_keyvalues_lenient(&kv)
+    }
+
+    pub fn from_keyvalues(kv: &KeyValues) -> Result<Self, VmfError> {
+        Self::from_keyvalues_internal(kv, false, &mut vec![])
+    }
+
+    pub fn from_keyvalues_lenient(kv: &KeyValues) -> Result<(Self, Vec<VmfError>), VmfError> {
+        let mut warnings = vec![];
+        let vmf = Self::from_keyvalues_internal(kv, true, &mut warnings)?;
+        Ok((vmf, warnings))
+    }
+
+    fn from_keyvalues_internal(
+        kv: &KeyValues,
+        lenient: bool,
+        warnings: &mut Vec<VmfError>,
+    ) -> Result<Self, VmfError> {
+        let mut versioninfo = None;
+        let mut visgroups = None;
+        let mut viewsettings = None;
+        let mut world = None;
+        let mut entities = vec![];
+        let mut cameras = None;
+        let mut cordons = None;
+        let mut extra = vec![];
+        for (name, block) in kv.get_all_kv_pairs() {
+            match name.to_lowercase().as_str() {
+                "versioninfo" => versioninfo = Some(block.clone().into_owned()),
+                "visgroups" => {
+                    visgroups = Some(
+                        block
+                            .get_all("visgroup")
+                            .into_iter()
+                            .map(Visgroup::parse)
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+                "viewsettings" => viewsettings = Some(ViewSettings::parse(block)?),
+                "world" => world = Some(Entity::parse(block, lenient, warnings)?),
+                "entity" => {
+                    if let Some(entity) =
+                        skip_if_lenient(Entity::parse(block, lenient, warnings), lenient, warnings)?
+                    {
+                        entities.push(entity);
+                    }
+                }
+                "hidden" => {
+                    for entity in block.get_all("entity") {
+                        if let Some(entity) = skip_if_lenient(
+                            Entity::parse(entity, lenient, warnings),
+                            lenient,
+                            warnings,
+                        )? {
+                            entities.push(Entity {
+                                hidden: true,
+                                ..entity
+                            });
+                        }
+                    }
+                }
+                "cameras" => cameras = Some(Cameras::parse(block)?),
+                "cordons" => cordons = Some(Cordons::parse(block)?),
+                _ => extra.push((name.to_string(), block.clone().into_owned())),
+            }
+        }
+
+        Ok(Self {
+            versioninfo,
+            visgroups,
+            viewsettings,
+            world: world.ok_or(VmfError::MissingBlock(String::from("world")))?,
+            entities,
+            cameras,
+            cordons,
+            extra,
+        })
+    }
+
+    /// Writes the VMF into a file, in the same layout Hammer uses
+    /// Errors if writing fails
+    pub fn write(&self, file: &Path) -> Result<(), io::Error> {
+        fs::write(file, self.get_string())
+    }
+
+    /// Gets the content of the VMF file, in the same layout Hammer uses
+    pub fn get_string(&self) -> String {
+        let mut blocks = vec![];
+        if let Some(versioninfo) = &self.versioninfo {
+            blocks.push(("versioninfo", versioninfo.clone()));
+        }
+        if let Some(visgroups) = &self.visgroups {
+            blocks.push((
+                "visgroups",
+                KeyValues::new_list(
+                    visgroups
+                        .iter()
+                        .map(|visgroup| ("visgroup", visgroup.to_keyvalues()))
+                        .collect(),
+                ),
+            ));
+        }
+        if let Some(viewsettings) = &self.viewsettings {
+            blocks.push(("viewsettings", viewsettings.to_keyvalues()));
+        }
+        blocks.push(("world", self.world.to_keyvalues()));
+        for entity in &self.entities {
+            blocks.push(hidden_kv(entity.hidden, "entity", entity.to_keyvalues()));
+        }
+        if let Some(cameras) = &self.cameras {
+            blocks.push(("cameras", cameras.to_keyvalues()));
+        }
+        if let Some(cordons) = &self.cordons {
+            blocks.push(("cordons", cordons.to_keyvalues()));
+        }
+        for (name, block) in &self.extra {
+            blocks.push((name, block.clone()));
+        }
+        blocks
+            .iter()
+            .map(|(name, block)| get_vmf_string(name, block, 0))
+            .collect()
+    }
+
+    /// Makes a copy with only the solids and entities inside the active cordons
+    /// Like Hammer, each cordon box is sealed with a shell of tools/toolsskybox brushes
+    /// Returns None if cordoning is turned off or no cordon is active
+    pub fn get_cordoned(&self) -> Option<VMF> {
+        let boxes = self.cordons.as_ref()?.get_active_boxes();
+        if boxes.is_empty() {
+            return None;
+        }
+        let inside = |brush: &Brush| {
+            brush
+                .get_bounds()
+                .is_some_and(|(mins, maxs)| boxes.iter().any(|x| x.intersects(&mins, &maxs)))
+        };
+
+        let mut vmf = self.clone();
+        vmf.cordons = None;
+        vmf.world.brushes.retain(|x| !x.hidden && inside(x));
+        vmf.entities.retain_mut(|entity| {
+            if entity.hidden {
+                return false;
+            }
+            if entity.is_brush_entity() {
+                entity.brushes.retain(|x| !x.hidden && inside(x));
+                return !entity.brushes.is_empty();
+            }
+            // Entities without an origin don't have a place in the map, e.g. logic_auto
+            entity
+                .origin()
+                .is_none_or(|origin| boxes.iter().any(|x| x.contains(&origin)))
+        });
+
+        let mut ids = self.get_id_registry();
+        for cordon_box in boxes {
+            for (mins, maxs) in cordon_box.get_shell(CORDON_SHELL_THICKNESS) {
+                // Flat cordons don't need sealing on that side
+                if let Ok(mut brush) = Brush::new_box(0, 0, &mins, &maxs, "TOOLS/TOOLSSKYBOX") {
+                    ids.renumber_brush(&mut brush, &mut IdRemap::default());
+                    vmf.world.brushes.push(brush);
+                }
+            }
+        }
+        Some(vmf)
+    }
+
+    /// Finds a visgroup by its id, even if it is inside another one
+    pub fn get_visgroup(&self, id: i32) -> Option<&Visgroup> {
+        self.visgroups
+            .iter()
+            .flatten()
+            .find_map(|visgroup| visgroup.find(id))
+    }
+
+    /// Gets the ids of every hidden visgroup, including the ones inside hidden visgroups
+    pub fn get_hidden_visgroups(&self, hidden: &HashSet<i32>) -> HashSet<i32> {
+        let mut result = HashSet::new();
+        for visgroup in self.visgroups.iter().flatten() {
+            visgroup.collect_hidden(hidden, false, &mut result);
+        }
+        result
+    }
+}
+
+/// A visgroup from the "visgroups" block
+/// Visgroups can contain other visgroups, objects name the visgroups they are in in their editor block
+#[derive(Debug, Clone)]
+pub struct Visgroup {
+    pub name: String,
+    pub id: i32,
+    pub color: [u8; 3],
+    pub children: Vec<Visgroup>,
+    pub extra: Extra,
+}
+
+impl Visgroup {
+    fn parse(kv: &KeyValues) -> Result<Self, VmfError> {
+        Ok(Self {
+            name: get_value(kv, "name")?.to_string(),
+            id: parse_value(kv, "visgroupid")?,
+            color: parse_color(kv, "color")?,
+            children: kv
+                .get_all("visgroup")
+                .into_iter()
+                .map(Visgroup::parse)
+                .collect::<Result<_, _>>()?,
+            extra: get_extra(kv, &["name", "visgroupid", "color", "visgroup"]),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let mut subkeys = vec![
+            (String::from("name"), value_kv(&self.name)),
+            (String::from("visgroupid"), value_kv(self.id)),
+            (String::from("color"), value_kv(color_string(&self.color))),
+        ];
+        for child in &self.children {
+            subkeys.push((String::from("visgroup"), child.to_keyvalues()));
+        }
+        subkeys.extend(self.extra.iter().cloned());
+        KeyValues::new_list(subkeys)
+    }
+
+    /// Finds this visgroup or one inside it by the id
+    pub fn find(&self, id: i32) -> Option<&Visgroup> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(id))
+    }
+
+    fn collect_hidden(
+        &self,
+        hidden: &HashSet<i32>,
+        parent_hidden: bool,
+        result: &mut HashSet<i32>,
+    ) {
+        let is_hidden = parent_hidden || hidden.contains(&self.id);
+        if is_hidden {
+            result.insert(self.id);
+        }
+        for child in &self.children {
+            child.collect_hidden(hidden, is_hidden, result);
+        }
+    }
+}
+
+/// The "cordons" block, cordons limit compiling to the boxes inside them
+#[derive(Debug, Clone)]
+pub struct Cordons {
+    /// Whether cordoning is turned on at all
+    pub active: bool,
+    pub cordons: Vec<Cordon>,
+    pub extra: Extra,
+}
+
+impl Cordons {
+    fn parse(kv: &KeyValues) -> Result<Self, VmfError> {
+        Ok(Self {
+            active: get_value(kv, "active")? != "0",
+            cordons: kv
+                .get_all("cordon")
+                .into_iter()
+                .map(Cordon::parse)
+                .collect::<Result<_, _>>()?,
+            extra: get_extra(kv, &["active", "cordon"]),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let mut subkeys = vec![(String::from("active"), value_kv(self.active as i32))];
+        for cordon in &self.cordons {
+            subkeys.push((String::from("cordon"), cordon.to_keyvalues()));
+        }
+        subkeys.extend(self.extra.iter().cloned());
+        KeyValues::new_list(subkeys)
+    }
+
+    /// Gets the boxes of every active cordon, or nothing if cordoning is turned off
+    pub fn get_active_boxes(&self) -> Vec<&CordonBox> {
+        if !self.active {
+            return vec![];
+        }
+        self.cordons
+            .iter()
+            .filter(|cordon| cordon.active)
+            .flat_map(|cordon| &cordon.boxes)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cordon {
+    pub name: String,
+    pub active: bool,
+    pub boxes: Vec<CordonBox>,
+    pub extra: Extra,
+}
+
+impl Cordon {
+    fn parse(kv: &KeyValues) -> Result<Self, VmfError> {
+        Ok(Self {
+            name: get_value(kv, "name")?.to_string(),
+            active: get_value(kv, "active")? != "0",
+            boxes: kv
+                .get_all("box")
+                .into_iter()
+                .map(CordonBox::parse)
+                .collect::<Result<_, _>>()?,
+            extra: get_extra(kv, &["name", "active", "box"]),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let mut subkeys = vec![
+            (String::from("name"), value_kv(&self.name)),
+            (String::from("active"), value_kv(self.active as i32)),
+        ];
+        for cordon_box in &self.boxes {
+            subkeys.push((String::from("box"), cordon_box.to_keyvalues()));
+        }
+        subkeys.extend(self.extra.iter().cloned());
+        KeyValues::new_list(subkeys)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CordonBox {
+    pub mins: Vec3,
+    pub maxs: Vec3,
+    pub extra: Extra,
+    pub source: SourceText,
+}
+
+impl CordonBox {
+    fn parse(kv: &KeyValues) -> Result<Self, VmfError> {
+        Ok(Self {
+            mins: parse_point(kv, "mins")?,
+            maxs: parse_point(kv, "maxs")?,
+            extra: get_extra(kv, &["mins", "maxs"]),
+            source: SourceText::new(kv, &["mins", "maxs"]),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let point_string = |p: &Vec3| format!("({} {} {})", p.x, p.y, p.z);
+        let mut subkeys = vec![
+            (String::from("mins"), value_kv(point_string(&self.mins))),
+            (String::from("maxs"), value_kv(point_string(&self.maxs))),
+        ];
+        subkeys.extend(self.extra.iter().cloned());
+        let mut kv = KeyValues::new_list(subkeys);
+        self.source.restore(&mut kv);
+        kv
+    }
+
+    /// Whether the point is inside the box, points on the border count as inside
+    pub fn contains(&self, point: &Vec3) -> bool {
+        (0..3).all(|i| self.mins[i] <= point[i] && point[i] <= self.maxs[i])
+    }
+
+    /// Whether the box overlaps the bounding box given by mins and maxs, touching doesn't count
+    pub fn intersects(&self, mins: &Vec3, maxs: &Vec3) -> bool {
+        (0..3).all(|i| self.mins[i] < maxs[i] && mins[i] < self.maxs[i])
+    }
+
+    /// Gets the bounds of six brushes with the given thickness that enclose the box from the outside
+    pub fn get_shell(&self, thickness: f32) -> [(Vec3, Vec3); 6] {
+        let outer_mins = self.mins.add_scalar(-thickness);
+        let outer_maxs = self.maxs.add_scalar(thickness);
+        let mut shell = [(outer_mins, outer_maxs); 6];
+        for axis in 0..3 {
+            // The walls on the x axis cover the whole side, the ones on y and z fit between them
+            for (mins, maxs) in &mut shell[axis * 2..axis * 2 + 2] {
+                for other in 0..axis {
+                    mins[other] = self.mins[other];
+                    maxs[other] = self.maxs[other];
+                }
+            }
+            shell[axis * 2].1[axis] = self.mins[axis];
+            shell[axis * 2 + 1].0[axis] = self.maxs[axis];
+        }
+        shell
+    }
 }
 
-impl VMF {
-    pub fn parse(file: &Path) -> Option<Self> {
-        let kv = KeyValues::parse(file).ok()?;
-        let mut worldbrushes = vec![];
-        for solid in kv.get("world")?.get_all("solid") {
-            worldbrushes.push(Brush::parse(solid)?);
+/// The editor settings from the "viewsettings" block
+#[derive(Debug, Clone)]
+pub struct ViewSettings {
+    pub snap_to_grid: bool,
+    pub show_grid: bool,
+    pub show_logical_grid: bool,
+    pub grid_spacing: i32,
+    pub show_3d_grid: bool,
+    pub extra: Extra,
+}
+
+impl Default for ViewSettings {
+    fn default() -> Self {
+        Self {
+            snap_to_grid: true,
+            show_grid: true,
+            show_logical_grid: false,
+            grid_spacing: 64,
+            show_3d_grid: false,
+            extra: vec![],
+        }
+    }
+}
+
+impl ViewSettings {
+    /// Missing keys keep the value Hammer uses for new maps
+    fn parse(kv: &KeyValues) -> Result<Self, VmfError> {
+        let default = Self::default();
+        let parse_bool = |key: &str, default: bool| match kv.get(key) {
+            Some(_) => Ok(get_value(kv, key)? != "0"),
+            None => Ok::<_, VmfError>(default),
+        };
+        Ok(Self {
+            snap_to_grid: parse_bool("bSnapToGrid", default.snap_to_grid)?,
+            show_grid: parse_bool("bShowGrid", default.show_grid)?,
+            show_logical_grid: parse_bool("bShowLogicalGrid", default.show_logical_grid)?,
+            grid_spacing: match kv.get("nGridSpacing") {
+                Some(_) => parse_value(kv, "nGridSpacing")?,
+                None => default.grid_spacing,
+            },
+            show_3d_grid: parse_bool("bShow3DGrid", default.show_3d_grid)?,
+            extra: get_extra(kv, &VIEWSETTINGS_KEY_ORDER),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let mut subkeys = vec![
+            (
+                String::from("bSnapToGrid"),
+                value_kv(self.snap_to_grid as i32),
+            ),
+            (String::from("bShowGrid"), value_kv(self.show_grid as i32)),
+            (
+                String::from("bShowLogicalGrid"),
+                value_kv(self.show_logical_grid as i32),
+            ),
+            (String::from("nGridSpacing"), value_kv(self.grid_spacing)),
+            (
+                String::from("bShow3DGrid"),
+                value_kv(self.show_3d_grid as i32),
+            ),
+        ];
+        subkeys.extend(self.extra.iter().cloned());
+        KeyValues::new_list(subkeys)
+    }
+}
+
+/// The saved 3D view cameras from the "cameras" block
+#[derive(Debug, Clone)]
+pub struct Cameras {
+    /// Index into cameras, -1 if there is no active camera
+    pub active_camera: i32,
+    pub cameras: Vec<Camera>,
+    pub extra: Extra,
+}
+
+impl Cameras {
+    fn parse(kv: &KeyValues) -> Result<Self, VmfError> {
+        Ok(Self {
+            active_camera: parse_value(kv, "activecamera")?,
+            cameras: kv
+                .get_all("camera")
+                .into_iter()
+                .map(Camera::parse)
+                .collect::<Result<_, _>>()?,
+            extra: get_extra(kv, &["activecamera", "camera"]),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let mut subkeys = vec![(String::from("activecamera"), value_kv(self.active_camera))];
+        for camera in &self.cameras {
+            subkeys.push((String::from("camera"), camera.to_keyvalues()));
+        }
+        subkeys.extend(self.extra.iter().cloned());
+        KeyValues::new_list(subkeys)
+    }
+
+    /// The camera the 3D view used when the map was saved
+    pub fn active(&self) -> Option<&Camera> {
+        usize::try_from(self.active_camera)
+            .ok()
+            .and_then(|index| self.cameras.get(index))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Camera {
+    pub position: Vec3,
+    /// The point the camera looks at
+    pub look: Vec3,
+    pub extra: Extra,
+    pub source: SourceText,
+}
+
+impl Camera {
+    fn parse(kv: &KeyValues) -> Result<Self, VmfError> {
+        Ok(Self {
+            position: parse_bracketed_vec3(kv, "position")?,
+            look: parse_bracketed_vec3(kv, "look")?,
+            extra: get_extra(kv, &["position", "look"]),
+            source: SourceText::new(kv, &["position", "look"]),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let mut subkeys = vec![
+            (
+                String::from("position"),
+                value_kv(vec3_string(&self.position)),
+            ),
+            (String::from("look"), value_kv(vec3_string(&self.look))),
+        ];
+        subkeys.extend(self.extra.iter().cloned());
+        let mut kv = KeyValues::new_list(subkeys);
+        self.source.restore(&mut kv);
+        kv
+    }
+
+    /// Yaw and pitch in radians, yaw is counterclockwise from the x axis
+    pub fn yaw_pitch(&self) -> (f32, f32) {
+        let dir = self.look - self.position;
+        if dir.norm_squared() == 0.0 {
+            return (0.0, 0.0);
+        }
+        let dir = glm::normalize(&dir);
+        (f32::atan2(dir.y, dir.x), f32::asin(dir.z))
+    }
+}
+
+/// The "editor" block of solids and entities
+/// Only Hammer uses this, the game never sees it
+#[derive(Debug, Clone)]
+pub struct EditorInfo {
+    pub color: Option<[u8; 3]>,
+    /// The visgroups this is in, there can be more than one
+    pub visgroup_ids: Vec<i32>,
+    /// The group this is in, groups are stored like entities in the "group" blocks of the world
+    pub group_id: Option<i32>,
+    pub visgroup_shown: bool,
+    pub visgroup_auto_shown: bool,
+    /// e.g. "logicalpos" or "comments"
+    pub extra: Extra,
+}
+
+impl EditorInfo {
+    fn parse(kv: &KeyValues) -> Result<Self, VmfError> {
+        let visgroup_ids = kv
+            .get_all("visgroupid")
+            .into_iter()
+            .map(|node| {
+                let value = node.get_value().unwrap_or_default();
+                value.parse().map_err(|_| VmfError::InvalidValue {
+                    key: String::from("visgroupid"),
+                    value: value.to_string(),
+                    span: node.span(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let optional = |key: &str| kv.get(key).is_some();
+        Ok(Self {
+            color: match optional("color") {
+                true => Some(parse_color(kv, "color")?),
+                false => None,
+            },
+            visgroup_ids,
+            group_id: match optional("groupid") {
+                true => Some(parse_value(kv, "groupid")?),
+                false => None,
+            },
+            visgroup_shown: !optional("visgroupshown") || get_value(kv, "visgroupshown")? != "0",
+            visgroup_auto_shown: !optional("visgroupautoshown")
+                || get_value(kv, "visgroupautoshown")? != "0",
+            extra: get_extra(
+                kv,
+                &[
+                    "color",
+                    "visgroupid",
+                    "groupid",
+                    "visgroupshown",
+                    "visgroupautoshown",
+                ],
+            ),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let mut subkeys = vec![];
+        if let Some(color) = &self.color {
+            subkeys.push((String::from("color"), value_kv(color_string(color))));
+        }
+        for id in &self.visgroup_ids {
+            subkeys.push((String::from("visgroupid"), value_kv(id)));
+        }
+        if let Some(group_id) = self.group_id {
+            subkeys.push((String::from("groupid"), value_kv(group_id)));
+        }
+        subkeys.push((
+            String::from("visgroupshown"),
+            value_kv(self.visgroup_shown as i32),
+        ));
+        subkeys.push((
+            String::from("visgroupautoshown"),
+            value_kv(self.visgroup_auto_shown as i32),
+        ));
+        subkeys.extend(self.extra.iter().cloned());
+        sort_keys(&mut subkeys, &EDITOR_KEY_ORDER);
+        KeyValues::new_list(subkeys)
+    }
+
+    /// Whether none of the visgroups this is in are hidden
+    /// hidden_visgroups should come from VMF::get_hidden_visgroups, so visgroups inside hidden ones count as well
+    pub fn is_visible(&self, hidden_visgroups: &HashSet<i32>) -> bool {
+        !self
+            .visgroup_ids
+            .iter()
+            .any(|id| hidden_visgroups.contains(id))
+    }
+}
+
+/// Represents a point or brush entity
+/// Brush entities own their solids, point entities have none
+/// The world is an entity as well (with the classname "worldspawn")
+#[derive(Debug, Clone)]
+pub struct Entity {
+    pub id: i32,
+    /// Every keyvalue except the id, in the order it appeared in the file (this includes classname, origin and angles)
+    pub keyvalues: Vec<(String, String)>,
+    pub connections: Vec<Connection>,
+    /// In the order they are in the file, including hidden ones
+    pub brushes: Vec<Brush>,
+    /// Hidden in the editor, it is written inside a "hidden" block
+    pub hidden: bool,
+    pub editor: Option<EditorInfo>,
+    /// e.g. "group" blocks
+    pub extra: Extra,
+}
+
+impl Entity {
+    /// If lenient is set, broken solids get skipped and added to warnings
+    fn parse(
+        kv: &KeyValues,
+        lenient: bool,
+        warnings: &mut Vec<VmfError>,
+    ) -> Result<Self, VmfError> {
+        let id = parse_value(kv, "id")?;
+        (|| {
+            let mut keyvalues = vec![];
+            let mut connections = vec![];
+            let mut brushes = vec![];
+            let mut editor = None;
+            let mut extra = vec![];
+            for (key, value) in kv.get_all_kv_pairs() {
+                match (key.to_lowercase().as_str(), value) {
+                    ("id", _) => {}
+                    (_, KeyValues::Value { value, .. }) => {
+                        keyvalues.push((key.to_string(), value.to_string()))
+                    }
+                    ("connections", _) => {
+                        for (output, value) in value.get_all_kv_pairs() {
+                            let span = value.span();
+                            let value =
+                                value
+                                    .get_value()
+                                    .ok_or_else(|| VmfError::InvalidConnection {
+                                        output: output.to_string(),
+                                        value: String::new(),
+                                        span,
+                                    })?;
+                            connections.push(Connection::parse(output, value, span)?);
+                        }
+                    }
+                    ("solid", _) => {
+                        if let Some(brush) =
+                            skip_if_lenient(Brush::parse(value), lenient, warnings)?
+                        {
+                            brushes.push(brush);
+                        }
+                    }
+                    ("hidden", _) => {
+                        for solid in value.get_all("solid") {
+                            if let Some(brush) =
+                                skip_if_lenient(Brush::parse(solid), lenient, warnings)?
+                            {
+                                brushes.push(Brush {
+                                    hidden: true,
+                                    ..brush
+                                });
+                            }
+                        }
+                    }
+                    ("editor", _) => editor = Some(EditorInfo::parse(value)?),
+                    _ => extra.push((key.to_string(), value.clone().into_owned())),
+                }
+            }
+            let entity = Self {
+                id,
+                keyvalues,
+                connections,
+                brushes,
+                hidden: false,
+                editor,
+                extra,
+            };
+            entity
+                .get("classname")
+                .ok_or(VmfError::MissingKey(String::from("classname"), kv.span()))?;
+            Ok(entity)
+        })()
+        .map_err(|e| VmfError::InEntity {
+            id,
+            source: Box::new(e),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let mut subkeys = vec![(String::from("id"), value_kv(self.id))];
+        for (key, value) in &self.keyvalues {
+            subkeys.push((key.clone(), value_kv(value)));
+        }
+        if !self.connections.is_empty() {
+            subkeys.push((
+                String::from("connections"),
+                KeyValues::new_list(
+                    self.connections
+                        .iter()
+                        .map(|connection| (connection.output.clone(), connection.to_keyvalues()))
+                        .collect(),
+                ),
+            ));
+        }
+        for brush in &self.brushes {
+            let (name, kv) = hidden_kv(brush.hidden, "solid", brush.to_keyvalues());
+            subkeys.push((name.to_string(), kv));
+        }
+        if let Some(editor) = &self.editor {
+            subkeys.push((String::from("editor"), editor.to_keyvalues()));
+        }
+        subkeys.extend(self.extra.iter().cloned());
+        KeyValues::new_list(subkeys)
+    }
+
+    /// Gets the first keyvalue with the specified name
+    /// Names are case insensitive
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.keyvalues
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn classname(&self) -> &str {
+        self.get("classname").unwrap_or_default()
+    }
+
+    pub fn origin(&self) -> Option<Vec3> {
+        keyvalue::parse_vec3(self.get("origin")?)
+    }
+
+    /// Pitch, yaw and roll in degrees
+    pub fn angles(&self) -> Option<Vec3> {
+        keyvalue::parse_vec3(self.get("angles")?)
+    }
+
+    pub fn is_brush_entity(&self) -> bool {
+        !self.brushes.is_empty()
+    }
+
+    /// Whether the entity isn't hidden or in a hidden visgroup, see EditorInfo::is_visible
+    pub fn is_visible(&self, hidden_visgroups: &HashSet<i32>) -> bool {
+        !self.hidden
+            && self
+                .editor
+                .as_ref()
+                .is_none_or(|editor| editor.is_visible(hidden_visgroups))
+    }
+}
+
+/// A single output of an entity
+/// In the VMF, these are stored in the "connections" block as "output" "target,input,parameter,delay,times_to_fire"
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub output: String,
+    pub target: String,
+    pub input: String,
+    pub parameter: String,
+    pub delay: f32,
+    /// -1 means infinite
+    pub times_to_fire: i32,
+    /// Newer versions of Hammer use the escape character instead of commas
+    pub separator: char,
+    /// Only the delay, the other parts are written as they are anyway
+    pub source: SourceText,
+}
+
+impl Connection {
+    fn parse(output: &str, value: &str, span: Span) -> Result<Self, VmfError> {
+        let error = || VmfError::InvalidConnection {
+            output: output.to_string(),
+            value: value.to_string(),
+            span,
+        };
+        let separator = if value.contains('\x1b') { '\x1b' } else { ',' };
+        let parts: Vec<&str> = value.split(separator).collect();
+        if parts.len() != 5 {
+            return Err(error());
+        }
+        Ok(Self {
+            output: output.to_string(),
+            target: parts[0].to_string(),
+            input: parts[1].to_string(),
+            parameter: parts[2].to_string(),
+            delay: parts[3].parse().map_err(|_| error())?,
+            times_to_fire: parts[4].parse().map_err(|_| error())?,
+            separator,
+            source: SourceText(HashMap::from([(
+                String::from("delay"),
+                parts[3].to_string(),
+            )])),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        value_kv(
+            [
+                self.target.clone(),
+                self.input.clone(),
+                self.parameter.clone(),
+                self.source.keep("delay", self.delay.to_string()),
+                self.times_to_fire.to_string(),
+            ]
+            .join(&self.separator.to_string()),
+        )
+    }
+}
+
+pub type BrushShape = Polyhedron<Face>;
+
+/// How textures follow a brush when it gets transformed, like the toggles in Hammer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextureLock {
+    /// Textures stay where they are in the world and slide over the brush
+    None,
+    /// Textures move and turn with the brush, but keep their scale
+    #[default]
+    Lock,
+    /// Textures also get stretched when the brush is scaled
+    ScalingLock,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum TransformError {
+    #[error(transparent)]
+    Polyhedron(#[from] PolyhedronError),
+    #[error("the matrix flattens the brush")]
+    NotInvertible,
+    #[error("displacements can't be mirrored")]
+    MirroredDisplacement,
+}
+
+/// Represents a Brush
+/// In the VMF, this is called a "solid"
+#[derive(Debug, Clone)]
+pub struct Brush {
+    pub id: i32,
+    /// The sides as they are in the file
+    /// Sides that don't touch the brush only exist here, not in the shape
+    pub faces: Vec<Face>,
+    pub shape: BrushShape,
+    /// Hidden in the editor, it is written inside a "hidden" block
+    pub hidden: bool,
+    pub editor: Option<EditorInfo>,
+    pub extra: Extra,
+}
+
+impl Brush {
+    fn parse(kv: &KeyValues) -> Result<Self, VmfError> {
+        let id = parse_value(kv, "id")?;
+        let mut faces = vec![];
+        let mut editor = None;
+        let mut extra = vec![];
+        let in_solid = |e| VmfError::InSolid {
+            id,
+            source: Box::new(e),
+        };
+        for (key, value) in kv.get_all_kv_pairs() {
+            match key.to_lowercase().as_str() {
+                "id" => {}
+                "side" => faces.push(Face::parse(value).map_err(in_solid)?),
+                "editor" => editor = Some(EditorInfo::parse(value).map_err(in_solid)?),
+                _ => extra.push((key.to_string(), value.clone().into_owned())),
+            }
+        }
+        Ok(Self {
+            id,
+            shape: get_polyhedron(&faces)
+                .map_err(|e| in_solid(VmfError::InvalidShape(e, kv.span())))?,
+            faces,
+            hidden: false,
+            editor,
+            extra,
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let mut subkeys = vec![(String::from("id"), value_kv(self.id))];
+        for face in &self.faces {
+            subkeys.push((String::from("side"), face.to_keyvalues()));
+        }
+        if let Some(editor) = &self.editor {
+            subkeys.push((String::from("editor"), editor.to_keyvalues()));
+        }
+        subkeys.extend(self.extra.iter().cloned());
+        KeyValues::new_list(subkeys)
+    }
+
+    /// Makes an axis aligned box brush, the sides get the ids first_side_id to first_side_id + 5
+    /// The textures are aligned to the world like Hammer does for new brushes
+    /// Fails if the box is flat or too big for the map
+    pub fn new_box(
+        id: i32,
+        first_side_id: i32,
+        mins: &Vec3,
+        maxs: &Vec3,
+        material: &str,
+    ) -> Result<Self, PolyhedronError> {
+        let mut faces = vec![];
+        for axis in 0..3 {
+            for positive in [false, true] {
+                // cross(a, b) has to point out of the brush, see Face::normal
+                let (mut a, mut b) = ((axis + 1) % 3, (axis + 2) % 3);
+                if !positive {
+                    (a, b) = (b, a);
+                }
+                let mut p1 = *mins;
+                if positive {
+                    p1[axis] = maxs[axis];
+                }
+                let mut p2 = p1;
+                p2[b] = maxs[b];
+                let mut p3 = p1;
+                p3[a] = maxs[a];
+                faces.push(Face::with_plane_points(
+                    first_side_id + faces.len() as i32,
+                    (p1, p2, p3),
+                    material,
+                ));
+            }
+        }
+        Self::from_faces(id, faces)
+    }
+
+    /// Makes a brush out of sides, the same way loaded brushes are built
+    pub fn from_faces(id: i32, faces: Vec<Face>) -> Result<Self, PolyhedronError> {
+        Ok(Self {
+            id,
+            shape: get_polyhedron(&faces)?,
+            faces,
+            hidden: false,
+            editor: None,
+            extra: vec![],
+        })
+    }
+
+    /// Gets the smallest and largest corner of the bounding box of the brush
+    /// Returns None if the brush has no volume
+    pub fn get_bounds(&self) -> Option<(Vec3, Vec3)> {
+        self.shape.get_bounds()
+    }
+
+    /// Whether the brush isn't hidden or in a hidden visgroup, see EditorInfo::is_visible
+    /// Brushes of an entity are also hidden if the entity is
+    pub fn is_visible(&self, hidden_visgroups: &HashSet<i32>) -> bool {
+        !self.hidden
+            && self
+                .editor
+                .as_ref()
+                .is_none_or(|editor| editor.is_visible(hidden_visgroups))
+    }
+
+    pub fn has_displacement(&self) -> bool {
+        self.shape
+            .faces
+            .iter()
+            .any(|(face, _)| face.dispinfo.is_some())
+    }
+
+    /// Moves, turns, scales or mirrors the brush, lock decides what happens to the textures
+    /// With TextureLock::Lock, the texture stays put at the center of the brush
+    /// The brush stays as it was if this fails
+    pub fn transform(
+        &mut self,
+        matrix: &glm::Mat4,
+        lock: TextureLock,
+    ) -> Result<(), TransformError> {
+        let matrix: glm::DMat4 = matrix.cast();
+        let determinant = glm::mat4_to_mat3(&matrix).determinant();
+        if determinant.abs() < 1e-9 {
+            return Err(TransformError::NotInvertible);
+        }
+        if determinant < 0.0 && self.has_displacement() {
+            return Err(TransformError::MirroredDisplacement);
+        }
+        let anchor = self.shape.get_centroid().unwrap_or_default().cast();
+        let mut faces = self.faces.clone();
+        for face in &mut faces {
+            face.transform(&matrix, lock, &anchor);
+        }
+        self.shape = get_polyhedron(&faces)?;
+        self.faces = faces;
+        Ok(())
+    }
+}
+
+/// Represents a Face of a Brush
+/// In the VMF, this is called a "side"
+#[derive(Debug, Clone)]
+pub struct Face {
+    pub id: i32,
+    plane: (glm::Vec3, glm::Vec3, glm::Vec3),
+    pub material: String,
+    pub uaxis: UVAxis,
+    pub vaxis: UVAxis,
+    lightmapscale: i32,
+    /// Bitmask, sides sharing a bit get smooth normals where they touch
+    pub smoothing_groups: i32,
+    pub dispinfo: Option<Dispinfo>,
+    /// e.g. "rotation"
+    pub extra: Extra,
+    source: SourceText,
+}
+
+impl Face {
+    fn parse(kv: &KeyValues) -> Result<Self, VmfError> {
+        let id = parse_value(kv, "id")?;
+        (|| {
+            let plane = parse_plane(get_value(kv, "plane")?, get_span(kv, "plane"))?;
+            Ok(Self {
+                id,
+                plane,
+                material: get_value(kv, "material")?.to_string(),
+                uaxis: UVAxis::parse(get_value(kv, "uaxis")?, get_span(kv, "uaxis"))?,
+                vaxis: UVAxis::parse(get_value(kv, "vaxis")?, get_span(kv, "vaxis"))?,
+                lightmapscale: parse_value(kv, "lightmapscale")?,
+                smoothing_groups: parse_value(kv, "smoothing_groups")?,
+                dispinfo: match kv.get("dispinfo") {
+                    Some(info) => Some(Dispinfo::parse(info)?),
+                    None => None,
+                },
+                extra: get_extra(
+                    kv,
+                    &[
+                        "id",
+                        "plane",
+                        "material",
+                        "uaxis",
+                        "vaxis",
+                        "lightmapscale",
+                        "smoothing_groups",
+                        "dispinfo",
+                    ],
+                ),
+                source: SourceText::new(kv, &["plane", "uaxis", "vaxis", "dispinfo"]),
+            })
+        })()
+        .map_err(|e| VmfError::InSide {
+            id,
+            source: Box::new(e),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let (p1, p2, p3) = &self.plane;
+        let mut subkeys = vec![
+            (String::from("id"), value_kv(self.id)),
+            (
+                String::from("plane"),
+                value_kv(format!(
+                    "({} {} {}) ({} {} {}) ({} {} {})",
+                    p1.x, p1.y, p1.z, p2.x, p2.y, p2.z, p3.x, p3.y, p3.z
+                )),
+            ),
+            (String::from("material"), value_kv(&self.material)),
+            (String::from("uaxis"), value_kv(self.uaxis.to_string())),
+            (String::from("vaxis"), value_kv(self.vaxis.to_string())),
+            (String::from("lightmapscale"), value_kv(self.lightmapscale)),
+            (
+                String::from("smoothing_groups"),
+                value_kv(self.smoothing_groups),
+            ),
+        ];
+        if let Some(dispinfo) = &self.dispinfo {
+            subkeys.push((String::from("dispinfo"), dispinfo.to_keyvalues()));
+        }
+        subkeys.extend(self.extra.iter().cloned());
+        sort_keys(&mut subkeys, &FACE_KEY_ORDER);
+        let mut kv = KeyValues::new_list(subkeys);
+        self.source.restore(&mut kv);
+        kv
+    }
+
+    /// Makes a side on the plane with the texture aligned to the world like Hammer does
+    /// The plane points get put close to near
+    pub fn new(id: i32, plane: &Plane, near: &glm::DVec3, material: &str) -> Self {
+        let [a, b, c] = plane.get_points(near);
+        Self::with_plane_points(id, (a.cast(), b.cast(), c.cast()), material)
+    }
+
+    /// Makes a side through three points, clockwise when looking at the front like in the VMF
+    /// The texture gets aligned to the world, returns None if the points are on a line
+    pub fn from_points(id: i32, points: (Vec3, Vec3, Vec3), material: &str) -> Option<Self> {
+        let face = Self::with_plane_points(id, points, material);
+        face.get_plane().is_some().then_some(face)
+    }
+
+    fn with_plane_points(id: i32, plane: (Vec3, Vec3, Vec3), material: &str) -> Self {
+        // Same as normal(), the length doesn't matter for picking the axes
+        let (uaxis, vaxis) =
+            UVAxis::world_aligned(&glm::cross(&(plane.2 - plane.0), &(plane.1 - plane.0)));
+        Self {
+            id,
+            plane,
+            material: material.to_string(),
+            uaxis,
+            vaxis,
+            lightmapscale: 16,
+            smoothing_groups: 0,
+            dispinfo: None,
+            extra: vec![(String::from("rotation"), value_kv(0))],
+            source: SourceText::default(),
+        }
+    }
+
+    /// Moves the side onto another plane, the plane points get put close to near
+    pub fn set_plane(&mut self, plane: &Plane, near: &glm::DVec3) {
+        let [a, b, c] = plane.get_points(near);
+        self.plane = (a.cast(), b.cast(), c.cast());
+    }
+
+    /// The plane of the side in double precision, None if its points are on a line
+    pub fn get_plane(&self) -> Option<Plane> {
+        Plane::from_points(
+            &self.plane.0.cast(),
+            &self.plane.1.cast(),
+            &self.plane.2.cast(),
+        )
+    }
+
+    /// The normal of the plane, pointing out of the brush
+    pub fn normal(&self) -> Vec3 {
+        glm::normalize(&glm::cross(
+            &(self.plane.2 - self.plane.0),
+            &(self.plane.1 - self.plane.0),
+        ))
+    }
+
+    /// Moves the plane, texture axes and displacement, see Brush::transform
+    /// anchor is the point that keeps its texture coordinates with TextureLock::Lock
+    /// The matrix has to be invertible
+    pub fn transform(&mut self, matrix: &glm::DMat4, lock: TextureLock, anchor: &glm::DVec3) {
+        let linear = glm::mat4_to_mat3(matrix);
+        let translation = matrix.column(3).xyz();
+        let point = |x: &Vec3| -> Vec3 { (linear * x.cast::<f64>() + translation).cast() };
+        let vector = |x: &Vec3| -> Vec3 { (linear * x.cast::<f64>()).cast() };
+
+        self.plane = (
+            point(&self.plane.0),
+            point(&self.plane.1),
+            point(&self.plane.2),
+        );
+        if linear.determinant() < 0.0 {
+            // Mirrored, the points have to go the other way around to keep the normal pointing out
+            std::mem::swap(&mut self.plane.1, &mut self.plane.2);
+        }
+
+        let inverse_transpose = linear
+            .try_inverse()
+            .unwrap_or_else(glm::DMat3::identity)
+            .transpose();
+        let new_anchor = linear * anchor + translation;
+        for axis in [&mut self.uaxis, &mut self.vaxis] {
+            // u = dot(point, gradient) + translation, see UVAxis::get_uv
+            let gradient = axis.dir.cast::<f64>() / axis.scaling as f64;
+            let new_gradient = match lock {
+                TextureLock::None => continue,
+                TextureLock::Lock => glm::normalize(&(linear * gradient)) * gradient.norm(),
+                // Keeps the uv of every point exactly
+                TextureLock::ScalingLock => inverse_transpose * gradient,
+            };
+            let uv = glm::dot(anchor, &gradient) + axis.translation as f64;
+            let sign = axis.scaling.signum() as f64;
+            axis.dir = (glm::normalize(&new_gradient) * sign).cast();
+            axis.scaling = (sign / new_gradient.norm()) as f32;
+            axis.translation = (uv - glm::dot(&new_anchor, &new_gradient)) as f32;
+        }
+
+        if let Some(dispinfo) = &mut self.dispinfo {
+            dispinfo.startpos = point(&dispinfo.startpos);
+            for (normal, distance) in dispinfo
+                .normals
+                .iter_mut()
+                .flatten()
+                .zip(dispinfo.distances.iter_mut().flatten())
+            {
+                let new_normal = vector(normal);
+                if new_normal.norm_squared() > 0.0 {
+                    *distance *= new_normal.norm();
+                    *normal = glm::normalize(&new_normal);
+                }
+            }
+            for offset in dispinfo.offsets.iter_mut().flatten().flatten() {
+                *offset = vector(offset);
+            }
+            for normal in dispinfo.offset_normals.iter_mut().flatten().flatten() {
+                let new_normal = vector(normal);
+                if new_normal.norm_squared() > 0.0 {
+                    *normal = glm::normalize(&new_normal);
+                }
+            }
+        }
+    }
+
+    /// Scales the texture so it repeats the given number of times across the side, then lines it up with the top left
+    /// polygon is the side from Brush::shape and texture_size the size of the base texture, see Material::get_texture_size
+    pub fn fit(&mut self, polygon: &[Vec3], texture_size: (u32, u32), repeat: (f32, f32)) {
+        let sizes = [texture_size.0 as f32, texture_size.1 as f32];
+        for ((axis, size), repeat) in [&mut self.uaxis, &mut self.vaxis]
+            .into_iter()
+            .zip(sizes)
+            .zip([repeat.0, repeat.1])
+        {
+            axis.normalize();
+            let Some((min, max)) = get_range(polygon, |point| glm::dot(point, &axis.dir)) else {
+                return;
+            };
+            if max - min > 1e-3 && repeat > 0.0 {
+                axis.scaling = (max - min) / (size * repeat) * axis.scaling.signum();
+            }
+        }
+        self.justify(Justify::Left, polygon, texture_size);
+        self.justify(Justify::Top, polygon, texture_size);
+    }
+
+    /// Shifts the texture so an edge of it lines up with the edge of the side, or centers it
+    /// The shift gets wrapped to the texture size like in Hammer
+    pub fn justify(&mut self, justify: Justify, polygon: &[Vec3], texture_size: (u32, u32)) {
+        let sizes = [texture_size.0 as f32, texture_size.1 as f32];
+        for ((axis, size), is_u) in [&mut self.uaxis, &mut self.vaxis]
+            .into_iter()
+            .zip(sizes)
+            .zip([true, false])
+        {
+            let Some((min, max)) = get_range(polygon, |point| axis.get_uv(point)) else {
+                return;
+            };
+            let shift = match justify {
+                Justify::Left if is_u => -min,
+                Justify::Right if is_u => -max,
+                Justify::Top if !is_u => -min,
+                Justify::Bottom if !is_u => -max,
+                Justify::Center => (size - min - max) / 2.0,
+                _ => continue,
+            };
+            axis.translation = (axis.translation + shift).rem_euclid(size.max(1.0));
+        }
+    }
+
+    /// Points the texture axes along the world axes closest to the side, keeping the scale and shift
+    pub fn align_to_world(&mut self) {
+        let (uaxis, vaxis) = UVAxis::world_aligned(&self.normal());
+        self.uaxis.normalize();
+        self.vaxis.normalize();
+        self.uaxis.dir = uaxis.dir;
+        self.vaxis.dir = vaxis.dir;
+        self.set_rotation(0.0);
+    }
+
+    /// Lays the texture axes onto the side, so it doesn't get stretched on slanted sides
+    /// The axes point as close to the world aligned ones as they can
+    pub fn align_to_face(&mut self) {
+        let normal = self.normal();
+        let (uaxis, vaxis) = UVAxis::world_aligned(&normal);
+        let udir = glm::normalize(&(uaxis.dir - normal * glm::dot(&uaxis.dir, &normal)));
+        let vdir = glm::cross(&normal, &udir);
+        self.uaxis.normalize();
+        self.vaxis.normalize();
+        self.uaxis.dir = udir;
+        // Keeps v going down the side, like the world aligned axis
+        self.vaxis.dir = vdir * glm::dot(&vdir, &vaxis.dir).signum();
+        self.set_rotation(0.0);
+    }
+
+    /// Turns the texture axes around the normal, counterclockwise when looking at the front
+    pub fn rotate(&mut self, degrees: f32) {
+        let normal = self.normal();
+        let angle = degrees.to_radians();
+        self.uaxis.dir = glm::rotate_vec3(&self.uaxis.dir, angle, &normal);
+        self.vaxis.dir = glm::rotate_vec3(&self.vaxis.dir, angle, &normal);
+        self.set_rotation(self.get_rotation() + degrees);
+    }
+
+    /// Moves the texture along its axes, in pixels
+    pub fn shift(&mut self, u: f32, v: f32) {
+        self.uaxis.translation += u;
+        self.vaxis.translation += v;
+    }
+
+    /// The "rotation" key Hammer shows in the face edit sheet, it doesn't change the texture itself
+    pub fn get_rotation(&self) -> f32 {
+        match self.extra.iter().find(|(key, _)| key == "rotation") {
+            Some((_, KeyValues::Value { value, .. })) => value.parse().unwrap_or(0.0),
+            _ => 0.0,
+        }
+    }
+
+    fn set_rotation(&mut self, degrees: f32) {
+        let value = value_kv(degrees.rem_euclid(360.0));
+        match self.extra.iter_mut().find(|(key, _)| key == "rotation") {
+            Some((_, kv)) => *kv = value,
+            None => self.extra.push((String::from("rotation"), value)),
+        }
+    }
+}
+
+/// Which part of the side the texture gets lined up with, see Face::justify
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Justify {
+    Left,
+    Right,
+    Top,
+    Bottom,
+    Center,
+}
+
+#[derive(Debug, Clone)]
+pub struct UVAxis {
+    pub dir: Vec3,
+    pub translation: f32,
+    pub scaling: f32,
+}
+
+impl UVAxis {
+    /// The axes Hammer gives a side with this normal when aligning the texture to the world
+    /// Picks the closest axis, ties go to z, then x, then y
+    pub fn world_aligned(normal: &Vec3) -> (Self, Self) {
+        let n = normal.abs();
+        let (udir, vdir) = if n.z >= n.x && n.z >= n.y {
+            (glm::vec3(1.0, 0.0, 0.0), glm::vec3(0.0, -1.0, 0.0))
+        } else if n.x >= n.y {
+            (glm::vec3(0.0, 1.0, 0.0), glm::vec3(0.0, 0.0, -1.0))
+        } else {
+            (glm::vec3(1.0, 0.0, 0.0), glm::vec3(0.0, 0.0, -1.0))
+        };
+        let axis = |dir| Self {
+            dir,
+            translation: 0.0,
+            scaling: 0.25,
+        };
+        (axis(udir), axis(vdir))
+    }
+
+    fn parse(input: &str, span: Span) -> Result<Self, VmfError> {
+        let mut x: f32 = 0.0;
+        let mut y: f32 = 0.0;
+        let mut z: f32 = 0.0;
+        let mut translation: f32 = 0.0;
+        let mut scaling: f32 = 0.0;
+        sscanf!(input, "[{} {} {} {}] {}", x, y, z, translation, scaling)
+            .map_err(|_| VmfError::InvalidUVAxis(input.to_string(), span))?;
+        // Kept as it is in the file, the axis doesn't have to be normalized (see get_uv)
+        Ok(Self {
+            dir: glm::vec3(x, y, z),
+            translation,
+            scaling,
+        })
+    }
+
+    /// The texture coordinate of a point along this axis, in pixels
+    /// Like in the engine, a longer axis makes the texture smaller
+    pub fn get_uv(&self, point: &Vec3) -> f32 {
+        glm::dot(point, &self.dir) / self.scaling + self.translation
+    }
+
+    /// Makes the axis a unit vector, its length moves into the scaling so the texture stays the same
+    pub fn normalize(&mut self) {
+        let length = self.dir.norm();
+        if length > 0.0 {
+            self.dir /= length;
+            self.scaling /= length;
+        }
+    }
+}
+
+impl std::fmt::Display for UVAxis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[{} {} {} {}] {}",
+            self.dir.x, self.dir.y, self.dir.z, self.translation, self.scaling
+        )
+    }
+}
+
+/// The displacement data of a face
+/// normals and distances are stored like they are in the file, use get_normalized() before using them for geometry
+#[derive(Debug, Clone)]
+pub struct Dispinfo {
+    pub power: u8,
+    pub startpos: glm::Vec3,
+    /// Collision flags, see DISPINFO_FLAG_NO_PHYSICS and friends, older files don't have them
+    pub flags: Option<i32>,
+    pub elevation: f32,
+    pub subdiv: bool,
+    pub normals: Vec<Vec<glm::Vec3>>,
+    pub distances: Vec<Vec<f32>>,
+    /// Older files don't have them, which is the same as all of them being zero
+    pub offsets: Option<Vec<Vec<glm::Vec3>>>,
+    /// The face normal at each vertex, used by Hammer when the displacement gets sewn
+    pub offset_normals: Option<Vec<Vec<glm::Vec3>>>,
+    // Why can these be decimal values????
+    pub alphas: Vec<Vec<f32>>,
+    /// Only written by games with multiblend materials (e.g. CS:GO)
+    pub multiblend: Option<Multiblend>,
+    /// One row for each row of quads, with two triangles per quad
+    pub triangle_tags: Option<Vec<Vec<TriangleTag>>>,
+    /// Bits of the vertices that may be removed when the displacement gets simplified, -1 means all
+    pub allowed_verts: Option<Vec<i32>>,
+    pub extra: Extra,
+}
+
+pub const DISPINFO_FLAG_NO_PHYSICS: i32 = 1 << 1;
+pub const DISPINFO_FLAG_NO_HULL: i32 = 1 << 2;
+pub const DISPINFO_FLAG_NO_RAY: i32 = 1 << 3;
+
+/// The blend data of multiblend materials, every vertex has 4 weights and a color per layer
+#[derive(Debug, Clone)]
+pub struct Multiblend {
+    pub multiblend: Vec<Vec<glm::Vec4>>,
+    pub alphablend: Vec<Vec<glm::Vec4>>,
+    pub colors: [Vec<Vec<glm::Vec3>>; 4],
+}
+
+/// The flags Hammer keeps for every displacement triangle
+/// Walkable and buildable get worked out from the slope unless they are forced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TriangleTag(pub u8);
+
+impl TriangleTag {
+    pub const SURFACE: u8 = 1 << 0;
+    pub const WALKABLE: u8 = 1 << 1;
+    pub const FORCE_WALKABLE_BIT: u8 = 1 << 2;
+    pub const FORCE_WALKABLE_VAL: u8 = 1 << 3;
+    pub const BUILDABLE: u8 = 1 << 4;
+    pub const FORCE_BUILDABLE_BIT: u8 = 1 << 5;
+    pub const FORCE_BUILDABLE_VAL: u8 = 1 << 6;
+    pub const FORCE_REMOVE_BIT: u8 = 1 << 7;
+
+    /// The steepest triangles players can still walk on, about 45 degrees
+    const WALKABLE_NORMAL_Z: f32 = 0.7;
+    /// The steepest triangles that can be built on, about 36 degrees
+    const BUILDABLE_NORMAL_Z: f32 = 0.8;
+
+    fn has(self, flag: u8) -> bool {
+        self.0 & flag != 0
+    }
+
+    /// Whether players can walk on the triangle with the given normal
+    pub fn is_walkable(self, normal: &Vec3) -> bool {
+        match self.has(Self::FORCE_WALKABLE_BIT) {
+            true => self.has(Self::FORCE_WALKABLE_VAL),
+            false => normal.z >= Self::WALKABLE_NORMAL_Z,
+        }
+    }
+
+    /// Whether buildings (e.g. from TF2) can be placed on the triangle with the given normal
+    pub fn is_buildable(self, normal: &Vec3) -> bool {
+        match self.has(Self::FORCE_BUILDABLE_BIT) {
+            true => self.has(Self::FORCE_BUILDABLE_VAL),
+            false => normal.z >= Self::BUILDABLE_NORMAL_Z,
+        }
+    }
+}
+
+impl Default for TriangleTag {
+    fn default() -> Self {
+        Self(Self::SURFACE)
+    }
+}
+
+impl FromStr for TriangleTag {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+impl std::fmt::Display for TriangleTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Dispinfo {
+    fn parse(kv: &KeyValues) -> Result<Self, VmfError> {
+        let power = parse_value::<u8>(kv, "power")?;
+        if !(2..=4).contains(&power) {
+            return Err(VmfError::InvalidValue {
+                key: String::from("power"),
+                value: power.to_string(),
+                span: get_span(kv, "power"),
+            });
+        }
+        let startpos = {
+            let value = get_value(kv, "startposition")?;
+            keyvalue::parse_bracketed_vec3(value).ok_or_else(|| VmfError::InvalidValue {
+                key: String::from("startposition"),
+                value: value.to_string(),
+                span: get_span(kv, "startposition"),
+            })?
+        };
+        let size = (1 << power) + 1;
+        let multiblend = match kv.get("multiblend") {
+            Some(multiblend) => {
+                let color = |i: usize| {
+                    let key = format!("multiblend_color_{}", i);
+                    get_dispdata_3(power, get_block(kv, &key)?, &key)
+                };
+                Some(Multiblend {
+                    multiblend: get_dispdata_4(power, multiblend, "multiblend")?,
+                    alphablend: get_dispdata_4(power, get_block(kv, "alphablend")?, "alphablend")?,
+                    colors: [color(0)?, color(1)?, color(2)?, color(3)?],
+                })
+            }
+            None => None,
+        };
+        let allowed_verts = match kv.get("allowed_verts") {
+            Some(allowed_verts) => {
+                let count = ALLOWED_VERTS_SIZE.to_string();
+                Some(
+                    get_rows(allowed_verts, "allowed_verts", &[count], ALLOWED_VERTS_SIZE)?
+                        .remove(0),
+                )
+            }
+            None => None,
+        };
+        Ok(Self {
+            power,
+            startpos,
+            flags: match kv.get("flags") {
+                Some(_) => Some(parse_value(kv, "flags")?),
+                None => None,
+            },
+            elevation: parse_value(kv, "elevation")?,
+            subdiv: get_value(kv, "subdiv")? != "0",
+            normals: get_dispdata_3(power, get_block(kv, "normals")?, "normals")?,
+            distances: get_dispdata_1(power, get_block(kv, "distances")?, "distances")?,
+            offsets: match kv.get("offsets") {
+                Some(offsets) => Some(get_dispdata_3(power, offsets, "offsets")?),
+                None => None,
+            },
+            offset_normals: match kv.get("offset_normals") {
+                Some(normals) => Some(get_dispdata_3(power, normals, "offset_normals")?),
+                None => None,
+            },
+            alphas: get_dispdata_1(power, get_block(kv, "alphas")?, "alphas")?,
+            multiblend,
+            triangle_tags: match kv.get("triangle_tags") {
+                Some(tags) => Some(get_rows(
+                    tags,
+                    "triangle_tags",
+                    &row_names(size - 1),
+                    (size - 1) * 2,
+                )?),
+                None => None,
+            },
+            allowed_verts,
+            extra: get_extra(kv, &DISPINFO_KEY_ORDER),
+        })
+    }
+
+    fn to_keyvalues(&self) -> KeyValues<'static> {
+        let mut subkeys = vec![
+            (String::from("power"), value_kv(self.power)),
+            (
+                String::from("startposition"),
+                value_kv(format!(
+                    "[{} {} {}]",
+                    self.startpos.x, self.startpos.y, self.startpos.z
+                )),
+            ),
+        ];
+        if let Some(flags) = self.flags {
+            subkeys.push((String::from("flags"), value_kv(flags)));
+        }
+        subkeys.extend([
+            (String::from("elevation"), value_kv(self.elevation)),
+            (String::from("subdiv"), value_kv(self.subdiv as i32)),
+            (String::from("normals"), dispdata_3_kv(&self.normals)),
+            (String::from("distances"), dispdata_1_kv(&self.distances)),
+        ]);
+        if let Some(offsets) = &self.offsets {
+            subkeys.push((String::from("offsets"), dispdata_3_kv(offsets)));
+        }
+        subkeys.push((String::from("alphas"), dispdata_1_kv(&self.alphas)));
+        if let Some(offset_normals) = &self.offset_normals {
+            subkeys.push((
+                String::from("offset_normals"),
+                dispdata_3_kv(offset_normals),
+            ));
+        }
+        if let Some(multiblend) = &self.multiblend {
+            let vec4_string = |v: &glm::Vec4| format!("{} {} {} {}", v.x, v.y, v.z, v.w);
+            subkeys.push((
+                String::from("multiblend"),
+                rows_kv(&multiblend.multiblend, vec4_string),
+            ));
+            subkeys.push((
+                String::from("alphablend"),
+                rows_kv(&multiblend.alphablend, vec4_string),
+            ));
+            for (i, color) in multiblend.colors.iter().enumerate() {
+                subkeys.push((format!("multiblend_color_{}", i), dispdata_3_kv(color)));
+            }
+        }
+        if let Some(triangle_tags) = &self.triangle_tags {
+            subkeys.push((
+                String::from("triangle_tags"),
+                rows_kv(triangle_tags, ToString::to_string),
+            ));
         }
+        if let Some(allowed_verts) = &self.allowed_verts {
+            subkeys.push((
+                String::from("allowed_verts"),
+                KeyValues::new_list(vec![(
+                    allowed_verts.len().to_string(),
+                    value_kv(join(allowed_verts, ToString::to_string)),
+                )]),
+            ));
+        }
+        subkeys.extend(self.extra.iter().cloned());
+        sort_keys(&mut subkeys, &DISPINFO_KEY_ORDER);
+        KeyValues::new_list(subkeys)
+    }
+
+    /// Gets normals and distances, so that every normal has a length of 1 and points out of the face
+    /// Offsets along the normals stay the same
+    pub fn get_normalized(&self, face_normal: &glm::Vec3) -> (Vec<Vec<glm::Vec3>>, Vec<Vec<f32>>) {
+        let mut normals = self.normals.clone();
+        let mut distances = self.distances.clone();
+        normalize_normals(self.power, &mut normals, &mut distances, face_normal);
+        (normals, distances)
+    }
 
-        Some(Self { worldbrushes })
+    /// Gets the tag of one of the two triangles of the quad at row and column
+    /// Displacements without triangle_tags count as plain surface
+    pub fn get_triangle_tag(&self, row: usize, column: usize, second: bool) -> TriangleTag {
+        self.triangle_tags
+            .as_ref()
+            .and_then(|tags| tags.get(row)?.get(column * 2 + second as usize))
+            .copied()
+            .unwrap_or_default()
     }
 }
 
-pub type BrushShape = Polyhedron<Option<Face>>;
+/// The text values had in the file, keyed by their path in the block (e.g. "dispinfo#0/normals#0/row3#0")
+/// Rust writes floats differently than Hammer (e.g. 4.37114e-08), so values that still mean the same get written with their old text
+#[derive(Debug, Clone, Default)]
+pub struct SourceText(HashMap<String, String>);
 
-/// Represents a Brush
-/// In the VMF, this is called a "solid"
-#[derive(Debug)]
-pub struct Brush {
-    id: i32,
-    pub shape: BrushShape,
-}
+impl SourceText {
+    /// Remembers the values with numbers in them below the given keys of the block
+    fn new(kv: &KeyValues, keys: &[&str]) -> Self {
+        let mut values = HashMap::new();
+        walk_values(kv, "", &mut |path, value| {
+            let in_keys = keys.iter().any(|key| path.starts_with(&format!("{key}#")));
+            if in_keys && value.bytes().any(|x| x.is_ascii_digit()) {
+                values.insert(path, value.to_string());
+            }
+        });
+        Self(values)
+    }
 
-impl Brush {
-    fn parse(kv: &KeyValues) -> Option<Self> {
-        let mut faces = vec![];
-        for side in kv.get_all("side") {
-            faces.push(Face::parse(side)?);
+    /// The old text of the value at path if it still means the same as new, otherwise new
+    fn keep(&self, path: &str, new: String) -> String {
+        match self.0.get(path) {
+            Some(old) if is_same_value(old, &new) => old.clone(),
+            _ => new,
         }
-        Some(Self {
-            id: kv.get("id")?.get_value()?.parse().ok()?,
-            shape: get_polyhedron(faces),
-        })
     }
 
-    pub fn has_displacement(&self) -> bool {
-        for (face, _) in &self.shape.faces {
-            if let Some(face) = face {
-                if face.dispinfo.is_some() {
-                    return true;
+    /// Puts the old text back into every value of the block that still means the same
+    fn restore(&self, kv: &mut KeyValues<'static>) {
+        if !self.0.is_empty() {
+            self.restore_list(kv, "");
+        }
+    }
+
+    fn restore_list(&self, kv: &mut KeyValues<'static>, prefix: &str) {
+        let KeyValues::List { subkeys, .. } = kv else {
+            return;
+        };
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for (key, value) in subkeys {
+            let count = counts.entry(key.to_string()).or_default();
+            let path = format!("{prefix}{key}#{count}");
+            *count += 1;
+            match value {
+                KeyValues::Value { value, .. } => {
+                    *value = Cow::Owned(self.keep(&path, value.to_string()));
                 }
+                KeyValues::List { .. } => self.restore_list(value, &format!("{path}/")),
             }
         }
-        false
     }
 }
 
-/// Represents a Face of a Brush
-/// In the VMF, this is called a "side"
-#[derive(Debug, Clone)]
-pub struct Face {
-    pub id: i32,
-    plane: (glm::Vec3, glm::Vec3, glm::Vec3),
-    pub material: String,
-    pub uaxis: UVAxis,
-    pub vaxis: UVAxis,
-    lightmapscale: i32,
-    smoothing_groups: i32,
-    pub dispinfo: Option<Dispinfo>,
+/// Calls f with the path of every value below the block, see SourceText
+fn walk_values(kv: &KeyValues, prefix: &str, f: &mut impl FnMut(String, &str)) {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for (key, value) in kv.get_all_kv_pairs() {
+        let count = counts.entry(key).or_default();
+        let path = format!("{prefix}{key}#{count}");
+        *count += 1;
+        match value {
+            KeyValues::Value { value, .. } => f(path, value),
+            KeyValues::List { .. } => walk_values(value, &format!("{path}/"), f),
+        }
+    }
 }
 
-impl Face {
-    fn parse(kv: &KeyValues) -> Option<Self> {
-        //println!("{}", kv.get("id")?.get_value()?);
-        let mut x1: f32 = 0.0;
-        let mut y1: f32 = 0.0;
-        let mut z1: f32 = 0.0;
-        let mut x2: f32 = 0.0;
-        let mut y2: f32 = 0.0;
-        let mut z2: f32 = 0.0;
-        let mut x3: f32 = 0.0;
-        let mut y3: f32 = 0.0;
-        let mut z3: f32 = 0.0;
-        sscanf!(
-            kv.get("plane")?.get_value()?,
-            "({} {} {}) ({} {} {}) ({} {} {})",
-            x1,
-            y1,
-            z1,
-            x2,
-            y2,
-            z2,
-            x3,
-            y3,
-            z3
-        )
-        .ok()?;
-        let plane = (
-            glm::vec3(x1, y1, z1),
-            glm::vec3(x2, y2, z2),
-            glm::vec3(x3, y3, z3),
-        );
-        Some(Self {
-            id: kv.get("id")?.get_value()?.parse().ok()?,
-            plane,
-            material: kv.get("material")?.get_value()?.to_string(),
-            uaxis: UVAxis::parse(kv.get("uaxis")?.get_value()?)?,
-            vaxis: UVAxis::parse(kv.get("vaxis")?.get_value()?)?,
-            lightmapscale: kv.get("lightmapscale")?.get_value()?.parse().ok()?,
-            smoothing_groups: kv.get("smoothing_groups")?.get_value()?.parse().ok()?,
-            dispinfo: match kv.get("dispinfo") {
-                Some(info) => Some(Dispinfo::parse(
-                    info,
-                    &glm::normalize(&glm::cross(&(plane.2 - plane.0), &(plane.1 - plane.0))),
-                )?),
-                None => None,
-            },
+/// Whether the values only differ in how their numbers are written, e.g. "[1 0 -4.37114e-08]" and "[1 0 -0.0000000437114]"
+fn is_same_value(a: &str, b: &str) -> bool {
+    let is_separator = |x: char| x.is_whitespace() || "[]()".contains(x);
+    let separators = |x: &str| x.chars().filter(|&x| is_separator(x)).collect::<String>();
+    separators(a) == separators(b)
+        && a.split(is_separator)
+            .zip(b.split(is_separator))
+            .all(|(a, b)| {
+                a == b
+                    || matches!((a.parse::<f32>(), b.parse::<f32>()),
+                    (Ok(a), Ok(b)) if a.to_bits() == b.to_bits())
+            })
+}
+
+fn get_polyhedron(faces: &[Face]) -> Result<BrushShape, PolyhedronError> {
+    let planes: Vec<_> = faces
+        .iter()
+        .enumerate()
+        .map(|(i, face)| {
+            let plane = face.get_plane().ok_or(PolyhedronError::InvalidPlane(i))?;
+            Ok((plane, face.clone()))
         })
+        .collect::<Result<_, _>>()?;
+    math::polyhedron_from_planes(&planes, MAX_BRUSH_EXTENT, &Epsilons::default())
+}
+
+/// In lenient mode, errors get added to the warnings instead of being returned
+fn skip_if_lenient<T>(
+    result: Result<T, VmfError>,
+    lenient: bool,
+    warnings: &mut Vec<VmfError>,
+) -> Result<Option<T>, VmfError> {
+    match result {
+        Ok(x) => Ok(Some(x)),
+        Err(e) if lenient => {
+            warnings.push(e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct UVAxis {
-    pub dir: Vec3,
-    pub translation: f32,
-    pub scaling: f32,
+fn get_block<'a, 'b>(kv: &'a KeyValues<'b>, key: &str) -> Result<&'a KeyValues<'b>, VmfError> {
+    kv.get(key)
+        .ok_or_else(|| VmfError::MissingKey(key.to_string(), kv.span()))
 }
 
-impl UVAxis {
-    fn parse(input: &str) -> Option<Self> {
-        let mut x = 0.0;
-        let mut y = 0.0;
-        let mut z = 0.0;
-        let mut translation = 0.0;
-        let mut scaling = 0.0;
-        sscanf!(input, "[{} {} {} {}] {}", x, y, z, translation, scaling).ok()?;
-        let dir = glm::vec3(x, y, z);
-        if f32::abs(dir.norm_squared() - 1.0) > 0.1 {
-            eprintln!("UVaxis isn't normalized");
-            // TODO: handle this properly instead of just giving an error message
-        }
-        Some(Self {
-            dir,
-            translation,
-            scaling,
-        })
+fn get_value<'a>(kv: &'a KeyValues, key: &str) -> Result<&'a str, VmfError> {
+    match kv.get(key) {
+        Some(KeyValues::Value { value, .. }) => Ok(value),
+        _ => Err(VmfError::MissingKey(key.to_string(), kv.span())),
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Dispinfo {
-    pub power: u8,
-    pub startpos: glm::Vec3,
-    pub elevation: f32,
-    pub subdiv: bool,
-    pub normals: Vec<Vec<glm::Vec3>>,
-    pub distances: Vec<Vec<f32>>,
-    pub offsets: Vec<Vec<glm::Vec3>>,
-    // pub offset_normals: Vec<Vec<glm::Vec3>>,
-    pub alphas: Vec<Vec<u8>>,
+/// The smallest and largest value of f over the points, None if there are none
+fn get_range(points: &[Vec3], f: impl Fn(&Vec3) -> f32) -> Option<(f32, f32)> {
+    points.iter().map(f).fold(None, |range, x| match range {
+        Some((min, max)) => Some((f32::min(min, x), f32::max(max, x))),
+        None => Some((x, x)),
+    })
 }
 
-impl Dispinfo {
-    fn parse(kv: &KeyValues, face_normal: &glm::Vec3) -> Option<Self> {
-        let power = kv.get("power")?.get_value()?.parse::<u8>().ok()?;
-        let startpos = {
-            let mut x = 0.0;
-            let mut y = 0.0;
-            let mut z = 0.0;
-            sscanf!(kv.get("startposition")?.get_value()?, "[{} {} {}]", x, y, z).ok()?;
-            glm::vec3(x, y, z)
-        };
-        let mut normals = get_dispdata_3(power, kv.get("normals")?)?;
-        let mut distances = vec![];
-        for row in 0..((1 << power) + 1) {
-            let mut row_data = vec![];
-            let mut values: Vec<f32> = vec![];
-            for value in kv
-                .get("distances")?
-                .get(&format!("row{}", row))?
-                .get_value()?
-                .split(' ')
-            {
-                values.push(value.parse().ok()?);
-            }
-            for column in 0..((1 << power) + 1) {
-                row_data.push(values[column as usize]);
-            }
-            distances.push(row_data);
-        }
-        let offsets =
-            (|| -> Option<Vec<Vec<glm::Vec3>>> { get_dispdata_3(power, kv.get("offsets")?) })()
-                .unwrap_or(vec![
-                    vec![glm::vec3(0.0, 0.0, 0.0); (1 << power) + 1];
-                    (1 << power) + 1
-                ]);
-        let mut alphas = vec![];
-        for row in 0..((1 << power) + 1) {
-            let mut row_data = vec![];
-            let mut values: Vec<u8> = vec![];
-            for value in kv
-                .get("alphas")?
-                .get(&format!("row{}", row))?
-                .get_value()?
-                .split(' ')
-            {
-                // Why can these be decimal values????
-                values.push(value.parse::<f32>().ok()? as u8);
-            }
-            for column in 0..((1 << power) + 1) {
-                row_data.push(values[column as usize]);
-            }
-            alphas.push(row_data);
-        }
-        normalize_normals(power, &mut normals, &mut distances, face_normal);
-        Some(Self {
-            power,
-            startpos,
-            elevation: kv.get("elevation")?.get_value()?.parse().ok()?,
-            subdiv: kv.get("subdiv")?.get_value()? != "0",
-            normals,
-            distances,
-            offsets,
-            // offset_normals: get_dispdata_3(power, kv.get("offset_normals")?)?,
-            alphas,
-        })
+/// Gets the position of a key, so errors can point to it
+fn get_span(kv: &KeyValues, key: &str) -> Span {
+    kv.get(key).map(|x| x.span()).unwrap_or(kv.span())
+}
+
+fn parse_value<T: FromStr>(kv: &KeyValues, key: &str) -> Result<T, VmfError> {
+    let value = get_value(kv, key)?;
+    value.parse().map_err(|_| VmfError::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+        span: get_span(kv, key),
+    })
+}
+
+/// Parses the three points of a plane, they may not lie on a line
+fn parse_plane(input: &str, span: Span) -> Result<(Vec3, Vec3, Vec3), VmfError> {
+    let [a, b, c] = keyvalue::parse_plane_points(input)
+        .ok_or_else(|| VmfError::InvalidPlane(input.to_string(), span))?;
+    let plane = (a, b, c);
+    if glm::cross(&(plane.2 - plane.0), &(plane.1 - plane.0)).norm_squared() == 0.0 {
+        return Err(VmfError::InvalidPlane(input.to_string(), span));
     }
+    Ok(plane)
 }
 
-fn get_polyhedron(faces: Vec<Face>) -> BrushShape {
-    let mut poly = Polyhedron {
-        vertices: vec![
-            glm::vec3(-MAX_MAP_EXTENT, -MAX_MAP_EXTENT, MAX_MAP_EXTENT),
-            glm::vec3(-MAX_MAP_EXTENT, MAX_MAP_EXTENT, MAX_MAP_EXTENT),
-            glm::vec3(-MAX_MAP_EXTENT, -MAX_MAP_EXTENT, -MAX_MAP_EXTENT),
-            glm::vec3(-MAX_MAP_EXTENT, MAX_MAP_EXTENT, -MAX_MAP_EXTENT),
-            glm::vec3(MAX_MAP_EXTENT, -MAX_MAP_EXTENT, MAX_MAP_EXTENT),
-            glm::vec3(MAX_MAP_EXTENT, MAX_MAP_EXTENT, MAX_MAP_EXTENT),
-            glm::vec3(MAX_MAP_EXTENT, -MAX_MAP_EXTENT, -MAX_MAP_EXTENT),
-            glm::vec3(MAX_MAP_EXTENT, MAX_MAP_EXTENT, -MAX_MAP_EXTENT),
-        ],
-        faces: vec![
-            (None, vec![0, 1, 3, 2]),
-            (None, vec![2, 3, 7, 6]),
-            (None, vec![6, 7, 5, 4]),
-            (None, vec![4, 5, 1, 0]),
-            (None, vec![2, 6, 4, 0]),
-            (None, vec![7, 3, 1, 5]),
-        ],
-    };
-    for face in faces {
-        let normal = glm::normalize(&glm::cross(
-            &(face.plane.2 - face.plane.0),
-            &(face.plane.1 - face.plane.0),
-        ));
-        math::clip_polyhedron_to_plane(&mut poly, &face.plane.0, &normal, Some(face.clone()));
+/// Gets every key that isn't in known_keys
+fn get_extra(kv: &KeyValues, known_keys: &[&str]) -> Extra {
+    kv.get_all_kv_pairs()
+        .into_iter()
+        .filter(|(key, _)| !known_keys.iter().any(|x| key.eq_ignore_ascii_case(x)))
+        .map(|(key, value)| (key.to_string(), value.clone().into_owned()))
+        .collect()
+}
+
+/// Sorts keys into the order Hammer uses. Unknown keys stay at the end
+fn sort_keys(subkeys: &mut [(String, KeyValues)], order: &[&str]) {
+    subkeys.sort_by_key(|(key, _)| {
+        order
+            .iter()
+            .position(|x| key.eq_ignore_ascii_case(x))
+            .unwrap_or(order.len())
+    });
+}
+
+/// Parses a color written as "r g b"
+fn parse_color(kv: &KeyValues, key: &str) -> Result<[u8; 3], VmfError> {
+    let value = get_value(kv, key)?;
+    let [r, g, b, _] = keyvalue::parse_color(value).ok_or_else(|| VmfError::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+        span: get_span(kv, key),
+    })?;
+    Ok([r, g, b])
+}
+
+/// Parses a point written as "(x y z)"
+fn parse_point(kv: &KeyValues, key: &str) -> Result<Vec3, VmfError> {
+    let value = get_value(kv, key)?;
+    value
+        .trim()
+        .strip_prefix('(')
+        .and_then(|x| x.strip_suffix(')'))
+        .and_then(keyvalue::parse_vec3)
+        .ok_or_else(|| VmfError::InvalidValue {
+            key: key.to_string(),
+            value: value.to_string(),
+            span: get_span(kv, key),
+        })
+}
+
+/// Parses a vector written as "[x y z]"
+fn parse_bracketed_vec3(kv: &KeyValues, key: &str) -> Result<Vec3, VmfError> {
+    let value = get_value(kv, key)?;
+    keyvalue::parse_bracketed_vec3(value).ok_or_else(|| VmfError::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+        span: get_span(kv, key),
+    })
+}
+
+fn vec3_string(vec: &Vec3) -> String {
+    format!("[{} {} {}]", vec.x, vec.y, vec.z)
+}
+
+fn color_string(color: &[u8; 3]) -> String {
+    format!("{} {} {}", color[0], color[1], color[2])
+}
+
+fn value_kv(value: impl ToString) -> KeyValues<'static> {
+    KeyValues::new_value(value.to_string())
+}
+
+/// Hammer writes block names without quotes and indents with tabs
+fn get_vmf_string(name: &str, kv: &KeyValues, indent: usize) -> String {
+    let indent_str = "\t".repeat(indent);
+    match kv {
+        KeyValues::Value { value, .. } => format!(
+            "{indent_str}\"{}\" \"{}\"\r\n",
+            escape_token(name),
+            escape_token(value)
+        ),
+        KeyValues::List { subkeys, .. } => format!(
+            "{indent_str}{name}\r\n{indent_str}{{\r\n{}{indent_str}}}\r\n",
+            subkeys
+                .iter()
+                .map(|(name, value)| get_vmf_string(name, value, indent + 1))
+                .collect::<String>()
+        ),
     }
-    poly
 }
 
-fn get_dispdata_3(power: u8, kv: &KeyValues) -> Option<Vec<Vec<glm::Vec3>>> {
+/// How many values "allowed_verts" has, it is a bitfield of the vertices of a power 4 displacement
+const ALLOWED_VERTS_SIZE: usize = 10;
+
+fn row_names(rows: usize) -> Vec<String> {
+    (0..rows).map(|row| format!("row{}", row)).collect()
+}
+
+/// Parses a block of rows, each with the same number of values
+fn get_rows<T: FromStr>(
+    kv: &KeyValues,
+    key: &str,
+    row_names: &[String],
+    columns: usize,
+) -> Result<Vec<Vec<T>>, VmfError> {
+    let found = kv.get_all_kv_pairs().len();
+    if found != row_names.len() {
+        return Err(VmfError::WrongRowCount {
+            key: key.to_string(),
+            expected: row_names.len(),
+            found,
+            span: kv.span(),
+        });
+    }
     let mut data = vec![];
-    for row in 0..((1 << power) + 1) {
-        let mut row_data = vec![];
-        let mut values: Vec<f32> = vec![];
-        for value in kv.get(&format!("row{}", row))?.get_value()?.split(' ') {
-            values.push(value.parse().ok()?);
-        }
-        for column in 0..((1 << power) + 1) {
-            row_data.push(glm::vec3(
-                values[(column * 3) as usize],
-                values[(column * 3 + 1) as usize],
-                values[(column * 3 + 2) as usize],
-            ));
+    for (row, row_name) in row_names.iter().enumerate() {
+        let row_value = get_value(kv, row_name)?;
+        let mut values = vec![];
+        for value in row_value.split_whitespace() {
+            values.push(value.parse().map_err(|_| VmfError::InvalidValue {
+                key: format!("{}/{}", key, row_name),
+                value: row_value.to_string(),
+                span: get_span(kv, row_name),
+            })?);
+        }
+        if values.len() != columns {
+            return Err(VmfError::WrongRowLength {
+                key: key.to_string(),
+                row,
+                expected: columns,
+                found: values.len(),
+                span: get_span(kv, row_name),
+            });
         }
-        data.push(row_data);
+        data.push(values);
+    }
+    Ok(data)
+}
+
+fn get_dispdata(
+    power: u8,
+    kv: &KeyValues,
+    key: &str,
+    components: usize,
+) -> Result<Vec<Vec<f32>>, VmfError> {
+    let size = (1 << power) + 1;
+    get_rows(kv, key, &row_names(size), size * components)
+}
+
+fn get_dispdata_1(power: u8, kv: &KeyValues, key: &str) -> Result<Vec<Vec<f32>>, VmfError> {
+    get_dispdata(power, kv, key, 1)
+}
+
+fn get_dispdata_3(power: u8, kv: &KeyValues, key: &str) -> Result<Vec<Vec<glm::Vec3>>, VmfError> {
+    Ok(get_dispdata(power, kv, key, 3)?
+        .iter()
+        .map(|values| {
+            values
+                .chunks(3)
+                .map(|v| glm::vec3(v[0], v[1], v[2]))
+                .collect()
+        })
+        .collect())
+}
+
+fn get_dispdata_4(power: u8, kv: &KeyValues, key: &str) -> Result<Vec<Vec<glm::Vec4>>, VmfError> {
+    Ok(get_dispdata(power, kv, key, 4)?
+        .iter()
+        .map(|values| {
+            values
+                .chunks(4)
+                .map(|v| glm::vec4(v[0], v[1], v[2], v[3]))
+                .collect()
+        })
+        .collect())
+}
+
+/// Puts hidden objects inside a "hidden" block, like Hammer does for each of them
+fn hidden_kv(
+    hidden: bool,
+    name: &'static str,
+    kv: KeyValues<'static>,
+) -> (&'static str, KeyValues<'static>) {
+    if hidden {
+        (
+            "hidden",
+            KeyValues::new_list(vec![(String::from(name), kv)]),
+        )
+    } else {
+        (name, kv)
     }
-    Some(data)
+}
+
+fn join<T>(values: &[T], format: impl Fn(&T) -> String) -> String {
+    values.iter().map(format).collect::<Vec<String>>().join(" ")
+}
+
+/// Writes a block of rows named "row0", "row1" and so on
+fn rows_kv<T>(data: &[Vec<T>], format: impl Fn(&T) -> String) -> KeyValues<'static> {
+    KeyValues::new_list(
+        data.iter()
+            .enumerate()
+            .map(|(row, row_data)| (format!("row{}", row), value_kv(join(row_data, &format))))
+            .collect(),
+    )
+}
+
+fn dispdata_1_kv(data: &[Vec<f32>]) -> KeyValues<'static> {
+    rows_kv(data, ToString::to_string)
+}
+
+fn dispdata_3_kv(data: &[Vec<glm::Vec3>]) -> KeyValues<'static> {
+    rows_kv(data, |v| format!("{} {} {}", v.x, v.y, v.z))
 }
 
 fn normalize_normals(
@@ -311,3 +2221,655 @@ fn normalize_normals(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::keyvalue::{Conditions, KeyValuesError, Location};
+
+    use super::*;
+
+    fn get_sample() -> String {
+        with_crlf(include_str!("../tests/data/sample.vmf"))
+    }
+
+    /// Hammer uses CRLF, but git might have changed it
+    fn with_crlf(content: &str) -> String {
+        content.replace("\r\n", "\n").replace('\n', "\r\n")
+    }
+
+    fn parse_kv(content: &str) -> KeyValues<'_> {
+        KeyValues::parse_internal(content, "test.vmf", &Conditions::default(), &|x| {
+            Err(KeyValuesError::InvalidFile(x, Location::default()))
+        })
+        .unwrap()
+    }
+
+    fn at(line: usize, column: usize) -> Span {
+        Span { line, column }
+    }
+
+    fn parse_str(content: &str) -> VMF {
+        VMF::from_keyvalues(&parse_kv(content)).unwrap()
+    }
+
+    #[test]
+    fn test_write_unchanged() {
+        let sample = get_sample();
+        assert_eq!(parse_str(&sample).get_string(), sample);
+    }
+
+    #[test]
+    fn test_write_unchanged_hammer() {
+        // Saved by Hammer, which writes floats with %g (e.g. -4.37114e-08) unlike Rust
+        let hammer = with_crlf(include_str!("../tests/data/hammer.vmf"));
+        let mut vmf = parse_str(&hammer);
+        assert_eq!(vmf.get_string(), hammer);
+
+        // Only the values that changed get written the Rust way
+        let face = &mut vmf.entities[1].brushes[0].faces[0];
+        assert_eq!(face.uaxis.dir.z, -4.37114e-08);
+        face.uaxis.translation = 1.0;
+        vmf.cameras.as_mut().unwrap().cameras[0].look.z = 32.0;
+        vmf.entities[0].connections[1].delay = 2.0;
+        let written = vmf.get_string();
+        assert!(written.contains("\"uaxis\" \"[1 0 -0.0000000437114 1] 0.25\""));
+        assert!(written.contains("\"vaxis\" \"[0 -1 0 0] 0.25\""));
+        assert!(written.contains("\"look\" \"[0.707107 -0.0000000437114 32]\""));
+        assert!(written.contains("\"OnTrigger\" \"light,TurnOn,0.10,2,1\""));
+        assert!(written.contains("\"row1\" \"0 -4.37114e-08 1 0 0 1"));
+        assert!(written.contains("\"mins\" \"(-256 -256 -6.10352e-05)\""));
+    }
+
+    #[test]
+    fn test_write_hidden_order() {
+        // Hammer writes objects in the order they were made, hidden or not
+        let swap = |content: &str, first: &str, second: &str, end: &str| {
+            let first = content.find(first).unwrap();
+            let second = content.find(second).unwrap();
+            let end = content.find(end).unwrap();
+            format!(
+                "{}{}{}{}",
+                &content[..first],
+                &content[second..end],
+                &content[first..second],
+                &content[end..]
+            )
+        };
+        let sample = swap(&get_sample(), "\tsolid\r\n", "\thidden\r\n", "}\r\nentity");
+        let sample = swap(
+            &sample,
+            "entity\r\n{\r\n\t\"id\" \"21\"",
+            "hidden\r\n{\r\n\tentity",
+            "cameras\r\n",
+        );
+        let vmf = parse_str(&sample);
+        assert!(vmf.world.brushes[0].hidden);
+        assert!(!vmf.world.brushes[1].hidden);
+        let ids: Vec<(i32, bool)> = vmf.entities.iter().map(|x| (x.id, x.hidden)).collect();
+        assert_eq!(ids, vec![(20, false), (30, true), (21, false)]);
+        assert_eq!(vmf.get_string(), sample);
+    }
+
+    #[test]
+    fn test_write_unnormalized_axes() {
+        let sample = get_sample()
+            .replacen("[1 0 0 0] 0.25", "[0.707107 0 -0.707107 0.5] 0.25", 1)
+            .replacen("[0 -1 0 0] 0.25", "[0 -2 0 0] 0.5", 1);
+        let vmf = parse_str(&sample);
+        assert_eq!(vmf.world.brushes[0].faces[0].vaxis.dir.y, -2.0);
+        assert_eq!(vmf.get_string(), sample);
+    }
+
+    #[test]
+    fn test_write_keeps_fields() {
+        let vmf = parse_str(&get_sample());
+        let vmf = parse_str(&vmf.get_string());
+        assert_eq!(vmf.world.get("skyname"), Some("sky_day01_01"));
+        assert_eq!(vmf.world.brushes.len(), 2);
+        assert!(!vmf.world.brushes[0].hidden);
+        assert!(vmf.world.brushes[1].hidden);
+        assert_eq!(vmf.entities.len(), 3);
+        assert_eq!(vmf.entities[0].connections.len(), 2);
+        assert_eq!(vmf.entities[1].brushes.len(), 1);
+        assert!(vmf.entities[2].hidden);
+        assert_eq!(vmf.entities[2].classname(), "info_player_start");
+        let face = &vmf.world.brushes[0].faces[0];
+        assert_eq!(face.lightmapscale, 16);
+        assert_eq!(face.smoothing_groups, 0);
+        let dispinfo = face.dispinfo.as_ref().unwrap();
+        assert_eq!(dispinfo.distances[2], vec![0.0, 8.0, 16.5, 8.0, -0.0]);
+        assert_eq!(dispinfo.alphas[4], vec![0.0, 127.5, 255.0, 127.5, 0.0]);
+        assert_eq!(dispinfo.flags, Some(0));
+        assert_eq!(
+            dispinfo.offset_normals.as_ref().unwrap()[1][3],
+            glm::vec3(0.0, 0.0, 1.0)
+        );
+        assert_eq!(dispinfo.get_triangle_tag(3, 2, false), TriangleTag(0));
+        assert_eq!(dispinfo.get_triangle_tag(3, 2, true), TriangleTag(9));
+        assert_eq!(dispinfo.allowed_verts, Some(vec![-1; 10]));
+        assert!(dispinfo.extra.is_empty());
+    }
+
+    #[test]
+    fn test_dispinfo_optional_keys() {
+        // Older files don't have flags or offsets, they shouldn't appear when saving
+        let sample = get_sample();
+        let offsets = sample.find("\t\t\t\toffsets\r\n").unwrap();
+        let offset_normals = sample.find("\t\t\t\toffset_normals\r\n").unwrap();
+        let old = format!("{}{}", &sample[..offsets], &sample[offset_normals..]).replacen(
+            "\t\t\t\t\"flags\" \"0\"\r\n",
+            "",
+            1,
+        );
+        let vmf = parse_str(&old);
+        let dispinfo = vmf.world.brushes[0].faces[0].dispinfo.as_ref().unwrap();
+        assert_eq!(dispinfo.flags, None);
+        assert_eq!(dispinfo.offsets, None);
+        assert_eq!(vmf.get_string(), old);
+    }
+
+    #[test]
+    fn test_dispinfo_multiblend() {
+        let rows = |values: &str| {
+            (0..5)
+                .map(|row| format!("\t\t\t\t\t\"row{row}\" \"{}\"\r\n", [values; 5].join(" ")))
+                .collect::<String>()
+        };
+        let block = |name: &str, values: &str| {
+            format!(
+                "\t\t\t\t{name}\r\n\t\t\t\t{{\r\n{}\t\t\t\t}}\r\n",
+                rows(values)
+            )
+        };
+        let multiblend = [
+            block("multiblend", "1 0 0 0"),
+            block("alphablend", "0 0 0 0"),
+            block("multiblend_color_0", "1 1 1"),
+            block("multiblend_color_1", "1 0.5 1"),
+            block("multiblend_color_2", "1 1 1"),
+            block("multiblend_color_3", "1 1 1"),
+        ]
+        .concat();
+        let sample = get_sample();
+        let with_multiblend = sample.replacen(
+            "\t\t\t\ttriangle_tags",
+            &(multiblend + "\t\t\t\ttriangle_tags"),
+            1,
+        );
+        let vmf = parse_str(&with_multiblend);
+        let dispinfo = vmf.world.brushes[0].faces[0].dispinfo.as_ref().unwrap();
+        let multiblend = dispinfo.multiblend.as_ref().unwrap();
+        assert_eq!(multiblend.multiblend[4][4], glm::vec4(1.0, 0.0, 0.0, 0.0));
+        assert_eq!(multiblend.colors[1][0][2], glm::vec3(1.0, 0.5, 1.0));
+        assert_eq!(vmf.get_string(), with_multiblend);
+    }
+
+    #[test]
+    fn test_triangle_tags() {
+        let flat = glm::vec3(0.0, 0.0, 1.0);
+        let steep = glm::normalize(&glm::vec3(1.0, 0.0, 0.5));
+        assert!(TriangleTag::default().is_walkable(&flat));
+        assert!(TriangleTag::default().is_buildable(&flat));
+        assert!(!TriangleTag::default().is_walkable(&steep));
+        let forced = TriangleTag(TriangleTag::FORCE_WALKABLE_BIT | TriangleTag::FORCE_WALKABLE_VAL);
+        assert!(forced.is_walkable(&steep));
+        assert!(!TriangleTag(TriangleTag::FORCE_WALKABLE_BIT).is_walkable(&flat));
+    }
+
+    #[test]
+    fn test_write_stable() {
+        // Not in the layout Hammer uses, but should be after writing it once
+        let content = "world { id 1 classname worldspawn } entity { id 2 classname \"info_target\" origin \"0 0 0\" }";
+        let written = parse_str(content).get_string();
+        assert_eq!(parse_str(&written).get_string(), written);
+    }
+
+    #[test]
+    fn test_connection_parse() {
+        let connection =
+            Connection::parse("OnTrigger", "door,Open,,0.5,-1", Span::default()).unwrap();
+        assert_eq!(connection.output, "OnTrigger");
+        assert_eq!(connection.target, "door");
+        assert_eq!(connection.input, "Open");
+        assert_eq!(connection.parameter, "");
+        assert_eq!(connection.delay, 0.5);
+        assert_eq!(connection.times_to_fire, -1);
+        assert_eq!(connection.separator, ',');
+
+        let connection = Connection::parse(
+            "OnPressed",
+            "relay\x1bTrigger\x1b\x1b0\x1b1",
+            Span::default(),
+        )
+        .unwrap();
+        assert_eq!(connection.target, "relay");
+        assert_eq!(connection.times_to_fire, 1);
+        assert_eq!(connection.separator, '\x1b');
+
+        assert_eq!(
+            Connection::parse("OnTrigger", "door,Open", at(3, 4)).unwrap_err(),
+            VmfError::InvalidConnection {
+                output: String::from("OnTrigger"),
+                value: String::from("door,Open"),
+                span: at(3, 4)
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let sample = get_sample();
+        let broken = sample.replace(
+            "\"plane\" \"(64 64 0) (64 -64 0) (64 -64 64)\"",
+            "\"plane\" \"(64 64 0) (64 64 0) (64 -64 64)\"",
+        );
+        assert_eq!(
+            VMF::from_keyvalues(&parse_kv(&broken)).unwrap_err(),
+            VmfError::InEntity {
+                id: 1,
+                source: Box::new(VmfError::InSolid {
+                    id: 2,
+                    source: Box::new(VmfError::InSide {
+                        id: 4,
+                        source: Box::new(VmfError::InvalidPlane(
+                            String::from("(64 64 0) (64 64 0) (64 -64 64)"),
+                            at(133, 4)
+                        ))
+                    })
+                })
+            }
+        );
+
+        let broken = sample.replace("\"uaxis\" \"[0 1 0 0] 0.25\"", "\"uaxis\" \"[0 1 0] 0.25\"");
+        assert_eq!(
+            VMF::from_keyvalues(&parse_kv(&broken))
+                .unwrap_err()
+                .to_string(),
+            "in entity 1: in solid 2: in side 3: malformed uv axis \"[0 1 0] 0.25\" at 124:4"
+        );
+
+        let broken = sample.replacen("\t\t\t\t\t\"row4\" \"0 8 16.5 8 -0\"\r\n", "", 1);
+        assert_eq!(
+            VMF::from_keyvalues(&parse_kv(&broken)).unwrap_err(),
+            VmfError::InEntity {
+                id: 1,
+                source: Box::new(VmfError::InSolid {
+                    id: 2,
+                    source: Box::new(VmfError::InSide {
+                        id: 1,
+                        source: Box::new(VmfError::WrongRowCount {
+                            key: String::from("distances"),
+                            expected: 5,
+                            found: 4,
+                            span: at(63, 5)
+                        })
+                    })
+                })
+            }
+        );
+
+        // Facing the wrong way, the solid is open towards +x
+        let broken = sample.replace(
+            "\"plane\" \"(64 64 0) (64 -64 0) (64 -64 64)\"",
+            "\"plane\" \"(64 -64 0) (64 64 0) (64 -64 64)\"",
+        );
+        assert_eq!(
+            VMF::from_keyvalues(&parse_kv(&broken))
+                .unwrap_err()
+                .to_string(),
+            "in entity 1: in solid 2: broken solid at 35:2: the sides don't close the solid or it reaches past ±65536"
+        );
+    }
+
+    #[test]
+    fn test_cameras() {
+        let vmf = parse_str(&get_sample());
+        let viewsettings = vmf.viewsettings.as_ref().unwrap();
+        assert_eq!(viewsettings.grid_spacing, 64);
+        assert!(viewsettings.snap_to_grid);
+        assert!(!viewsettings.show_3d_grid);
+
+        let cameras = vmf.cameras.as_ref().unwrap();
+        let camera = cameras.active().unwrap();
+        assert_eq!(camera.position, glm::vec3(-192.0, -256.0, 192.0));
+        assert_eq!(camera.look, glm::vec3(0.0, 0.0, 64.0));
+        let (yaw, pitch) = camera.yaw_pitch();
+        assert!((yaw - f32::atan2(256.0, 192.0)).abs() < 1e-5);
+        assert!(pitch < 0.0);
+
+        let no_camera = get_sample().replace("\"activecamera\" \"0\"", "\"activecamera\" \"-1\"");
+        assert!(parse_str(&no_camera).cameras.unwrap().active().is_none());
+    }
+
+    #[test]
+    fn test_cordons() {
+        let sample = get_sample();
+        let vmf = parse_str(&sample);
+        let cordons = vmf.cordons.as_ref().unwrap();
+        assert!(!cordons.active);
+        assert_eq!(cordons.cordons[0].name, "cordon");
+        assert_eq!(
+            cordons.cordons[0].boxes[0].maxs,
+            glm::vec3(256.0, 256.0, 256.0)
+        );
+        assert!(vmf.get_cordoned().is_none());
+
+        // Only the logic_relay at 0 0 72 is outside, hidden objects are left out as well
+        let active = sample
+            .replacen("\"active\" \"0\"", "\"active\" \"1\"", 1)
+            .replace("\"maxs\" \"(256 256 256)\"", "\"maxs\" \"(256 256 70)\"");
+        let vmf = parse_str(&active);
+        let cordoned = vmf.get_cordoned().unwrap();
+        assert!(cordoned.cordons.is_none());
+        assert_eq!(cordoned.entities.len(), vmf.entities.len() - 2);
+        assert!(cordoned
+            .entities
+            .iter()
+            .all(|entity| entity.classname() != "logic_relay" && !entity.hidden));
+        assert!(cordoned.world.brushes.iter().all(|x| !x.hidden));
+
+        let shell = &cordoned.world.brushes[vmf.world.brushes.len() - 1..];
+        assert_eq!(shell.len(), 6);
+        assert_eq!(
+            shell[0].get_bounds().unwrap(),
+            (
+                glm::vec3(-272.0, -272.0, -80.0),
+                glm::vec3(-256.0, 272.0, 86.0)
+            )
+        );
+        assert_eq!(
+            shell[5].get_bounds().unwrap(),
+            (
+                glm::vec3(-256.0, -256.0, 70.0),
+                glm::vec3(256.0, 256.0, 86.0)
+            )
+        );
+        let mut ids = vmf.get_id_registry();
+        assert_eq!(shell[0].id, ids.next_id());
+        let first_side_id = ids.next_side_id();
+        assert!(shell
+            .iter()
+            .flat_map(|brush| &brush.faces)
+            .all(|face| face.material == "TOOLS/TOOLSSKYBOX" && face.id >= first_side_id));
+        assert_eq!(cordoned.validate_ids(), vec![]);
+
+        // The exported map can be read again
+        let written = cordoned.get_string();
+        assert_eq!(parse_str(&written).get_string(), written);
+    }
+
+    #[test]
+    fn test_box_brush() {
+        let brush = Brush::new_box(
+            1,
+            1,
+            &glm::vec3(-64.0, -64.0, 0.0),
+            &glm::vec3(64.0, 64.0, 64.0),
+            "DEV/DEV_MEASUREGENERIC01B",
+        )
+        .unwrap();
+        let vmf = parse_str(&get_sample());
+        // Same planes as the box Hammer made in the sample, just in a different order
+        let mut expected: Vec<_> = vmf.world.brushes[0]
+            .faces
+            .iter()
+            .map(|face| face.normal())
+            .collect();
+        for face in &brush.faces {
+            let index = expected
+                .iter()
+                .position(|normal| (normal - face.normal()).norm() < 1e-6)
+                .unwrap();
+            expected.remove(index);
+        }
+        assert!(expected.is_empty());
+        assert_eq!(
+            brush.get_bounds().unwrap(),
+            (glm::vec3(-64.0, -64.0, 0.0), glm::vec3(64.0, 64.0, 64.0))
+        );
+    }
+
+    /// Checks every corner of every side has the same uv as it had before the transform
+    fn assert_uvs_kept(original: &Brush, transformed: &Brush, matrix: &glm::Mat4) {
+        for (i, (face, corners)) in original.shape.faces.iter().enumerate() {
+            for &corner in corners {
+                let position = original.shape.vertices[corner];
+                let uv = crate::mesh::get_uv_point(face, &position);
+                let position = (matrix * position.push(1.0)).xyz();
+                let new_uv = crate::mesh::get_uv_point(&transformed.faces[i], &position);
+                assert!((new_uv - uv).norm() < 0.01, "{uv} became {new_uv}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_transform() {
+        let mut original = Brush::new_box(
+            1,
+            1,
+            &glm::vec3(-64.0, -32.0, 0.0),
+            &glm::vec3(64.0, 32.0, 48.0),
+            "DEV/DEV_MEASUREGENERIC01B",
+        )
+        .unwrap();
+        for face in &mut original.faces {
+            face.uaxis.translation = 13.0;
+            face.vaxis.scaling = -0.5;
+        }
+        original.shape = get_polyhedron(&original.faces).unwrap();
+        let volume = original.shape.get_volume();
+
+        let moved = glm::translation(&glm::vec3(13.5, -7.0, 100.0));
+        let turned = moved * glm::rotation(0.5, &glm::normalize(&glm::vec3(1.0, 2.0, 3.0)));
+        for matrix in [moved, turned] {
+            for lock in [TextureLock::Lock, TextureLock::ScalingLock] {
+                let mut brush = original.clone();
+                brush.transform(&matrix, lock).unwrap();
+                assert_uvs_kept(&original, &brush, &matrix);
+                assert!((brush.shape.get_volume() - volume).abs() < 0.1);
+            }
+        }
+
+        // Without lock the texture stays in the world
+        let mut brush = original.clone();
+        brush.transform(&moved, TextureLock::None).unwrap();
+        assert_eq!(brush.faces[0].uaxis.dir, original.faces[0].uaxis.dir);
+        assert_eq!(brush.faces[0].uaxis.translation, 13.0);
+        assert_eq!(
+            brush.get_bounds().unwrap().0,
+            glm::vec3(-50.5, -39.0, 100.0)
+        );
+
+        // Scaling stretches the texture only with scaling lock
+        let stretched = turned * glm::scaling(&glm::vec3(2.0, 1.0, 0.5));
+        let mut brush = original.clone();
+        brush
+            .transform(&stretched, TextureLock::ScalingLock)
+            .unwrap();
+        assert_uvs_kept(&original, &brush, &stretched);
+        assert!((brush.shape.get_volume() - volume).abs() < 0.1);
+        let mut brush = original.clone();
+        brush.transform(&stretched, TextureLock::Lock).unwrap();
+        for (face, original) in brush.faces.iter().zip(&original.faces) {
+            assert!((face.uaxis.scaling - original.uaxis.scaling).abs() < 1e-5);
+            assert!((face.vaxis.scaling - original.vaxis.scaling).abs() < 1e-5);
+        }
+
+        // Mirrored brushes still have their sides facing out
+        let mirrored = glm::scaling(&glm::vec3(-1.0, 1.0, 1.0));
+        let mut brush = original.clone();
+        brush
+            .transform(&mirrored, TextureLock::ScalingLock)
+            .unwrap();
+        assert!((brush.shape.get_volume() - volume).abs() < 0.1);
+        assert_uvs_kept(&original, &brush, &mirrored);
+
+        let mut brush = original.clone();
+        assert_eq!(
+            brush.transform(&glm::scaling(&glm::vec3(1.0, 0.0, 1.0)), TextureLock::Lock),
+            Err(TransformError::NotInvertible)
+        );
+        let mut sample = parse_str(&get_sample());
+        assert_eq!(
+            sample.world.brushes[0].transform(&mirrored, TextureLock::Lock),
+            Err(TransformError::MirroredDisplacement)
+        );
+        // Displacements move along
+        let displacement = &mut sample.world.brushes[0];
+        let start = displacement.faces[0].dispinfo.as_ref().unwrap().startpos;
+        displacement.transform(&moved, TextureLock::Lock).unwrap();
+        assert_eq!(
+            displacement.faces[0].dispinfo.as_ref().unwrap().startpos,
+            start + glm::vec3(13.5, -7.0, 100.0)
+        );
+        assert!(crate::mesh::get_brush_meshes(displacement).is_ok());
+    }
+
+    #[test]
+    fn test_texture_ops() {
+        let brush = Brush::new_box(
+            1,
+            1,
+            &glm::vec3(-64.0, -32.0, 0.0),
+            &glm::vec3(64.0, 32.0, 48.0),
+            "DEV/DEV_MEASUREGENERIC01B",
+        )
+        .unwrap();
+        let (top, corners) = brush
+            .shape
+            .faces
+            .iter()
+            .find(|(face, _)| face.normal().z > 0.5)
+            .unwrap();
+        let polygon: Vec<Vec3> = corners.iter().map(|&i| brush.shape.vertices[i]).collect();
+        let uv_range = |face: &Face| {
+            let u = get_range(&polygon, |point| face.uaxis.get_uv(point)).unwrap();
+            let v = get_range(&polygon, |point| face.vaxis.get_uv(point)).unwrap();
+            (u, v)
+        };
+        let assert_close = |a: f32, b: f32| assert!((a - b).abs() < 1e-3, "{a} != {b}");
+
+        let mut face = top.clone();
+        face.fit(&polygon, (256, 128), (1.0, 1.0));
+        let ((min_u, max_u), (min_v, max_v)) = uv_range(&face);
+        assert_close(min_u, 0.0);
+        assert_close(max_u, 256.0);
+        assert_close(min_v, 0.0);
+        assert_close(max_v, 128.0);
+        face.fit(&polygon, (256, 128), (2.0, 0.5));
+        let ((min_u, max_u), (min_v, max_v)) = uv_range(&face);
+        assert_close(max_u - min_u, 512.0);
+        assert_close(max_v - min_v, 64.0);
+
+        let mut face = top.clone();
+        face.justify(Justify::Right, &polygon, (100, 100));
+        let ((_, max_u), _) = uv_range(&face);
+        assert_close(max_u.rem_euclid(100.0), 0.0);
+        face.justify(Justify::Bottom, &polygon, (100, 100));
+        let (_, (_, max_v)) = uv_range(&face);
+        assert_close(max_v.rem_euclid(100.0), 0.0);
+        face.justify(Justify::Center, &polygon, (100, 100));
+        let ((min_u, max_u), (min_v, max_v)) = uv_range(&face);
+        assert_close(((min_u + max_u) / 2.0).rem_euclid(100.0), 50.0);
+        assert_close(((min_v + max_v) / 2.0).rem_euclid(100.0), 50.0);
+
+        let mut face = top.clone();
+        face.shift(3.0, -2.0);
+        face.rotate(90.0);
+        assert_close(face.get_rotation(), 90.0);
+        assert!((face.uaxis.dir - glm::vec3(0.0, 1.0, 0.0)).norm() < 1e-5);
+        assert!((face.vaxis.dir - glm::vec3(1.0, 0.0, 0.0)).norm() < 1e-5);
+        face.align_to_world();
+        assert_eq!(face.uaxis.dir, top.uaxis.dir);
+        assert_eq!(face.vaxis.dir, top.vaxis.dir);
+        assert_eq!(face.get_rotation(), 0.0);
+        assert_close(face.uaxis.translation, top.uaxis.translation + 3.0);
+
+        // On a slope the axes lie on the side, with v still going down
+        let mut face = Face::from_points(
+            1,
+            (
+                glm::vec3(0.0, 0.0, 0.0),
+                glm::vec3(0.0, 64.0, 0.0),
+                glm::vec3(64.0, 0.0, 128.0),
+            ),
+            "DEV/DEV_MEASUREGENERIC01B",
+        )
+        .unwrap();
+        assert!(face.normal().x.abs() > face.normal().z.abs());
+        face.align_to_face();
+        let normal = face.normal();
+        assert_close(glm::dot(&face.uaxis.dir, &normal), 0.0);
+        assert_close(glm::dot(&face.vaxis.dir, &normal), 0.0);
+        assert_close(glm::dot(&face.uaxis.dir, &face.vaxis.dir), 0.0);
+        assert_close(face.vaxis.dir.norm(), 1.0);
+        assert!(face.vaxis.dir.z < 0.0);
+
+        // Longer axes stay as they are until the face gets edited, the texture doesn't move
+        let input = "[0 2 0 16] 0.5";
+        let mut axis = UVAxis::parse(input, Span::default()).unwrap();
+        assert_eq!(axis.to_string(), input);
+        assert_eq!(axis.get_uv(&glm::vec3(0.0, 1.0, 0.0)), 20.0);
+        axis.normalize();
+        assert_eq!(axis.dir, glm::vec3(0.0, 1.0, 0.0));
+        assert_eq!(axis.scaling, 0.25);
+        assert_eq!(axis.get_uv(&glm::vec3(0.0, 1.0, 0.0)), 20.0);
+        let mut face = top.clone();
+        face.uaxis = UVAxis::parse("[2 0 0 0] 0.5", Span::default()).unwrap();
+        let before = face.uaxis.get_uv(&glm::vec3(64.0, 0.0, 0.0));
+        face.align_to_world();
+        assert_eq!(face.uaxis.get_uv(&glm::vec3(64.0, 0.0, 0.0)), before);
+    }
+
+    #[test]
+    fn test_visgroups() {
+        let content = "visgroups\n{\n\tvisgroup\n\t{\n\t\t\"name\" \"Details\"\n\t\t\"visgroupid\" \"1\"\n\t\t\"color\" \"65 228 109\"\n\t\tvisgroup\n\t\t{\n\t\t\t\"name\" \"Props\"\n\t\t\t\"visgroupid\" \"2\"\n\t\t\t\"color\" \"200 0 0\"\n\t\t}\n\t}\n\tvisgroup\n\t{\n\t\t\"name\" \"Lights\"\n\t\t\"visgroupid\" \"3\"\n\t\t\"color\" \"0 0 200\"\n\t}\n}\nworld\n{\n\t\"id\" \"1\"\n\t\"classname\" \"worldspawn\"\n}\nentity\n{\n\t\"id\" \"2\"\n\t\"classname\" \"light\"\n\teditor\n\t{\n\t\t\"color\" \"220 30 220\"\n\t\t\"visgroupid\" \"3\"\n\t\t\"visgroupid\" \"2\"\n\t\t\"visgroupshown\" \"1\"\n\t\t\"visgroupautoshown\" \"0\"\n\t\t\"logicalpos\" \"[0 500]\"\n\t}\n}\n";
+        let vmf = parse_str(content);
+        let visgroups = vmf.visgroups.as_ref().unwrap();
+        assert_eq!(visgroups.len(), 2);
+        assert_eq!(visgroups[0].children[0].name, "Props");
+        assert_eq!(vmf.get_visgroup(2).unwrap().color, [200, 0, 0]);
+        assert_eq!(vmf.get_visgroup(3).unwrap().name, "Lights");
+
+        let editor = vmf.entities[0].editor.as_ref().unwrap();
+        assert_eq!(editor.color, Some([220, 30, 220]));
+        assert_eq!(editor.visgroup_ids, vec![3, 2]);
+        assert!(editor.visgroup_shown);
+        assert!(!editor.visgroup_auto_shown);
+
+        // Hiding Details also hides Props inside it
+        let hidden = vmf.get_hidden_visgroups(&HashSet::from([1]));
+        assert_eq!(hidden, HashSet::from([1, 2]));
+        assert!(!vmf.entities[0].is_visible(&hidden));
+        assert!(vmf.entities[0].is_visible(&vmf.get_hidden_visgroups(&HashSet::new())));
+
+        assert_eq!(parse_str(&vmf.get_string()).get_string(), vmf.get_string());
+        assert!(vmf.get_string().contains(
+            "\t\t\"visgroupid\" \"3\"\r\n\t\t\"visgroupid\" \"2\"\r\n\t\t\"visgroupshown\" \"1\"\r\n\t\t\"visgroupautoshown\" \"0\"\r\n\t\t\"logicalpos\" \"[0 500]\""
+        ));
+
+        // A file without a visgroups block doesn't get an empty one
+        let world_only = "world\r\n{\r\n\t\"id\" \"1\"\r\n\t\"classname\" \"worldspawn\"\r\n}\r\n";
+        let vmf = parse_str(world_only);
+        assert!(vmf.visgroups.is_none());
+        assert_eq!(vmf.get_string(), world_only);
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        let broken = get_sample().replace("\"id\" \"22\"", "\"id\" \"twentytwo\"");
+        assert!(VMF::from_keyvalues(&parse_kv(&broken)).is_err());
+        let (vmf, warnings) = VMF::from_keyvalues_lenient(&parse_kv(&broken)).unwrap();
+        assert_eq!(vmf.world.brushes.len(), 2);
+        assert_eq!(vmf.entities.len(), 3);
+        assert!(vmf.entities[1].brushes.is_empty());
+        assert_eq!(
+            warnings,
+            vec![VmfError::InvalidValue {
+                key: String::from("id"),
+                value: String::from("twentytwo"),
+                span: at(275, 3)
+            }]
+        );
+    }
+}